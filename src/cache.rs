@@ -0,0 +1,655 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use crate::message::{
+    Class, DnsMessage, DnsQuestion, Name, RCODE_NAME_ERROR, RCODE_NO_ERROR, RData, ResourceRecord,
+    Type,
+};
+
+/// Upper bound on how long a negative answer is cached, as recommended by RFC 2308.
+const MAX_NEGATIVE_TTL: u32 = 3 * 60 * 60;
+
+/// TTL given to records served past their expiry, as recommended by RFC 8767.
+const STALE_ANSWER_TTL: u32 = 30;
+
+/// Number of hits an entry needs before it is considered popular enough to prefetch.
+const PREFETCH_MIN_HITS: u32 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    name: Name<'static>,
+    qtype: Type,
+    class: Class,
+}
+
+impl CacheKey {
+    fn new(question: &DnsQuestion<'_>) -> Self {
+        Self {
+            name: question.name.to_ascii_lowercase(),
+            qtype: question.qtype,
+            class: question.class,
+        }
+    }
+}
+
+/// An answer served from the cache, with TTLs already reduced by the time spent in the cache.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CachedAnswer {
+    /// The name exists and has records of the requested type. Only the Answer section of
+    /// the reply is kept: its Authority and Additional sections are dropped, as they are
+    /// optional and answers to several questions are assembled into one reply.
+    Records(Vec<ResourceRecord<'static>>),
+    /// The name does not exist (NXDOMAIN). The SOA belongs in the Authority section.
+    NxDomain { soa: ResourceRecord<'static> },
+    /// The name exists but has no records of the requested type (NODATA). The SOA belongs
+    /// in the Authority section.
+    NoData { soa: ResourceRecord<'static> },
+}
+
+impl CachedAnswer {
+    /// Classifies an upstream reply into something worth caching, along with its TTL.
+    fn from_reply(reply: &DnsMessage<'_>) -> Option<(Self, u32)> {
+        match reply.header.response_code {
+            RCODE_NO_ERROR if !reply.records.is_empty() => {
+                let ttl = reply.records.iter().map(|r| r.ttl).min()?;
+                let records = reply
+                    .records
+                    .iter()
+                    .map(|r| r.clone().into_owned())
+                    .collect();
+                Some((CachedAnswer::Records(records), ttl))
+            }
+
+            RCODE_NO_ERROR => {
+                let (soa, ttl) = negative_soa(reply)?;
+                Some((CachedAnswer::NoData { soa }, ttl))
+            }
+
+            // Negative answers reached through a CNAME chain are not cached
+            RCODE_NAME_ERROR if reply.records.is_empty() => {
+                let (soa, ttl) = negative_soa(reply)?;
+                Some((CachedAnswer::NxDomain { soa }, ttl))
+            }
+
+            _ => None,
+        }
+    }
+
+    fn with_remaining_ttl(&self, elapsed: u32) -> Self {
+        self.with_ttl(|ttl| ttl.saturating_sub(elapsed))
+    }
+
+    fn with_ttl(&self, f: impl Fn(u32) -> u32) -> Self {
+        let age = |record: &ResourceRecord<'static>| {
+            let mut record = record.clone();
+            record.ttl = f(record.ttl);
+            record
+        };
+
+        match self {
+            CachedAnswer::Records(records) => {
+                CachedAnswer::Records(records.iter().map(age).collect())
+            }
+            CachedAnswer::NxDomain { soa } => CachedAnswer::NxDomain { soa: age(soa) },
+            CachedAnswer::NoData { soa } => CachedAnswer::NoData { soa: age(soa) },
+        }
+    }
+
+    pub fn is_negative(&self) -> bool {
+        !matches!(self, CachedAnswer::Records(_))
+    }
+}
+
+/// Finds the SOA record in the Authority section of a negative reply and derives the
+/// negative caching TTL from it, per RFC 2308 section 5.
+fn negative_soa(reply: &DnsMessage<'_>) -> Option<(ResourceRecord<'static>, u32)> {
+    reply
+        .authority_records
+        .iter()
+        .find_map(|record| match record.rdata {
+            RData::SOA { minimum, .. } => {
+                let ttl = record.ttl.min(minimum).min(MAX_NEGATIVE_TTL);
+                let mut soa = record.clone().into_owned();
+                soa.ttl = ttl;
+                Some((soa, ttl))
+            }
+            _ => None,
+        })
+}
+
+/// The result of a successful cache lookup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheHit {
+    pub answer: CachedAnswer,
+    /// The entry is popular and close to expiring, and should be refreshed from upstream in
+    /// the background.
+    pub prefetch: bool,
+}
+
+#[derive(Debug)]
+struct Entry {
+    answer: CachedAnswer,
+    stored: Instant,
+    ttl: u32,
+    hits: u32,
+    prefetching: bool,
+}
+
+impl Entry {
+    fn elapsed(&self, now: Instant) -> u32 {
+        now.duration_since(self.stored)
+            .as_secs()
+            .try_into()
+            .unwrap_or(u32::MAX)
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        self.elapsed(now) >= self.ttl
+    }
+
+    fn is_past_stale_window(&self, now: Instant, stale_window: u32) -> bool {
+        self.elapsed(now) >= self.ttl.saturating_add(stale_window)
+    }
+
+    /// Whether the entry has been hit often enough and is within the last `percent` of its
+    /// TTL. Only the first caller to see this gets `true`.
+    fn claim_prefetch(&mut self, now: Instant, percent: u32) -> bool {
+        let remaining = self.ttl.saturating_sub(self.elapsed(now)) as u64;
+        let due = remaining * 100 <= self.ttl as u64 * percent as u64;
+        if percent == 0 || self.prefetching || self.hits < PREFETCH_MIN_HITS || !due {
+            return false;
+        }
+
+        self.prefetching = true;
+        true
+    }
+}
+
+/// Where an entry sits in the expiry order: its expiry time, then a sequence number to
+/// tell apart entries expiring at the same instant.
+type Deadline = (Instant, u64);
+
+/// The cached entries, indexed both by question and by expiry so the entry closest to
+/// expiring can be evicted without scanning the whole cache.
+#[derive(Debug, Default)]
+struct Entries {
+    by_key: HashMap<CacheKey, (Entry, Deadline)>,
+    by_deadline: BTreeMap<Deadline, CacheKey>,
+    sequence: u64,
+}
+
+impl Entries {
+    fn len(&self) -> usize {
+        self.by_key.len()
+    }
+
+    fn get(&self, key: &CacheKey) -> Option<&Entry> {
+        self.by_key.get(key).map(|(entry, _)| entry)
+    }
+
+    fn get_mut(&mut self, key: &CacheKey) -> Option<&mut Entry> {
+        self.by_key.get_mut(key).map(|(entry, _)| entry)
+    }
+
+    fn contains_key(&self, key: &CacheKey) -> bool {
+        self.by_key.contains_key(key)
+    }
+
+    fn insert(&mut self, key: CacheKey, entry: Entry) {
+        let deadline = (
+            entry.stored + Duration::from_secs(entry.ttl.into()),
+            self.sequence,
+        );
+        self.sequence += 1;
+        self.by_deadline.insert(deadline, key.clone());
+        if let Some((_, old)) = self.by_key.insert(key, (entry, deadline)) {
+            self.by_deadline.remove(&old);
+        }
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some((_, deadline)) = self.by_key.remove(key) {
+            self.by_deadline.remove(&deadline);
+        }
+    }
+
+    /// Removes the entry that expires (or expired) first.
+    fn evict_first(&mut self) {
+        if let Some((_, key)) = self.by_deadline.pop_first() {
+            self.by_key.remove(&key);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    hits: AtomicU64,
+    negative_hits: AtomicU64,
+    stale_hits: AtomicU64,
+    misses: AtomicU64,
+    prefetches: AtomicU64,
+}
+
+/// A point-in-time copy of the cache counters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Lookups answered with cached records.
+    pub hits: u64,
+    /// Lookups answered with a cached NXDOMAIN or NODATA.
+    pub negative_hits: u64,
+    /// Answers served past their expiry because the upstream failed.
+    pub stale_hits: u64,
+    /// Lookups that had to go upstream.
+    pub misses: u64,
+    /// Popular entries refreshed ahead of their expiry.
+    pub prefetches: u64,
+}
+
+/// A TTL-respecting cache of upstream answers, including negative answers (RFC 2308).
+///
+/// Expired answers are kept for a further `stale_window` seconds so they can be served
+/// when the upstream is unavailable (RFC 8767). Popular answers served within the last
+/// `prefetch_percent` of their TTL are flagged for refreshing before they expire.
+#[derive(Debug)]
+pub struct Cache {
+    entries: Mutex<Entries>,
+    capacity: usize,
+    stale_window: u32,
+    prefetch_percent: u32,
+    counters: Counters,
+}
+
+impl Cache {
+    pub fn new(capacity: usize, stale_window: u32, prefetch_percent: u32) -> Self {
+        Self {
+            entries: Mutex::new(Entries::default()),
+            capacity,
+            stale_window,
+            prefetch_percent,
+            counters: Counters::default(),
+        }
+    }
+
+    pub fn get(&self, question: &DnsQuestion<'_>) -> Option<CacheHit> {
+        self.get_at(question, Instant::now())
+    }
+
+    fn get_at(&self, question: &DnsQuestion<'_>, now: Instant) -> Option<CacheHit> {
+        let key = CacheKey::new(question);
+
+        let mut entries = self.entries.lock().unwrap();
+        let hit = match entries.get_mut(&key) {
+            Some(entry) if !entry.is_expired(now) => {
+                entry.hits = entry.hits.saturating_add(1);
+                Some(CacheHit {
+                    answer: entry.answer.with_remaining_ttl(entry.elapsed(now)),
+                    prefetch: entry.claim_prefetch(now, self.prefetch_percent),
+                })
+            }
+            Some(entry) if entry.is_past_stale_window(now, self.stale_window) => {
+                entries.remove(&key);
+                None
+            }
+            _ => None,
+        };
+        drop(entries);
+
+        let counter = match &hit {
+            Some(hit) if hit.answer.is_negative() => &self.counters.negative_hits,
+            Some(_) => &self.counters.hits,
+            None => &self.counters.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        if hit.as_ref().is_some_and(|hit| hit.prefetch) {
+            self.counters.prefetches.fetch_add(1, Ordering::Relaxed);
+        }
+
+        hit
+    }
+
+    /// Whether a fresh answer to `question` is cached. Unlike [Cache::get], this does not
+    /// count as a lookup.
+    pub fn contains(&self, question: &DnsQuestion<'_>) -> bool {
+        let now = Instant::now();
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(&CacheKey::new(question))
+            .is_some_and(|entry| !entry.is_expired(now))
+    }
+
+    /// Looks up an expired answer that is still within the stale window, to be served when
+    /// the upstream times out or fails.
+    pub fn get_stale(&self, question: &DnsQuestion<'_>) -> Option<CachedAnswer> {
+        self.get_stale_at(question, Instant::now())
+    }
+
+    fn get_stale_at(&self, question: &DnsQuestion<'_>, now: Instant) -> Option<CachedAnswer> {
+        let key = CacheKey::new(question);
+
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(&key)
+            .filter(|entry| !entry.is_past_stale_window(now, self.stale_window))
+            .map(|entry| entry.answer.with_ttl(|_| STALE_ANSWER_TTL))
+    }
+
+    /// Counts an answer from [Cache::get_stale] that was served to a client.
+    pub fn record_stale_hit(&self) {
+        self.counters.stale_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Lets the entry for `question` be prefetched again, once the refresh claimed by a
+    /// [CacheHit] with `prefetch` set has finished, whether or not it succeeded.
+    pub fn end_prefetch(&self, question: &DnsQuestion<'_>) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.get_mut(&CacheKey::new(question)) {
+            entry.prefetching = false;
+        }
+    }
+
+    /// Stores the reply to `question` if it is cacheable.
+    pub fn insert(&self, question: &DnsQuestion<'_>, reply: &DnsMessage<'_>) {
+        self.insert_at(question, reply, Instant::now());
+    }
+
+    fn insert_at(&self, question: &DnsQuestion<'_>, reply: &DnsMessage<'_>, now: Instant) {
+        let Some((answer, ttl)) = CachedAnswer::from_reply(reply) else {
+            return;
+        };
+        if ttl == 0 || self.capacity == 0 {
+            return;
+        }
+
+        let key = CacheKey::new(question);
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            // Every entry shares the stale window, so the one closest to expiring is also
+            // the first to have left it
+            entries.evict_first();
+        }
+
+        entries.insert(
+            key,
+            Entry {
+                answer,
+                stored: now,
+                ttl,
+                hits: 0,
+                prefetching: false,
+            },
+        );
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            negative_hits: self.counters.negative_hits.load(Ordering::Relaxed),
+            stale_hits: self.counters.stale_hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            prefetches: self.counters.prefetches.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::message::{DnsHeader, Opcode, RCODE_SERVER_FAILURE};
+
+    fn question(name: &str) -> DnsQuestion<'static> {
+        DnsQuestion {
+            name: name.parse().unwrap(),
+            qtype: Type::A,
+            class: Class::IN,
+        }
+    }
+
+    fn a(name: &str, ttl: u32) -> ResourceRecord<'static> {
+        ResourceRecord {
+            name: name.parse().unwrap(),
+            atype: Type::A,
+            class: Class::IN,
+            ttl,
+            rdata: RData::A {
+                address: 0xc000_0201,
+            },
+        }
+    }
+
+    fn soa(ttl: u32, minimum: u32) -> ResourceRecord<'static> {
+        ResourceRecord {
+            name: "example.com.".parse().unwrap(),
+            atype: Type::SOA,
+            class: Class::IN,
+            ttl,
+            rdata: RData::SOA {
+                mname: "ns.example.com.".parse().unwrap(),
+                rname: "hostmaster.example.com.".parse().unwrap(),
+                serial: 1,
+                refresh: 7200,
+                retry: 900,
+                expire: 1_209_600,
+                minimum,
+            },
+        }
+    }
+
+    /// A reply to `question` with `response_code` and the given sections.
+    fn reply(
+        question: &DnsQuestion<'static>,
+        response_code: u8,
+        records: Vec<ResourceRecord<'static>>,
+        authority_records: Vec<ResourceRecord<'static>>,
+    ) -> DnsMessage<'static> {
+        DnsMessage {
+            header: DnsHeader {
+                id: 1,
+                qr_indicator: true,
+                opcode: Opcode::StandardQuery,
+                authoritative_answer: false,
+                truncation: false,
+                recursion_desired: true,
+                recursion_available: true,
+                reserved: 0,
+                response_code,
+                question_count: 1,
+                answer_record_count: records.len() as u16,
+                authority_record_count: authority_records.len() as u16,
+                additional_record_count: 0,
+            },
+            questions: vec![question.clone()],
+            records,
+            authority_records,
+            additional_records: vec![],
+            edns: None,
+        }
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn nxdomain_is_cached_for_the_soa_minimum() {
+        let cache = Cache::new(10, 0, 0);
+        let question = question("missing.example.com.");
+        let stored = Instant::now();
+        let nxdomain = reply(&question, RCODE_NAME_ERROR, vec![], vec![soa(3600, 60)]);
+        cache.insert_at(&question, &nxdomain, stored);
+
+        let hit = cache.get_at(&question, stored + secs(20)).unwrap();
+        let CachedAnswer::NxDomain { soa } = hit.answer else {
+            panic!("{:?} is not an NXDOMAIN", hit.answer);
+        };
+        assert!(matches!(soa.rdata, RData::SOA { minimum: 60, .. }));
+        assert_eq!(soa.ttl, 40);
+
+        assert!(cache.get_at(&question, stored + secs(59)).is_some());
+        assert!(cache.get_at(&question, stored + secs(60)).is_none());
+    }
+
+    #[test]
+    fn nodata_is_cached_for_the_lower_of_the_soa_ttl_and_minimum() {
+        let cache = Cache::new(10, 0, 0);
+        let question = question("www.example.com.");
+        let stored = Instant::now();
+        let nodata = reply(&question, RCODE_NO_ERROR, vec![], vec![soa(30, 300)]);
+        cache.insert_at(&question, &nodata, stored);
+
+        let hit = cache.get_at(&question, stored).unwrap();
+        assert!(matches!(&hit.answer, CachedAnswer::NoData { soa } if soa.ttl == 30));
+        assert!(cache.get_at(&question, stored + secs(29)).is_some());
+        assert!(cache.get_at(&question, stored + secs(30)).is_none());
+    }
+
+    #[test]
+    fn negative_answers_without_an_soa_are_not_cached() {
+        let cache = Cache::new(10, 0, 0);
+        let question = question("missing.example.com.");
+        cache.insert(
+            &question,
+            &reply(&question, RCODE_NAME_ERROR, vec![], vec![]),
+        );
+        cache.insert(
+            &question,
+            &reply(&question, RCODE_SERVER_FAILURE, vec![], vec![soa(60, 60)]),
+        );
+        assert!(cache.get(&question).is_none());
+    }
+
+    #[test]
+    fn positive_answers_keep_only_their_records() {
+        let cache = Cache::new(10, 0, 0);
+        let question = question("www.example.com.");
+        let stored = Instant::now();
+        let answer = reply(
+            &question,
+            RCODE_NO_ERROR,
+            vec![a("www.example.com.", 300), a("www.example.com.", 120)],
+            vec![soa(3600, 60)],
+        );
+        cache.insert_at(&question, &answer, stored);
+
+        let hit = cache.get_at(&question, stored + secs(100)).unwrap();
+        let CachedAnswer::Records(records) = hit.answer else {
+            panic!("{:?} has no records", hit.answer);
+        };
+        assert_eq!(records.len(), 2);
+        assert!(records.iter().all(|record| record.atype == Type::A));
+        // Every record expires along with the shortest lived
+        assert_eq!(records[0].ttl, 200);
+        assert!(cache.get_at(&question, stored + secs(120)).is_none());
+    }
+
+    #[test]
+    fn a_full_cache_evicts_the_entry_closest_to_expiring() {
+        let cache = Cache::new(3, 0, 0);
+        let stored = Instant::now();
+        let questions = ["a.example.", "b.example.", "c.example.", "d.example."].map(question);
+        for (question, ttl) in questions.iter().zip([300, 60, 600, 120]) {
+            let answer = reply(
+                question,
+                RCODE_NO_ERROR,
+                vec![a(&question.name.to_string(), ttl)],
+                vec![],
+            );
+            cache.insert_at(question, &answer, stored);
+        }
+
+        let cached = questions
+            .each_ref()
+            .map(|question| cache.get_at(question, stored).is_some());
+        assert_eq!(cached, [true, false, true, true]);
+
+        // Replacing an entry moves it in the expiry order rather than evicting another
+        let answer = reply(
+            &questions[2],
+            RCODE_NO_ERROR,
+            vec![a("c.example.", 10)],
+            vec![],
+        );
+        cache.insert_at(&questions[2], &answer, stored);
+        let answer = reply(
+            &questions[1],
+            RCODE_NO_ERROR,
+            vec![a("b.example.", 60)],
+            vec![],
+        );
+        cache.insert_at(&questions[1], &answer, stored);
+
+        let cached = questions
+            .each_ref()
+            .map(|question| cache.get_at(question, stored).is_some());
+        assert_eq!(cached, [true, true, false, true]);
+    }
+
+    #[test]
+    fn expired_answers_are_served_stale_within_the_window() {
+        let cache = Cache::new(10, 60, 0);
+        let question = question("www.example.com.");
+        let stored = Instant::now();
+        let answer = reply(
+            &question,
+            RCODE_NO_ERROR,
+            vec![a("www.example.com.", 300)],
+            vec![],
+        );
+        cache.insert_at(&question, &answer, stored);
+
+        // Fresh answers are not stale, but can stand in for a stale one all the same
+        let stale = cache.get_stale_at(&question, stored + secs(10)).unwrap();
+        assert!(matches!(&stale, CachedAnswer::Records(records) if records[0].ttl == 30));
+
+        assert!(cache.get_at(&question, stored + secs(300)).is_none());
+        let stale = cache.get_stale_at(&question, stored + secs(359)).unwrap();
+        assert!(matches!(&stale, CachedAnswer::Records(records) if records[0].ttl == 30));
+
+        assert!(cache.get_stale_at(&question, stored + secs(360)).is_none());
+        // Nor is it brought back by a lookup, which drops it
+        assert!(cache.get_at(&question, stored + secs(360)).is_none());
+        assert!(cache.get_stale_at(&question, stored + secs(300)).is_none());
+    }
+
+    #[test]
+    fn popular_entries_are_prefetched_once_near_expiry() {
+        let cache = Cache::new(10, 0, 10);
+        let question = question("www.example.com.");
+        let stored = Instant::now();
+        let answer = reply(
+            &question,
+            RCODE_NO_ERROR,
+            vec![a("www.example.com.", 100)],
+            vec![],
+        );
+        cache.insert_at(&question, &answer, stored);
+
+        let prefetch = |elapsed| {
+            cache
+                .get_at(&question, stored + secs(elapsed))
+                .unwrap()
+                .prefetch
+        };
+        // Not yet popular, then popular but not near expiry
+        assert!(!prefetch(91));
+        assert!(!prefetch(91));
+        assert!(!prefetch(50));
+        assert!(prefetch(91));
+        assert!(!prefetch(92));
+        assert!(!prefetch(99));
+        assert_eq!(cache.stats().prefetches, 1);
+
+        // A refresh that failed to replace the entry lets another be tried
+        cache.end_prefetch(&question);
+        assert!(prefetch(95));
+        assert!(!prefetch(95));
+    }
+}
@@ -0,0 +1,880 @@
+use std::{
+    collections::HashSet,
+    env, fmt, fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Deserializer, de};
+
+use crate::acl::{Acl, Network};
+use crate::dnstap;
+use crate::error::BoxError;
+use crate::forward::{ForwardMode, PartialFailure};
+use crate::log::{Format, Level};
+use crate::message::Name;
+use crate::upstream::Strategy;
+
+/// Address plain DNS is served on when none are given.
+const DEFAULT_LISTEN: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 2053);
+
+/// Address every question is answered with when there is nowhere to forward it to.
+pub const FALLBACK_ADDRESS: Ipv4Addr = Ipv4Addr::new(8, 8, 8, 8);
+
+/// TTL of the fallback answers.
+pub const FALLBACK_TTL: u32 = 60;
+
+/// The error for a flag with its value missing or malformed.
+fn invalid(flag: &str) -> BoxError {
+    format!("{flag} needs a valid value, see --help").into()
+}
+
+fn usage(program: &str) {
+    println!("Usage: {program} [options]");
+    println!();
+    println!("  --config <file>                  Read options from a TOML config file");
+    println!("  --listen <address>...            Serve plain DNS on an address");
+    println!("  --resolver <address>...          Forward queries to an upstream resolver");
+    println!("  --recursive, --no-recursive      Resolve queries from the root servers down");
+    println!("  --root-hint <address>...         Start recursive resolution from a root server");
+    println!("  --qname-minimisation, --no-qname-minimisation");
+    println!("                                   Send name servers only the labels they need");
+    println!("  --forward-zone <zone>=<address>[,...]...");
+    println!("                                   Forward a zone to its own resolvers");
+    println!("  --upstream-strategy <strategy>   Spread queries across the resolvers");
+    println!("  --forward-mode <mode>            Forward questions apart or messages whole");
+    println!("  --partial-failure <policy>       Reply when only some questions are answered");
+    println!("  --cache-capacity <answers>       Answers held in the cache");
+    println!("  --serve-stale <seconds>          Keep expired answers for failing upstreams");
+    println!("  --prefetch <percent>             Refresh popular answers near their expiry");
+    println!("  --allow <network>...             Answer only clients in a network");
+    println!("  --fallback-address <address>     Answer with an address without resolvers");
+    println!("  --fallback-ttl <seconds>         TTL of the address answered without resolvers");
+    println!("  --tls-listen <address>           Serve DNS over TLS on an address");
+    println!("  --https-listen <address>         Serve DNS over HTTPS on an address");
+    println!("  --quic-listen <address>          Serve DNS over QUIC on an address");
+    println!("  --tls-cert <file>                Certificate chain served over TLS");
+    println!("  --tls-key <file>                 Private key of the served certificate");
+    println!("  --tls-ca <file>                  Extra authorities trusted for TLS upstreams");
+    println!("  --metrics-listen <address>       Serve Prometheus metrics on an address");
+    println!("  --quiet, --no-quiet              Stop or start logging answered queries");
+    println!("  --log-level <level>              Least severe log entries written");
+    println!("  --log-format <format>            How log entries are written");
+    println!("  --log-file <file>                Log to a file instead of stdout");
+    println!("  --log-max-size <bytes>           Size the log file is rotated at");
+    println!("  --log-max-files <count>          Rotated log files kept");
+    println!("  --log-sample <fraction>          Fraction of answered queries logged");
+    println!("  --dnstap-socket <path>           Emit dnstap to a Unix socket");
+    println!("  --dnstap-file <path>             Emit dnstap to a file");
+    println!();
+    println!("Flags override the config file, repeatable ones (...) replacing all of its values");
+    println!("Plain DNS is served on {DEFAULT_LISTEN} unless --listen is given");
+    println!("Without resolvers or recursion, every question is answered with {FALLBACK_ADDRESS}");
+    println!("Strategies: failover (default), round-robin, lowest-rtt");
+    println!("Forward modes: split (default), passthrough");
+    println!("Partial failure policies: fail (default), partial");
+    println!("Resolvers given as tls://<host>:<port> are queried over TLS");
+    println!("Resolvers given as https://<host>[:<port>]/<path> are queried over HTTPS");
+    println!("Log levels: error, warn, info (default)");
+    println!("Log formats: text (default), json");
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// Addresses to serve plain DNS on.
+    pub listen: Vec<SocketAddr>,
+    /// Upstream resolvers that queries are forwarded to.
+    pub resolvers: Vec<String>,
+    /// Resolve queries without a forwarding route from the root servers down.
+    pub recursive: bool,
+    /// Root server addresses to start recursive resolution from, instead of the built-in ones.
+    pub root_hints: Vec<String>,
+    /// Send the full query name to every name server when resolving recursively.
+    pub disable_qname_minimisation: bool,
+    /// Zones whose queries go to their own upstream resolvers instead.
+    pub forward_zones: Vec<(Name<'static>, Vec<String>)>,
+    /// How queries are spread across the upstream resolvers.
+    pub strategy: Strategy,
+    /// How queries are sent to the upstream resolvers.
+    pub mode: ForwardMode,
+    /// What to reply when only some of the questions in a message could be answered.
+    pub partial_failure: PartialFailure,
+    /// Seconds that expired answers are kept to be served when the upstream fails.
+    pub stale_window: u32,
+    /// Popular answers are refreshed when served within this percentage of their TTL.
+    pub prefetch_percent: u32,
+    /// Address to serve DNS over TLS on.
+    pub tls_listen: Option<String>,
+    /// Address to serve DNS over HTTPS on.
+    pub https_listen: Option<String>,
+    /// Address to serve DNS over QUIC on.
+    pub quic_listen: Option<String>,
+    /// PEM file holding the certificate chain served over TLS.
+    pub tls_cert: Option<String>,
+    /// PEM file holding the private key of the served certificate.
+    pub tls_key: Option<String>,
+    /// PEM file holding extra certificate authorities trusted for TLS upstreams.
+    pub tls_ca: Option<String>,
+    /// Address to serve Prometheus metrics on, over plain HTTP.
+    pub metrics_listen: Option<String>,
+    /// Maximum number of answers held in the cache.
+    pub cache_capacity: Option<usize>,
+    /// Clients allowed to send queries.
+    pub acl: Acl,
+    /// Address answered to every question when there are no resolvers and no recursion.
+    pub fallback_address: Option<Ipv4Addr>,
+    /// TTL of the fallback answers.
+    pub fallback_ttl: Option<u32>,
+    /// Log every query answered.
+    pub log_queries: bool,
+    /// Least severe log entries that are written.
+    pub log_level: Level,
+    /// How log entries are written.
+    pub log_format: Format,
+    /// File to log to, instead of stdout.
+    pub log_file: Option<String>,
+    /// Size in bytes the log file may grow to before it is rotated.
+    pub log_max_size: Option<u64>,
+    /// Number of rotated log files kept.
+    pub log_max_files: Option<usize>,
+    /// Fraction of answered queries that are logged.
+    pub log_sample: Option<f64>,
+    /// Where client and forwarder messages are emitted as dnstap.
+    pub dnstap: Option<dnstap::Output>,
+}
+
+/// Parses a forwarding rule of the form `<zone>=<address>[,<address>...]`.
+fn parse_forward_zone(rule: &str) -> Option<(Name<'static>, Vec<String>)> {
+    let (zone, addrs) = rule.split_once('=')?;
+    let zone = zone.parse().ok()?;
+    let addrs: Vec<_> = addrs
+        .split(',')
+        .filter(|addr| !addr.is_empty())
+        .map(String::from)
+        .collect();
+    if addrs.is_empty() {
+        return None;
+    }
+
+    Some((zone, addrs))
+}
+
+/// Prints the usage if the command line asks for it, returning whether it did.
+pub fn help() -> bool {
+    let mut args = env::args();
+    let program = args.next().unwrap_or_default();
+    let asked = args.any(|arg| arg == "--help");
+    if asked {
+        usage(&program);
+    }
+
+    asked
+}
+
+/// Reads the configuration from the command line and the config file it names. Malformed
+/// flags are reported as errors, like an invalid config file, so that reloading never
+/// exits.
+pub fn read() -> Result<Config, BoxError> {
+    from_args(env::args())
+}
+
+/// Reads the configuration from `args`, the program name followed by its flags.
+pub fn from_args(args: impl Iterator<Item = String>) -> Result<Config, BoxError> {
+    let args: Vec<_> = args.skip(1).collect();
+
+    // The file is read first so that flags override it, wherever --config appears
+    let mut config = match args.iter().position(|arg| arg == "--config") {
+        Some(i) => match args.get(i + 1) {
+            Some(path) => load(path).map_err(|e| format!("invalid config file {path}: {e}"))?,
+            None => return Err(invalid("--config")),
+        },
+        None => Config {
+            log_queries: true,
+            ..Config::default()
+        },
+    };
+
+    // Repeatable flags replace the values from the file rather than adding to them
+    let mut overridden = HashSet::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if overridden.insert(arg.clone()) {
+            match arg.as_str() {
+                "--listen" => config.listen.clear(),
+                "--resolver" => config.resolvers.clear(),
+                "--root-hint" => config.root_hints.clear(),
+                "--forward-zone" => config.forward_zones.clear(),
+                "--allow" => config.acl = Acl::default(),
+                _ => {}
+            }
+        }
+
+        match arg.as_str() {
+            // Only asked for at startup, see help()
+            "--help" => {}
+
+            "--config" => {
+                args.next();
+            }
+
+            "--listen" => match args.next().and_then(|addr| addr.parse().ok()) {
+                Some(addr) => {
+                    config.listen.push(addr);
+                }
+                None => return Err(invalid(&arg)),
+            },
+
+            "--resolver" => match args.next() {
+                Some(addr) => {
+                    config.resolvers.push(addr);
+                }
+                None => return Err(invalid(&arg)),
+            },
+
+            "--recursive" => {
+                config.recursive = true;
+            }
+
+            "--no-recursive" => {
+                config.recursive = false;
+            }
+
+            "--root-hint" => match args.next() {
+                Some(addr) => {
+                    config.root_hints.push(addr);
+                }
+                None => return Err(invalid(&arg)),
+            },
+
+            "--qname-minimisation" => {
+                config.disable_qname_minimisation = false;
+            }
+
+            "--no-qname-minimisation" => {
+                config.disable_qname_minimisation = true;
+            }
+
+            "--forward-zone" => match args.next().as_deref().and_then(parse_forward_zone) {
+                Some(forward_zone) => {
+                    config.forward_zones.push(forward_zone);
+                }
+                None => return Err(invalid(&arg)),
+            },
+
+            "--upstream-strategy" => match args.next().and_then(|s| s.parse().ok()) {
+                Some(strategy) => {
+                    config.strategy = strategy;
+                }
+                None => return Err(invalid(&arg)),
+            },
+
+            "--forward-mode" => match args.next().and_then(|s| s.parse().ok()) {
+                Some(mode) => {
+                    config.mode = mode;
+                }
+                None => return Err(invalid(&arg)),
+            },
+
+            "--partial-failure" => match args.next().and_then(|s| s.parse().ok()) {
+                Some(policy) => {
+                    config.partial_failure = policy;
+                }
+                None => return Err(invalid(&arg)),
+            },
+
+            "--serve-stale" => match args.next().and_then(|secs| secs.parse().ok()) {
+                Some(secs) => {
+                    config.stale_window = secs;
+                }
+                None => return Err(invalid(&arg)),
+            },
+
+            "--prefetch" => match args.next().and_then(|percent| percent.parse().ok()) {
+                Some(percent @ 0..=100) => {
+                    config.prefetch_percent = percent;
+                }
+                _ => return Err(invalid(&arg)),
+            },
+
+            "--cache-capacity" => match args.next().and_then(|answers| answers.parse().ok()) {
+                Some(answers) => {
+                    config.cache_capacity = Some(answers);
+                }
+                None => return Err(invalid(&arg)),
+            },
+
+            "--allow" => match args.next().and_then(|network| network.parse().ok()) {
+                Some(network) => {
+                    config.acl.allow(network);
+                }
+                None => return Err(invalid(&arg)),
+            },
+
+            "--quiet" => {
+                config.log_queries = false;
+            }
+
+            "--no-quiet" => {
+                config.log_queries = true;
+            }
+
+            "--fallback-address" => match args.next().and_then(|addr| addr.parse().ok()) {
+                Some(addr) => {
+                    config.fallback_address = Some(addr);
+                }
+                None => return Err(invalid(&arg)),
+            },
+
+            "--fallback-ttl" => match args.next().and_then(|secs| secs.parse().ok()) {
+                Some(secs) => {
+                    config.fallback_ttl = Some(secs);
+                }
+                None => return Err(invalid(&arg)),
+            },
+
+            "--tls-listen" => match args.next() {
+                Some(addr) => {
+                    config.tls_listen = Some(addr);
+                }
+                None => return Err(invalid(&arg)),
+            },
+
+            "--https-listen" => match args.next() {
+                Some(addr) => {
+                    config.https_listen = Some(addr);
+                }
+                None => return Err(invalid(&arg)),
+            },
+
+            "--quic-listen" => match args.next() {
+                Some(addr) => {
+                    config.quic_listen = Some(addr);
+                }
+                None => return Err(invalid(&arg)),
+            },
+
+            "--tls-cert" => match args.next() {
+                Some(path) => {
+                    config.tls_cert = Some(path);
+                }
+                None => return Err(invalid(&arg)),
+            },
+
+            "--tls-key" => match args.next() {
+                Some(path) => {
+                    config.tls_key = Some(path);
+                }
+                None => return Err(invalid(&arg)),
+            },
+
+            "--tls-ca" => match args.next() {
+                Some(path) => {
+                    config.tls_ca = Some(path);
+                }
+                None => return Err(invalid(&arg)),
+            },
+
+            "--log-level" => match args.next().and_then(|level| level.parse().ok()) {
+                Some(level) => {
+                    config.log_level = level;
+                }
+                None => return Err(invalid(&arg)),
+            },
+
+            "--log-format" => match args.next().and_then(|format| format.parse().ok()) {
+                Some(format) => {
+                    config.log_format = format;
+                }
+                None => return Err(invalid(&arg)),
+            },
+
+            "--log-file" => match args.next() {
+                Some(path) => {
+                    config.log_file = Some(path);
+                }
+                None => return Err(invalid(&arg)),
+            },
+
+            "--log-max-size" => match args.next().and_then(|bytes| bytes.parse().ok()) {
+                Some(bytes) => {
+                    config.log_max_size = Some(bytes);
+                }
+                None => return Err(invalid(&arg)),
+            },
+
+            "--log-max-files" => match args.next().and_then(|count| count.parse().ok()) {
+                Some(count) => {
+                    config.log_max_files = Some(count);
+                }
+                None => return Err(invalid(&arg)),
+            },
+
+            "--log-sample" => match args.next().and_then(|fraction| fraction.parse().ok()) {
+                Some(fraction @ 0.0..=1.0) => {
+                    config.log_sample = Some(fraction);
+                }
+                _ => return Err(invalid(&arg)),
+            },
+
+            "--dnstap-socket" => match args.next() {
+                Some(path) => {
+                    config.dnstap = Some(dnstap::Output::Socket(path));
+                }
+                None => return Err(invalid(&arg)),
+            },
+
+            "--dnstap-file" => match args.next() {
+                Some(path) => {
+                    config.dnstap = Some(dnstap::Output::File(path));
+                }
+                None => return Err(invalid(&arg)),
+            },
+
+            "--metrics-listen" => match args.next() {
+                Some(addr) => {
+                    config.metrics_listen = Some(addr);
+                }
+                None => return Err(invalid(&arg)),
+            },
+
+            _ => return Err(format!("unknown option {arg}, see --help").into()),
+        }
+    }
+
+    if config.listen.is_empty() {
+        config.listen.push(DEFAULT_LISTEN);
+    }
+
+    // Recursion only covers queries without a forwarding route
+    if config.recursive && !config.resolvers.is_empty() {
+        return Err(
+            "upstream.recursive (--recursive) cannot be combined with upstream.resolvers (--resolver)".into(),
+        );
+    }
+
+    // Serving over TLS needs a certificate to present
+    if (config.tls_listen.is_some()
+        || config.https_listen.is_some()
+        || config.quic_listen.is_some())
+        && (config.tls_cert.is_none() || config.tls_key.is_none())
+    {
+        return Err("listen.tls, listen.https and listen.quic (--tls-listen, --https-listen, --quic-listen) need tls.cert and tls.key (--tls-cert, --tls-key)".into());
+    }
+
+    Ok(config)
+}
+
+/// The config file, which mirrors the command line flags:
+///
+/// ```toml
+/// [listen]
+/// udp = ["0.0.0.0:53", "[::]:53"]
+/// tls = "0.0.0.0:853"
+/// https = "0.0.0.0:443"
+/// quic = "0.0.0.0:853"
+/// metrics = "127.0.0.1:9153"
+///
+/// [tls]
+/// cert = "cert.pem"
+/// key = "key.pem"
+/// ca = "ca.pem"
+///
+/// [upstream]
+/// resolvers = ["1.1.1.1:53", "tls://one.one.one.one:853"]
+/// strategy = "lowest-rtt"
+/// mode = "split"
+/// partial_failure = "fail"
+///
+/// [[zones]]
+/// zone = "corp.example"
+/// resolvers = ["10.0.0.1:53"]
+///
+/// [cache]
+/// capacity = 10000
+/// serve_stale = 3600
+/// prefetch = 10
+///
+/// [acl]
+/// allow = ["127.0.0.0/8", "::1"]
+///
+/// [fallback]
+/// address = "8.8.8.8"
+/// ttl = 60
+///
+/// [log]
+/// queries = true
+/// level = "warn"
+/// format = "json"
+/// file = "/var/log/dns/queries.log"
+/// max_size = 104857600
+/// max_files = 5
+/// sample = 0.1
+///
+/// [dnstap]
+/// socket = "/run/dnstap.sock"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct File {
+    listen: ListenSection,
+    tls: TlsSection,
+    upstream: UpstreamSection,
+    zones: Vec<ZoneSection>,
+    cache: CacheSection,
+    acl: AclSection,
+    fallback: FallbackSection,
+    log: LogSection,
+    dnstap: DnstapSection,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ListenSection {
+    udp: Vec<SocketAddr>,
+    tls: Option<String>,
+    https: Option<String>,
+    quic: Option<String>,
+    metrics: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TlsSection {
+    cert: Option<String>,
+    key: Option<String>,
+    ca: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct UpstreamSection {
+    resolvers: Vec<String>,
+    #[serde(deserialize_with = "parsed")]
+    strategy: Strategy,
+    #[serde(deserialize_with = "parsed")]
+    mode: ForwardMode,
+    #[serde(deserialize_with = "parsed")]
+    partial_failure: PartialFailure,
+    recursive: bool,
+    root_hints: Vec<String>,
+    qname_minimisation: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ZoneSection {
+    #[serde(deserialize_with = "parsed")]
+    zone: Name<'static>,
+    resolvers: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CacheSection {
+    capacity: Option<usize>,
+    serve_stale: u32,
+    prefetch: u32,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct AclSection {
+    allow: Vec<Parsed<Network>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FallbackSection {
+    address: Option<Ipv4Addr>,
+    ttl: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LogSection {
+    queries: bool,
+    #[serde(deserialize_with = "parsed")]
+    level: Level,
+    #[serde(deserialize_with = "parsed")]
+    format: Format,
+    file: Option<String>,
+    max_size: Option<u64>,
+    max_files: Option<usize>,
+    sample: Option<f64>,
+}
+
+impl Default for LogSection {
+    fn default() -> Self {
+        Self {
+            queries: true,
+            level: Level::default(),
+            format: Format::default(),
+            file: None,
+            max_size: None,
+            max_files: None,
+            sample: None,
+        }
+    }
+}
+
+/// Where dnstap is emitted: a Unix socket or a file, not both.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct DnstapSection {
+    socket: Option<String>,
+    file: Option<String>,
+}
+
+/// Reads the config file at `path`. Errors name the offending key, and also show where
+/// in the file it is when the file could not be parsed.
+fn load(path: &str) -> Result<Config, BoxError> {
+    from_toml(&fs::read_to_string(path)?)
+}
+
+/// Reads a config file's contents.
+fn from_toml(contents: &str) -> Result<Config, BoxError> {
+    let file: File = toml::from_str(contents)?;
+
+    for (i, zone) in file.zones.iter().enumerate() {
+        if zone.resolvers.is_empty() {
+            return Err(
+                format!("zones[{i}].resolvers: no resolvers for zone {}", zone.zone).into(),
+            );
+        }
+    }
+    if file.cache.prefetch > 100 {
+        return Err("cache.prefetch: not a percentage".into());
+    }
+    if file
+        .log
+        .sample
+        .is_some_and(|sample| !(0.0..=1.0).contains(&sample))
+    {
+        return Err("log.sample: not a fraction between 0 and 1".into());
+    }
+    let dnstap = match (file.dnstap.socket, file.dnstap.file) {
+        (Some(_), Some(_)) => {
+            return Err("dnstap.socket and dnstap.file cannot be combined".into());
+        }
+        (Some(path), None) => Some(dnstap::Output::Socket(path)),
+        (None, Some(path)) => Some(dnstap::Output::File(path)),
+        (None, None) => None,
+    };
+
+    Ok(Config {
+        listen: file.listen.udp,
+        resolvers: file.upstream.resolvers,
+        recursive: file.upstream.recursive,
+        root_hints: file.upstream.root_hints,
+        disable_qname_minimisation: !file.upstream.qname_minimisation.unwrap_or(true),
+        forward_zones: file
+            .zones
+            .into_iter()
+            .map(|zone| (zone.zone, zone.resolvers))
+            .collect(),
+        strategy: file.upstream.strategy,
+        mode: file.upstream.mode,
+        partial_failure: file.upstream.partial_failure,
+        stale_window: file.cache.serve_stale,
+        prefetch_percent: file.cache.prefetch,
+        tls_listen: file.listen.tls,
+        https_listen: file.listen.https,
+        quic_listen: file.listen.quic,
+        tls_cert: file.tls.cert,
+        tls_key: file.tls.key,
+        tls_ca: file.tls.ca,
+        metrics_listen: file.listen.metrics,
+        cache_capacity: file.cache.capacity,
+        acl: Acl::new(
+            file.acl
+                .allow
+                .into_iter()
+                .map(|Parsed(network)| network)
+                .collect(),
+        ),
+        fallback_address: file.fallback.address,
+        fallback_ttl: file.fallback.ttl,
+        log_queries: file.log.queries,
+        log_level: file.log.level,
+        log_format: file.log.format,
+        log_file: file.log.file,
+        log_max_size: file.log.max_size,
+        log_max_files: file.log.max_files,
+        log_sample: file.log.sample,
+        dnstap,
+    })
+}
+
+/// Deserializes a value from a string, in the same form as given on the command line.
+fn parsed<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(de::Error::custom)
+}
+
+/// A value deserialized like [parsed], for use inside lists.
+#[derive(Debug)]
+struct Parsed<T>(T);
+
+impl<'de, T> Deserialize<'de> for Parsed<T>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        parsed(deserializer).map(Parsed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+
+    #[test]
+    fn files_are_read_into_the_config() {
+        let config = from_toml(
+            r#"
+            [listen]
+            udp = ["127.0.0.1:5353", "[::1]:5353"]
+            metrics = "127.0.0.1:9153"
+
+            [upstream]
+            resolvers = ["1.1.1.1:53", "tls://one.one.one.one:853"]
+            strategy = "lowest-rtt"
+            mode = "passthrough"
+            qname_minimisation = false
+
+            [[zones]]
+            zone = "corp.example"
+            resolvers = ["10.0.0.1:53"]
+
+            [cache]
+            capacity = 500
+            serve_stale = 3600
+
+            [acl]
+            allow = ["127.0.0.0/8", "::1"]
+
+            [fallback]
+            address = "192.0.2.1"
+
+            [log]
+            queries = false
+            level = "warn"
+            format = "json"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.listen.len(), 2);
+        assert_eq!(config.metrics_listen.as_deref(), Some("127.0.0.1:9153"));
+        assert_eq!(
+            config.resolvers,
+            ["1.1.1.1:53", "tls://one.one.one.one:853"]
+        );
+        assert_eq!(config.strategy, Strategy::LowestRtt);
+        assert_eq!(config.mode, ForwardMode::Passthrough);
+        assert!(config.disable_qname_minimisation);
+        assert_eq!(config.forward_zones[0].0.to_string(), "corp.example.");
+        assert_eq!(config.forward_zones[0].1, ["10.0.0.1:53"]);
+        assert_eq!(config.cache_capacity, Some(500));
+        assert_eq!(config.stale_window, 3600);
+        assert_eq!(config.prefetch_percent, 0);
+        assert!(config.acl.allows("127.0.0.53".parse().unwrap()));
+        assert!(!config.acl.allows("192.0.2.1".parse().unwrap()));
+        assert_eq!(config.fallback_address, Some(Ipv4Addr::new(192, 0, 2, 1)));
+        assert_eq!(config.fallback_ttl, None);
+        assert!(!config.log_queries);
+        assert_eq!(config.log_level, Level::Warn);
+        assert_eq!(config.log_format, Format::Json);
+    }
+
+    #[test]
+    fn errors_name_the_offending_key() {
+        let error = |contents| from_toml(contents).unwrap_err().to_string();
+
+        let unknown = error("[cache]\ncapacty = 10\n");
+        assert!(unknown.contains("capacty"), "{unknown}");
+        let unknown = error("[caches]\n");
+        assert!(unknown.contains("caches"), "{unknown}");
+
+        let invalid = error("[upstream]\nstrategy = \"random\"\n");
+        assert!(invalid.contains("strategy"), "{invalid}");
+        assert!(invalid.contains("random"), "{invalid}");
+
+        let invalid = error("[cache]\nprefetch = 150\n");
+        assert!(invalid.starts_with("cache.prefetch"), "{invalid}");
+        let invalid = error("[[zones]]\nzone = \"corp.example\"\nresolvers = []\n");
+        assert!(invalid.starts_with("zones[0].resolvers"), "{invalid}");
+    }
+
+    #[test]
+    fn flags_override_the_file() {
+        let path = env::temp_dir().join(format!("dns-server-config-{}.toml", process::id()));
+        fs::write(
+            &path,
+            r#"
+            [listen]
+            udp = ["127.0.0.1:5353", "127.0.0.1:5354"]
+
+            [upstream]
+            recursive = true
+            qname_minimisation = false
+
+            [cache]
+            serve_stale = 10
+            prefetch = 5
+
+            [log]
+            queries = true
+            "#,
+        )
+        .unwrap();
+
+        let args = [
+            "dns-server",
+            "--serve-stale",
+            "20",
+            "--listen",
+            "127.0.0.1:5355",
+            "--config",
+            path.to_str().unwrap(),
+            "--no-recursive",
+            "--qname-minimisation",
+            "--quiet",
+        ];
+        let config = from_args(args.into_iter().map(String::from));
+        fs::remove_file(&path).unwrap();
+        let config = config.unwrap();
+
+        assert_eq!(config.listen, ["127.0.0.1:5355".parse().unwrap()]);
+        assert_eq!(config.stale_window, 20);
+        assert!(!config.recursive);
+        assert!(!config.disable_qname_minimisation);
+        assert!(!config.log_queries);
+        // Values without a flag are kept from the file
+        assert_eq!(config.prefetch_percent, 5);
+    }
+
+    #[test]
+    fn malformed_flags_are_errors() {
+        for (args, expected) in [
+            (&["--listen"][..], "--listen needs a valid value"),
+            (&["--prefetch", "101"], "--prefetch needs a valid value"),
+            (&["--log-level", "loud"], "--log-level needs a valid value"),
+            (&["--config"], "--config needs a valid value"),
+            (&["--frobnicate"], "unknown option --frobnicate"),
+        ] {
+            let args = ["dns-server"].iter().chain(args).map(|arg| arg.to_string());
+            let error = from_args(args).unwrap_err().to_string();
+            assert!(error.starts_with(expected), "{error}");
+        }
+    }
+}
@@ -0,0 +1,583 @@
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter},
+    net::{UnixStream, unix::OwnedReadHalf},
+    sync::{
+        mpsc::{self, error::TryRecvError, error::TrySendError},
+        oneshot,
+    },
+    time::{self, Instant},
+};
+
+use crate::log::Logger;
+use crate::metrics::ClientTransport;
+
+/// Content type of the frames, as agreed with the receiver (Frame Streams).
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+/// Control frame types and fields of the Frame Streams protocol.
+const CONTROL_ACCEPT: u32 = 0x01;
+const CONTROL_START: u32 = 0x02;
+const CONTROL_STOP: u32 = 0x03;
+const CONTROL_READY: u32 = 0x04;
+const CONTROL_FINISH: u32 = 0x05;
+const CONTROL_FIELD_CONTENT_TYPE: u32 = 0x01;
+
+/// Largest control frame accepted from the receiver.
+const MAX_CONTROL_FRAME_SIZE: usize = 512;
+
+/// Number of messages that may be waiting to be written. Messages beyond that are dropped,
+/// rather than holding up queries.
+const QUEUE_SIZE: usize = 10_000;
+
+/// How long to wait before connecting to the socket again after failing to.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long the receiver has to acknowledge the handshake, and the end of the stream.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The kinds of message emitted, as numbered in `dnstap.proto`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    ClientQuery = 5,
+    ClientResponse = 6,
+    ForwarderQuery = 7,
+    ForwarderResponse = 8,
+}
+
+/// The protocol a message was sent over, as numbered in `dnstap.proto`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketProtocol {
+    Udp = 1,
+    Tcp = 2,
+    Dot = 3,
+    Doh = 4,
+    Doq = 7,
+}
+
+impl From<ClientTransport> for SocketProtocol {
+    fn from(transport: ClientTransport) -> Self {
+        match transport {
+            ClientTransport::Udp => SocketProtocol::Udp,
+            ClientTransport::Tls => SocketProtocol::Dot,
+            ClientTransport::Https => SocketProtocol::Doh,
+            ClientTransport::Quic => SocketProtocol::Doq,
+        }
+    }
+}
+
+/// A DNS message seen by the server, with the addresses and times of the exchange.
+#[derive(Debug)]
+pub struct Message<'a> {
+    pub message_type: MessageType,
+    pub protocol: SocketProtocol,
+    /// The side that sent the query, when known.
+    pub query_address: Option<SocketAddr>,
+    /// The side that answers the query, when known.
+    pub response_address: Option<SocketAddr>,
+    pub query_time: SystemTime,
+    pub query_message: Option<&'a [u8]>,
+    pub response_time: Option<SystemTime>,
+    pub response_message: Option<&'a [u8]>,
+}
+
+/// Where dnstap messages are written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Output {
+    /// A Unix socket that a collector listens on, spoken to bidirectionally.
+    Socket(String),
+    /// A file, which is started afresh.
+    File(String),
+}
+
+#[derive(Debug)]
+enum Command {
+    Frame(Vec<u8>),
+    Finish(oneshot::Sender<()>),
+}
+
+/// Emits dnstap messages (<https://dnstap.info>) to a socket or file, framed with Frame
+/// Streams. Messages are written by a task of their own, so emitting them never waits.
+#[derive(Debug)]
+pub struct Dnstap {
+    commands: mpsc::Sender<Command>,
+    dropped: AtomicU64,
+}
+
+impl Dnstap {
+    pub fn new(output: Output, log: Arc<Logger>) -> Self {
+        let (commands, receiver) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(write_frames(output, receiver, log));
+
+        Self {
+            commands,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Queues `message` to be written, dropping it if the output is falling behind.
+    pub fn emit(&self, message: &Message<'_>) {
+        match self.commands.try_send(Command::Frame(encode(message))) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(TrySendError::Closed(_)) => {}
+        }
+    }
+
+    /// Number of messages dropped because the output was falling behind.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Writes out the messages queued so far and ends the stream.
+    pub async fn finish(&self) {
+        let (done, finished) = oneshot::channel();
+        if self.commands.send(Command::Finish(done)).await.is_ok() {
+            let _ = finished.await;
+        }
+    }
+}
+
+/// An open output, with the start of the stream written.
+struct Connection {
+    writer: BufWriter<Box<dyn AsyncWrite + Send + Unpin>>,
+    /// The receiving end of a socket, over which the end of the stream is acknowledged.
+    reader: Option<OwnedReadHalf>,
+}
+
+impl Connection {
+    async fn open(output: &Output) -> io::Result<Self> {
+        match output {
+            Output::Socket(path) => {
+                let (mut reader, writer) = UnixStream::connect(path).await?.into_split();
+                let mut writer = BufWriter::new(Box::new(writer) as Box<_>);
+
+                writer.write_all(&control_frame(CONTROL_READY)).await?;
+                writer.flush().await?;
+                let accept = time::timeout(HANDSHAKE_TIMEOUT, read_control_frame(&mut reader))
+                    .await
+                    .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+                if accept != CONTROL_ACCEPT {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "receiver did not accept the stream",
+                    ));
+                }
+
+                writer.write_all(&control_frame(CONTROL_START)).await?;
+                Ok(Self {
+                    writer,
+                    reader: Some(reader),
+                })
+            }
+            Output::File(path) => {
+                let file = File::create(path).await?;
+                let mut writer = BufWriter::new(Box::new(file) as Box<_>);
+                writer.write_all(&control_frame(CONTROL_START)).await?;
+                Ok(Self {
+                    writer,
+                    reader: None,
+                })
+            }
+        }
+    }
+
+    async fn write(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.writer
+            .write_all(&(frame.len() as u32).to_be_bytes())
+            .await?;
+        self.writer.write_all(frame).await
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush().await
+    }
+
+    /// Ends the stream, waiting for a socket's receiver to acknowledge it.
+    async fn finish(mut self) -> io::Result<()> {
+        self.writer.write_all(&control_frame(CONTROL_STOP)).await?;
+        self.writer.flush().await?;
+        if let Some(reader) = &mut self.reader {
+            match time::timeout(HANDSHAKE_TIMEOUT, read_control_frame(reader)).await {
+                Ok(Ok(CONTROL_FINISH)) => {}
+                _ => {
+                    return Err(io::Error::other(
+                        "receiver did not acknowledge the end of the stream",
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes out frames as they are queued. A socket that cannot be reached is retried every
+/// so often, and messages are dropped in the meantime. A file is only opened once.
+async fn write_frames(output: Output, mut commands: mpsc::Receiver<Command>, log: Arc<Logger>) {
+    let mut connection: Option<Connection> = None;
+    let mut retry_at = Some(Instant::now());
+    loop {
+        let command = match commands.try_recv() {
+            Ok(command) => command,
+            Err(TryRecvError::Empty) => {
+                // Nothing else is waiting, so this is the time to write out what was
+                if let Some(open) = &mut connection {
+                    if let Err(e) = open.flush().await {
+                        log.error(format_args!("Error writing dnstap output: {e}"));
+                        connection = None;
+                        retry_at = retry_after(&output);
+                    }
+                }
+                match commands.recv().await {
+                    Some(command) => command,
+                    None => return,
+                }
+            }
+            Err(TryRecvError::Disconnected) => return,
+        };
+
+        if connection.is_none() && retry_at.is_some_and(|retry_at| Instant::now() >= retry_at) {
+            match Connection::open(&output).await {
+                Ok(open) => connection = Some(open),
+                Err(e) => {
+                    log.error(format_args!("Error opening dnstap output: {e}"));
+                    retry_at = retry_after(&output);
+                }
+            }
+        }
+
+        match command {
+            Command::Frame(frame) => {
+                let Some(open) = &mut connection else {
+                    continue;
+                };
+                if let Err(e) = open.write(&frame).await {
+                    log.error(format_args!("Error writing dnstap output: {e}"));
+                    connection = None;
+                    retry_at = retry_after(&output);
+                }
+            }
+            Command::Finish(done) => {
+                if let Some(open) = connection.take() {
+                    if let Err(e) = open.finish().await {
+                        log.error(format_args!("Error ending dnstap output: {e}"));
+                    }
+                }
+                let _ = done.send(());
+                return;
+            }
+        }
+    }
+}
+
+/// When to try opening `output` again after it failed, if ever. Reopening a file would
+/// start it afresh, losing what was written.
+fn retry_after(output: &Output) -> Option<Instant> {
+    match output {
+        Output::Socket(_) => Some(Instant::now() + RECONNECT_INTERVAL),
+        Output::File(_) => None,
+    }
+}
+
+/// Builds a control frame of `control_type`, carrying the content type where the
+/// protocol calls for it.
+fn control_frame(control_type: u32) -> Vec<u8> {
+    let mut payload = control_type.to_be_bytes().to_vec();
+    if matches!(control_type, CONTROL_READY | CONTROL_START) {
+        payload.extend_from_slice(&CONTROL_FIELD_CONTENT_TYPE.to_be_bytes());
+        payload.extend_from_slice(&(CONTENT_TYPE.len() as u32).to_be_bytes());
+        payload.extend_from_slice(CONTENT_TYPE);
+    }
+
+    // Control frames are told apart from data frames by a zero length
+    let mut frame = 0u32.to_be_bytes().to_vec();
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+/// Reads a control frame from the receiver and returns its type.
+async fn read_control_frame(reader: &mut OwnedReadHalf) -> io::Result<u32> {
+    if reader.read_u32().await? != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "expected a control frame",
+        ));
+    }
+    let len = reader.read_u32().await? as usize;
+    if !(4..=MAX_CONTROL_FRAME_SIZE).contains(&len) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "control frame of invalid length",
+        ));
+    }
+
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload).await?;
+    Ok(u32::from_be_bytes([
+        payload[0], payload[1], payload[2], payload[3],
+    ]))
+}
+
+/// Encodes `message` as a `Dnstap` protobuf message.
+fn encode(message: &Message<'_>) -> Vec<u8> {
+    let mut inner = Vec::with_capacity(256);
+    varint_field(&mut inner, 1, message.message_type as u64);
+    let family = message.query_address.or(message.response_address);
+    if let Some(family) = family {
+        varint_field(&mut inner, 2, if family.is_ipv4() { 1 } else { 2 });
+    }
+    varint_field(&mut inner, 3, message.protocol as u64);
+    if let Some(address) = message.query_address {
+        bytes_field(&mut inner, 4, &ip_bytes(address));
+        varint_field(&mut inner, 6, u64::from(address.port()));
+    }
+    if let Some(address) = message.response_address {
+        bytes_field(&mut inner, 5, &ip_bytes(address));
+        varint_field(&mut inner, 7, u64::from(address.port()));
+    }
+    let (secs, nanos) = since_epoch(message.query_time);
+    varint_field(&mut inner, 8, secs);
+    fixed32_field(&mut inner, 9, nanos);
+    if let Some(query) = message.query_message {
+        bytes_field(&mut inner, 10, query);
+    }
+    if let Some(response_time) = message.response_time {
+        let (secs, nanos) = since_epoch(response_time);
+        varint_field(&mut inner, 12, secs);
+        fixed32_field(&mut inner, 13, nanos);
+    }
+    if let Some(response) = message.response_message {
+        bytes_field(&mut inner, 14, response);
+    }
+
+    let mut outer = Vec::with_capacity(inner.len() + 64);
+    bytes_field(
+        &mut outer,
+        2,
+        concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION")).as_bytes(),
+    );
+    bytes_field(&mut outer, 14, &inner);
+    // The only type of Dnstap message there is: MESSAGE
+    varint_field(&mut outer, 15, 1);
+    outer
+}
+
+fn ip_bytes(address: SocketAddr) -> Vec<u8> {
+    match address {
+        SocketAddr::V4(address) => address.ip().octets().to_vec(),
+        SocketAddr::V6(address) => address.ip().octets().to_vec(),
+    }
+}
+
+fn since_epoch(time: SystemTime) -> (u64, u32) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    (since_epoch.as_secs(), since_epoch.subsec_nanos())
+}
+
+fn varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn varint_field(buf: &mut Vec<u8>, field: u64, value: u64) {
+    varint(buf, field << 3);
+    varint(buf, value);
+}
+
+fn fixed32_field(buf: &mut Vec<u8>, field: u64, value: u32) {
+    varint(buf, field << 3 | 5);
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn bytes_field(buf: &mut Vec<u8>, field: u64, value: &[u8]) {
+    varint(buf, field << 3 | 2);
+    varint(buf, value.len() as u64);
+    buf.extend_from_slice(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, process};
+
+    use tokio::net::UnixListener;
+
+    use super::*;
+    use crate::testing;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("dns-server-dnstap-{}-{name}", process::id()))
+    }
+
+    fn message() -> Message<'static> {
+        Message {
+            message_type: MessageType::ClientResponse,
+            protocol: SocketProtocol::Udp,
+            query_address: Some("192.0.2.1:5353".parse().unwrap()),
+            response_address: Some("192.0.2.53:53".parse().unwrap()),
+            query_time: UNIX_EPOCH + Duration::new(1_700_000_000, 0x0102_0304),
+            query_message: Some(&[0x12, 0x34]),
+            response_time: Some(UNIX_EPOCH + Duration::from_secs(1_700_000_001)),
+            response_message: Some(&[0xaa, 0xbb, 0xcc]),
+        }
+    }
+
+    /// Reads a frame, returning its payload, and whether it is a control frame.
+    async fn read_frame(stream: &mut (impl AsyncReadExt + Unpin)) -> (bool, Vec<u8>) {
+        let mut len = stream.read_u32().await.unwrap();
+        let control = len == 0;
+        if control {
+            len = stream.read_u32().await.unwrap();
+        }
+        let mut payload = vec![0; len as usize];
+        stream.read_exact(&mut payload).await.unwrap();
+        (control, payload)
+    }
+
+    /// The payload of a control frame of `control_type`, with the content type if given.
+    fn control(control_type: u32, content_type: bool) -> Vec<u8> {
+        let mut payload = control_type.to_be_bytes().to_vec();
+        if content_type {
+            payload.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 22]);
+            payload.extend_from_slice(b"protobuf:dnstap.Dnstap");
+        }
+        payload
+    }
+
+    #[test]
+    fn messages_are_encoded_as_dnstap_protobufs() {
+        #[rustfmt::skip]
+        let message = [
+            0x08, 0x06, // type: CLIENT_RESPONSE
+            0x10, 0x01, // socket_family: INET
+            0x18, 0x01, // socket_protocol: UDP
+            0x22, 0x04, 192, 0, 2, 1, // query_address
+            0x30, 0xe9, 0x29, // query_port: 5353
+            0x2a, 0x04, 192, 0, 2, 53, // response_address
+            0x38, 0x35, // response_port: 53
+            0x40, 0x80, 0xe2, 0xcf, 0xaa, 0x06, // query_time_sec
+            0x4d, 0x04, 0x03, 0x02, 0x01, // query_time_nsec, fixed32
+            0x52, 0x02, 0x12, 0x34, // query_message
+            0x60, 0x81, 0xe2, 0xcf, 0xaa, 0x06, // response_time_sec
+            0x6d, 0x00, 0x00, 0x00, 0x00, // response_time_nsec, fixed32
+            0x72, 0x03, 0xaa, 0xbb, 0xcc, // response_message
+        ];
+        let identity = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+        let mut dnstap = vec![0x12, identity.len() as u8];
+        dnstap.extend_from_slice(identity.as_bytes());
+        dnstap.extend_from_slice(&[0x72, message.len() as u8]);
+        dnstap.extend_from_slice(&message);
+        dnstap.extend_from_slice(&[0x78, 0x01]); // type: MESSAGE
+
+        assert_eq!(encode(&self::message()), dnstap);
+    }
+
+    #[tokio::test]
+    async fn sockets_are_spoken_to_bidirectionally() {
+        let path = temp_path("socket");
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let collector = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut frames = vec![read_frame(&mut stream).await];
+            stream
+                .write_all(&control_frame(CONTROL_ACCEPT))
+                .await
+                .unwrap();
+            loop {
+                let frame = read_frame(&mut stream).await;
+                let stop = frame == (true, control(CONTROL_STOP, false));
+                frames.push(frame);
+                if stop {
+                    break;
+                }
+            }
+            stream
+                .write_all(&control_frame(CONTROL_FINISH))
+                .await
+                .unwrap();
+            frames
+        });
+
+        let dnstap = Dnstap::new(
+            Output::Socket(path.to_string_lossy().into()),
+            testing::logger(),
+        );
+        dnstap.emit(&message());
+        time::timeout(Duration::from_secs(5), dnstap.finish())
+            .await
+            .unwrap();
+        let frames = collector.await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            frames,
+            [
+                (true, control(CONTROL_READY, true)),
+                (true, control(CONTROL_START, true)),
+                (false, encode(&message())),
+                (true, control(CONTROL_STOP, false)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn files_hold_the_frames_between_start_and_stop() {
+        let path = temp_path("file");
+        let dnstap = Dnstap::new(
+            Output::File(path.to_string_lossy().into()),
+            testing::logger(),
+        );
+        dnstap.emit(&message());
+        let mut other = message();
+        other.message_type = MessageType::ForwarderQuery;
+        dnstap.emit(&other);
+        dnstap.finish().await;
+
+        let contents = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut contents = &contents[..];
+        let mut frames = Vec::new();
+        while !contents.is_empty() {
+            frames.push(read_frame(&mut contents).await);
+        }
+        assert_eq!(
+            frames,
+            [
+                (true, control(CONTROL_START, true)),
+                (false, encode(&message())),
+                (false, encode(&other)),
+                (true, control(CONTROL_STOP, false)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn messages_are_dropped_and_counted_when_the_queue_is_full() {
+        let (commands, _receiver) = mpsc::channel(2);
+        let dnstap = Dnstap {
+            commands,
+            dropped: AtomicU64::new(0),
+        };
+        for _ in 0..5 {
+            dnstap.emit(&message());
+        }
+        assert_eq!(dnstap.dropped(), 3);
+    }
+}
@@ -0,0 +1,613 @@
+use std::{
+    convert::Infallible,
+    io::{self, Cursor},
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{
+    Method, Request, Response, StatusCode, Uri,
+    body::{Body, Frame, Incoming, SizeHint},
+    client,
+    header::{ACCEPT, CACHE_CONTROL, CONTENT_TYPE, HeaderValue},
+    server::conn::http2,
+    service::service_fn,
+};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+    time,
+};
+use tokio_rustls::{
+    TlsAcceptor, TlsConnector,
+    rustls::{ClientConfig, ServerConfig, pki_types::ServerName},
+};
+
+use crate::log::Logger;
+use crate::message::{DnsMessage, MAX_MESSAGE_SIZE};
+use crate::stream;
+
+/// Path that DNS queries are served on.
+const PATH: &str = "/dns-query";
+
+/// Media type of DNS messages in wire form (RFC 8484 section 6).
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// How long a client has to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serves DNS over HTTPS (RFC 8484) on `listener` at `/dns-query`, answering each query
+/// with `handler`. Only HTTP/2 is spoken, as negotiated through ALPN. A reply is held on
+/// to until hyper is done sending it.
+pub async fn serve<H, F, R>(
+    listener: TcpListener,
+    config: Arc<ServerConfig>,
+    handler: H,
+    log: Arc<Logger>,
+) where
+    H: Fn(Vec<u8>, SocketAddr) -> F + Clone + Send + Sync + 'static,
+    F: Future<Output = Option<R>> + Send + 'static,
+    R: AsRef<[u8]> + Send + Unpin + 'static,
+{
+    let mut config = (*config).clone();
+    config.alpn_protocols = vec![b"h2".to_vec()];
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    loop {
+        let (stream, addr) = stream::accept(&listener, "HTTPS", &log).await;
+
+        let acceptor = acceptor.clone();
+        let handler = handler.clone();
+        let log = log.clone();
+        tokio::spawn(async move {
+            let stream = match time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    log.warn(format_args!("HTTPS connection from {addr} failed: {e}"));
+                    return;
+                }
+                Err(_) => {
+                    log.warn(format_args!(
+                        "HTTPS connection from {addr} failed: TLS handshake timed out"
+                    ));
+                    return;
+                }
+            };
+
+            let service = service_fn(move |request| respond(request, addr, handler.clone()));
+            if let Err(e) = http2::Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                log.warn(format_args!("HTTPS connection from {addr} failed: {e}"));
+            }
+        });
+    }
+}
+
+async fn respond<H, F, R>(
+    request: Request<Incoming>,
+    client: SocketAddr,
+    handler: H,
+) -> Result<Response<ReplyBody<R>>, hyper::Error>
+where
+    H: Fn(Vec<u8>, SocketAddr) -> F,
+    F: Future<Output = Option<R>>,
+    R: AsRef<[u8]>,
+{
+    if request.uri().path() != PATH {
+        return Ok(status(StatusCode::NOT_FOUND));
+    }
+
+    let query = match *request.method() {
+        Method::GET => match query_param(&request) {
+            Some(query) => query,
+            None => return Ok(status(StatusCode::BAD_REQUEST)),
+        },
+        Method::POST => {
+            let content_type = request.headers().get(CONTENT_TYPE);
+            if !content_type.is_some_and(is_dns_message) {
+                return Ok(status(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+            }
+            match Limited::new(request.into_body(), MAX_MESSAGE_SIZE)
+                .collect()
+                .await
+            {
+                Ok(body) => body.to_bytes().to_vec(),
+                Err(_) => return Ok(status(StatusCode::PAYLOAD_TOO_LARGE)),
+            }
+        }
+        _ => return Ok(status(StatusCode::METHOD_NOT_ALLOWED)),
+    };
+
+    if DnsMessage::try_parse(&mut Cursor::new(&*query)).is_err() {
+        return Ok(status(StatusCode::BAD_REQUEST));
+    }
+    let Some(reply) = handler(query, client).await else {
+        return Ok(status(StatusCode::BAD_GATEWAY));
+    };
+
+    let mut response = Response::builder().header(CONTENT_TYPE, DNS_MESSAGE);
+    if let Some(max_age) = max_age(reply.as_ref()) {
+        response = response.header(CACHE_CONTROL, format!("max-age={max_age}"));
+    }
+
+    Ok(response.body(ReplyBody::new(reply)).unwrap())
+}
+
+/// Whether `content_type` is that of DNS messages, with or without parameters.
+fn is_dns_message(content_type: &HeaderValue) -> bool {
+    content_type.to_str().is_ok_and(|content_type| {
+        let media_type = content_type.split(';').next().unwrap_or_default();
+        media_type.trim().eq_ignore_ascii_case(DNS_MESSAGE)
+    })
+}
+
+/// Decodes the query carried base64url encoded in the `dns` parameter of a GET request.
+fn query_param(request: &Request<Incoming>) -> Option<Vec<u8>> {
+    let encoded = request
+        .uri()
+        .query()?
+        .split('&')
+        .find_map(|param| param.strip_prefix("dns="))?;
+
+    // Padding is not meant to be sent, but is harmless
+    URL_SAFE_NO_PAD.decode(encoded.trim_end_matches('=')).ok()
+}
+
+/// How long HTTP caches may keep `reply`: the lowest TTL among its answers, or for
+/// negative answers, among its authority records (RFC 8484 section 5.1).
+fn max_age(reply: &[u8]) -> Option<u32> {
+    let reply = DnsMessage::try_parse(&mut Cursor::new(reply)).ok()?;
+    let records = match reply.records.is_empty() {
+        true => &reply.authority_records,
+        false => &reply.records,
+    };
+
+    records.iter().map(|record| record.ttl).min()
+}
+
+fn status<R>(status: StatusCode) -> Response<ReplyBody<R>> {
+    Response::builder()
+        .status(status)
+        .body(ReplyBody::empty())
+        .unwrap()
+}
+
+/// A response body carrying a reply, if any, which is only dropped along with the body
+/// once hyper is done with it.
+struct ReplyBody<R> {
+    reply: Option<R>,
+    sent: bool,
+}
+
+impl<R> ReplyBody<R> {
+    fn new(reply: R) -> Self {
+        Self {
+            reply: Some(reply),
+            sent: false,
+        }
+    }
+
+    fn empty() -> Self {
+        Self {
+            reply: None,
+            sent: true,
+        }
+    }
+}
+
+impl<R: AsRef<[u8]> + Unpin> Body for ReplyBody<R> {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+        if self.sent {
+            return Poll::Ready(None);
+        }
+        self.sent = true;
+
+        let data = self.reply.as_ref().map_or(&[][..], AsRef::as_ref);
+        Poll::Ready(Some(Ok(Frame::data(Bytes::copy_from_slice(data)))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.sent
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match (&self.reply, self.sent) {
+            (Some(reply), false) => SizeHint::with_exact(reply.as_ref().len() as u64),
+            _ => SizeHint::with_exact(0),
+        }
+    }
+}
+
+/// An HTTP/2 connection to an upstream, over which any number of queries may be
+/// outstanding at once.
+#[derive(Debug)]
+pub struct HttpsConnection {
+    sender: client::conn::http2::SendRequest<Full<Bytes>>,
+    connection: JoinHandle<()>,
+}
+
+impl HttpsConnection {
+    pub async fn connect(
+        address: SocketAddr,
+        config: &ClientConfig,
+        server_name: ServerName<'static>,
+    ) -> io::Result<Self> {
+        let mut config = config.clone();
+        config.alpn_protocols = vec![b"h2".to_vec()];
+
+        let stream = TcpStream::connect(address).await?;
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(server_name, stream)
+            .await?;
+        let (sender, connection) =
+            client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                .await
+                .map_err(io::Error::other)?;
+        let connection = tokio::spawn(async move {
+            // Failures show up as errors sending requests
+            let _ = connection.await;
+        });
+
+        Ok(Self { sender, connection })
+    }
+
+    /// Whether the upstream closed the connection, or it failed.
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+
+    /// POSTs `query`, in wire form, to `uri` and returns the reply. The query is sent
+    /// with ID 0 (RFC 8484 section 4.1), and the reply is given back the original ID.
+    pub async fn exchange(&self, uri: &Uri, query: &[u8]) -> io::Result<Vec<u8>> {
+        if query.len() < 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "query too short",
+            ));
+        }
+
+        let mut query = query.to_vec();
+        let original_id = [query[0], query[1]];
+        query[..2].copy_from_slice(&[0, 0]);
+
+        let request = Request::post(uri)
+            .header(CONTENT_TYPE, DNS_MESSAGE)
+            .header(ACCEPT, DNS_MESSAGE)
+            .body(Full::new(Bytes::from(query)))
+            .map_err(io::Error::other)?;
+        let response = self
+            .sender
+            .clone()
+            .send_request(request)
+            .await
+            .map_err(io::Error::other)?;
+        if response.status() != StatusCode::OK {
+            return Err(io::Error::other(format!(
+                "upstream replied with HTTP status {}",
+                response.status()
+            )));
+        }
+
+        let mut reply = Limited::new(response.into_body(), MAX_MESSAGE_SIZE)
+            .collect()
+            .await
+            .map_err(io::Error::other)?
+            .to_bytes()
+            .to_vec();
+        if reply.len() < 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "reply too short",
+            ));
+        }
+        reply[..2].copy_from_slice(&original_id);
+
+        Ok(reply)
+    }
+}
+
+impl Drop for HttpsConnection {
+    fn drop(&mut self) {
+        self.connection.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        message::ByteSerialize,
+        testing::{self, TestCertificate},
+    };
+
+    /// What the stand-in upstream saw of a request.
+    #[derive(Debug)]
+    struct Seen {
+        method: Method,
+        path: String,
+        content_type: Option<String>,
+        id: u16,
+    }
+
+    /// Serves HTTP/2 on a local port, answering every request with `status` and, when it
+    /// is OK, an A record. Requests are passed on to the returned channel.
+    async fn stand_in(
+        certificate: &TestCertificate,
+        status: StatusCode,
+    ) -> (SocketAddr, mpsc::UnboundedReceiver<Seen>) {
+        let mut config = (*certificate.server_config()).clone();
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let (listener, address) = testing::listener().await;
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let stream = acceptor.accept(stream).await.unwrap();
+                let tx = tx.clone();
+                let service = service_fn(move |request: Request<Incoming>| {
+                    let tx = tx.clone();
+                    async move {
+                        let method = request.method().clone();
+                        let path = request.uri().path().to_string();
+                        let content_type = request
+                            .headers()
+                            .get(CONTENT_TYPE)
+                            .map(|value| value.to_str().unwrap().to_string());
+                        let query = request.into_body().collect().await?.to_bytes();
+                        tx.send(Seen {
+                            method,
+                            path,
+                            content_type,
+                            id: u16::from_be_bytes([query[0], query[1]]),
+                        })
+                        .unwrap();
+
+                        let response = match status {
+                            StatusCode::OK => Response::builder()
+                                .header(CONTENT_TYPE, DNS_MESSAGE)
+                                .body(Full::new(Bytes::from(testing::reply(
+                                    &query,
+                                    [192, 0, 2, 1],
+                                ))))
+                                .unwrap(),
+                            status => Response::builder()
+                                .status(status)
+                                .body(Full::new(Bytes::new()))
+                                .unwrap(),
+                        };
+                        Ok::<_, hyper::Error>(response)
+                    }
+                });
+                tokio::spawn(
+                    http2::Builder::new(TokioExecutor::new())
+                        .serve_connection(TokioIo::new(stream), service),
+                );
+            }
+        });
+
+        (address, rx)
+    }
+
+    async fn connect(certificate: &TestCertificate, address: SocketAddr) -> HttpsConnection {
+        let config = certificate.client_config();
+        HttpsConnection::connect(address, &config, certificate.server_name())
+            .await
+            .unwrap()
+    }
+
+    fn uri(address: SocketAddr) -> Uri {
+        format!("https://localhost:{}{PATH}", address.port())
+            .parse()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn queries_are_posted_with_id_zero() {
+        let certificate = TestCertificate::new();
+        let (address, mut seen) = stand_in(&certificate, StatusCode::OK).await;
+        let connection = connect(&certificate, address).await;
+
+        let reply = connection
+            .exchange(&uri(address), &testing::query(0x1234, "example.com."))
+            .await
+            .unwrap();
+        assert_eq!(reply[..2], 0x1234u16.to_be_bytes());
+        assert_eq!(testing::answer(&reply), [192, 0, 2, 1]);
+
+        let seen = seen.recv().await.unwrap();
+        assert_eq!(seen.method, Method::POST);
+        assert_eq!(seen.path, PATH);
+        assert_eq!(seen.content_type.as_deref(), Some(DNS_MESSAGE));
+        assert_eq!(seen.id, 0);
+    }
+
+    #[tokio::test]
+    async fn error_statuses_fail_the_exchange() {
+        let certificate = TestCertificate::new();
+        for status in [
+            StatusCode::BAD_REQUEST,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ] {
+            let (address, _seen) = stand_in(&certificate, status).await;
+            let connection = connect(&certificate, address).await;
+
+            let result = connection
+                .exchange(&uri(address), &testing::query(1, "example.com."))
+                .await;
+            assert!(result.is_err(), "{status} was taken for a reply");
+            assert!(!connection.is_closed());
+        }
+    }
+
+    #[tokio::test]
+    async fn served_queries_are_answered() {
+        let certificate = TestCertificate::new();
+        let (address, connection) = serving(&certificate, answering).await;
+
+        let reply = connection
+            .exchange(&uri(address), &testing::query(7, "example.com."))
+            .await
+            .unwrap();
+        assert_eq!(reply[..2], 7u16.to_be_bytes());
+        assert_eq!(testing::answer(&reply), [192, 0, 2, 1]);
+
+        let wrong_path = format!("https://localhost:{}/other", address.port())
+            .parse()
+            .unwrap();
+        let result = connection
+            .exchange(&wrong_path, &testing::query(7, "example.com."))
+            .await;
+        assert!(result.is_err());
+    }
+
+    /// Serves DNS over HTTPS on a local port, answering every query with `reply`, and
+    /// returns a connection to it.
+    async fn serving(
+        certificate: &TestCertificate,
+        reply: fn(&[u8]) -> Vec<u8>,
+    ) -> (SocketAddr, HttpsConnection) {
+        let (listener, address) = testing::listener().await;
+        tokio::spawn(serve(
+            listener,
+            certificate.server_config(),
+            move |query: Vec<u8>, _| async move { Some(reply(&query)) },
+            testing::logger(),
+        ));
+
+        (address, connect(certificate, address).await)
+    }
+
+    /// Sends `request` and returns the response, with its body collected.
+    async fn send(
+        connection: &HttpsConnection,
+        request: hyper::http::request::Builder,
+        body: Vec<u8>,
+    ) -> Response<Bytes> {
+        let request = request.body(Full::new(Bytes::from(body))).unwrap();
+        let response = connection
+            .sender
+            .clone()
+            .send_request(request)
+            .await
+            .unwrap();
+        let (parts, body) = response.into_parts();
+        Response::from_parts(parts, body.collect().await.unwrap().to_bytes())
+    }
+
+    fn answering(query: &[u8]) -> Vec<u8> {
+        testing::reply(query, [192, 0, 2, 1])
+    }
+
+    #[tokio::test]
+    async fn get_queries_carry_the_query_base64url_encoded() {
+        let certificate = TestCertificate::new();
+        let (address, connection) = serving(&certificate, answering).await;
+        let query = testing::query(0, "example.com.");
+        let encoded = URL_SAFE_NO_PAD.encode(&query);
+        let get = |query: &str| {
+            Request::get(format!("https://localhost:{}{PATH}{query}", address.port()))
+        };
+
+        for params in [
+            format!("?dns={encoded}"),
+            format!("?ct&dns={encoded}"),
+            // Padding is tolerated
+            format!("?dns={encoded}{}", "=".repeat((4 - encoded.len() % 4) % 4)),
+        ] {
+            let response = send(&connection, get(&params), Vec::new()).await;
+            assert_eq!(response.status(), StatusCode::OK, "{params}");
+            assert_eq!(testing::answer(response.body()), [192, 0, 2, 1]);
+        }
+
+        for params in [
+            String::new(),
+            "?ct".to_string(),
+            "?dns=".to_string(),
+            // Padding in the middle, and the standard rather than the URL-safe alphabet
+            format!("?dns=AA=={encoded}"),
+            format!(
+                "?dns={}",
+                encoded.replace('-', "+").replace('_', "/") + "+/"
+            ),
+        ] {
+            let response = send(&connection, get(&params), Vec::new()).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{params}");
+        }
+    }
+
+    #[tokio::test]
+    async fn post_queries_may_have_content_type_parameters() {
+        let certificate = TestCertificate::new();
+        let (address, connection) = serving(&certificate, answering).await;
+        let post =
+            |content_type: &str| Request::post(uri(address)).header(CONTENT_TYPE, content_type);
+        let query = testing::query(0, "example.com.");
+
+        for content_type in [
+            DNS_MESSAGE,
+            "application/dns-message; charset=utf-8",
+            "Application/DNS-Message",
+        ] {
+            let response = send(&connection, post(content_type), query.clone()).await;
+            assert_eq!(response.status(), StatusCode::OK, "{content_type}");
+        }
+        for content_type in ["text/plain", "application/dns-message-x", "application/dns"] {
+            let response = send(&connection, post(content_type), query.clone()).await;
+            assert_eq!(
+                response.status(),
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "{content_type}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn replies_may_be_cached_for_their_lowest_ttl() {
+        fn two_records(query: &[u8]) -> Vec<u8> {
+            let reply = testing::reply_with_ttl(query, [192, 0, 2, 1], 300);
+            let mut reply = DnsMessage::try_parse(&mut Cursor::new(&reply[..])).unwrap();
+            let mut record = reply.records[0].clone();
+            record.ttl = 30;
+            reply.records.push(record);
+            reply.header.answer_record_count = 2;
+
+            let mut buf = Vec::new();
+            reply.serialize(&mut buf).unwrap();
+            buf
+        }
+
+        let certificate = TestCertificate::new();
+        let (address, connection) = serving(&certificate, two_records).await;
+        let post = Request::post(uri(address)).header(CONTENT_TYPE, DNS_MESSAGE);
+        let response = send(&connection, post, testing::query(0, "example.com.")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CACHE_CONTROL], "max-age=30");
+
+        // Nothing to go by without records
+        let (address, connection) = serving(&certificate, |query| testing::failure(query, 2)).await;
+        let post = Request::post(uri(address)).header(CONTENT_TYPE, DNS_MESSAGE);
+        let response = send(&connection, post, testing::query(0, "example.com.")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key(CACHE_CONTROL));
+    }
+}
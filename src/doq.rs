@@ -0,0 +1,215 @@
+use std::{io::Cursor, net::SocketAddr, sync::Arc};
+
+use quinn::{
+    Connection, ConnectionError, Endpoint, Incoming, RecvStream, SendStream, VarInt,
+    crypto::rustls::QuicServerConfig,
+};
+use tokio_rustls::rustls::ServerConfig;
+
+use crate::error::BoxError;
+use crate::log::Logger;
+use crate::message::DnsMessage;
+use crate::stream::{self, MAX_FRAMED_SIZE};
+
+/// Error codes closing connections and resetting streams (RFC 9250 section 4.3).
+const DOQ_INTERNAL_ERROR: VarInt = VarInt::from_u32(0x1);
+const DOQ_PROTOCOL_ERROR: VarInt = VarInt::from_u32(0x2);
+
+/// Opens a QUIC endpoint for serving DNS over QUIC on `address`.
+pub fn endpoint(address: SocketAddr, config: Arc<ServerConfig>) -> Result<Endpoint, BoxError> {
+    let mut config = (*config).clone();
+    config.alpn_protocols = vec![b"doq".to_vec()];
+    let config = quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(config)?));
+
+    Ok(Endpoint::server(config, address)?)
+}
+
+/// Serves DNS over QUIC (RFC 9250) on `endpoint`, answering each query with `handler`.
+///
+/// Every query arrives on a stream of its own, which its reply is written back to, so
+/// queries on a connection are answered concurrently.
+pub async fn serve<H, F, R>(endpoint: Endpoint, handler: H, log: Arc<Logger>)
+where
+    H: Fn(Vec<u8>, SocketAddr) -> F + Clone + Send + 'static,
+    F: Future<Output = Option<R>> + Send + 'static,
+    R: AsRef<[u8]> + Send + 'static,
+{
+    while let Some(incoming) = endpoint.accept().await {
+        let handler = handler.clone();
+        let log = log.clone();
+        tokio::spawn(async move {
+            let addr = incoming.remote_address();
+            if let Err(e) = serve_connection(incoming, handler).await {
+                log.warn(format_args!("QUIC connection from {addr} failed: {e}"));
+            }
+        });
+    }
+}
+
+async fn serve_connection<H, F, R>(incoming: Incoming, handler: H) -> Result<(), ConnectionError>
+where
+    H: Fn(Vec<u8>, SocketAddr) -> F + Clone + Send + 'static,
+    F: Future<Output = Option<R>> + Send + 'static,
+    R: AsRef<[u8]> + Send + 'static,
+{
+    let connection = incoming.await?;
+    loop {
+        let (send, recv) = match connection.accept_bi().await {
+            Ok(streams) => streams,
+            // The client is done with the connection or let it sit idle, or it was closed
+            // over a protocol error
+            Err(
+                ConnectionError::ApplicationClosed(_)
+                | ConnectionError::TimedOut
+                | ConnectionError::LocallyClosed,
+            ) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        let connection = connection.clone();
+        let handler = handler.clone();
+        tokio::spawn(serve_stream(connection, send, recv, handler));
+    }
+}
+
+async fn serve_stream<H, F, R>(
+    connection: Connection,
+    mut send: SendStream,
+    mut recv: RecvStream,
+    handler: H,
+) where
+    H: Fn(Vec<u8>, SocketAddr) -> F,
+    F: Future<Output = Option<R>>,
+    R: AsRef<[u8]>,
+{
+    // The client may have given up on the query
+    let Ok(data) = recv.read_to_end(MAX_FRAMED_SIZE).await else {
+        return;
+    };
+
+    let Some(query) = stream::unframe(&data).filter(|query| query.len() >= 2) else {
+        connection.close(DOQ_PROTOCOL_ERROR, b"malformed query");
+        return;
+    };
+    // The stream identifies the query, so its ID must be 0 (RFC 9250 section 4.2.1)
+    if query[..2] != [0, 0] {
+        connection.close(DOQ_PROTOCOL_ERROR, b"nonzero message ID");
+        return;
+    }
+    // Queries that cannot be parsed are the client's fault (RFC 9250 section 4.3.3)
+    if DnsMessage::try_parse(&mut Cursor::new(query)).is_err() {
+        let _ = send.reset(DOQ_PROTOCOL_ERROR);
+        return;
+    }
+
+    let Some(reply) = handler(query.to_vec(), connection.remote_address()).await else {
+        let _ = send.reset(DOQ_INTERNAL_ERROR);
+        return;
+    };
+    let Ok(framed) = stream::frame(reply.as_ref()) else {
+        let _ = send.reset(DOQ_INTERNAL_ERROR);
+        return;
+    };
+    // Failures mean the client is gone
+    if send.write_all(&framed).await.is_ok() {
+        let _ = send.finish();
+    }
+    // Only let go of the reply once it is written
+    drop(reply);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use quinn::{ReadError, ReadToEndError, crypto::rustls::QuicClientConfig};
+    use tokio::time;
+
+    use super::*;
+    use crate::testing::{self, TestCertificate};
+
+    /// Serves DoQ on a local port, answering `slow.example.` only after a delay, and
+    /// connects to it.
+    async fn connect(certificate: &TestCertificate) -> Connection {
+        let server = endpoint("127.0.0.1:0".parse().unwrap(), certificate.server_config()).unwrap();
+        let address = server.local_addr().unwrap();
+        tokio::spawn(serve(server, testing::respond, testing::logger()));
+
+        let mut config = (*certificate.client_config()).clone();
+        config.alpn_protocols = vec![b"doq".to_vec()];
+        let config =
+            quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(config).unwrap()));
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(config);
+        client.connect(address, "localhost").unwrap().await.unwrap()
+    }
+
+    /// Sends `query` on a stream of its own and reads back the reply, without its prefix.
+    async fn exchange(connection: &Connection, query: &[u8]) -> Option<Vec<u8>> {
+        let (mut send, mut recv) = connection.open_bi().await.ok()?;
+        send.write_all(&(query.len() as u16).to_be_bytes())
+            .await
+            .ok()?;
+        send.write_all(query).await.ok()?;
+        send.finish().ok()?;
+
+        let reply = recv.read_to_end(MAX_FRAMED_SIZE).await.ok()?;
+        stream::unframe(&reply).map(<[u8]>::to_vec)
+    }
+
+    #[tokio::test]
+    async fn queries_on_a_connection_are_answered_concurrently() {
+        let certificate = TestCertificate::new();
+        let connection = connect(&certificate).await;
+
+        let slow = async {
+            let reply = exchange(&connection, &testing::query(0, "slow.example.")).await;
+            (reply, time::Instant::now())
+        };
+        let fast = async {
+            time::sleep(Duration::from_millis(50)).await;
+            let reply = exchange(&connection, &testing::query(0, "fast.example.")).await;
+            (reply, time::Instant::now())
+        };
+        let ((slow, slow_done), (fast, fast_done)) = tokio::join!(slow, fast);
+
+        assert_eq!(testing::answer(&slow.unwrap()), [192, 0, 2, 2]);
+        assert_eq!(testing::answer(&fast.unwrap()), [192, 0, 2, 1]);
+        assert!(fast_done < slow_done);
+    }
+
+    #[tokio::test]
+    async fn nonzero_ids_close_the_connection() {
+        let certificate = TestCertificate::new();
+        let connection = connect(&certificate).await;
+
+        let reply = exchange(&connection, &testing::query(1, "example.com.")).await;
+        assert!(reply.is_none());
+        match connection.closed().await {
+            ConnectionError::ApplicationClosed(close) => {
+                assert_eq!(close.error_code, DOQ_PROTOCOL_ERROR)
+            }
+            e => panic!("connection closed with {e}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_queries_reset_the_stream_as_a_protocol_error() {
+        let certificate = TestCertificate::new();
+        let connection = connect(&certificate).await;
+
+        let (mut send, mut recv) = connection.open_bi().await.unwrap();
+        send.write_all(&[0, 3, 0, 0, 0xff]).await.unwrap();
+        send.finish().unwrap();
+        match recv.read_to_end(MAX_FRAMED_SIZE).await {
+            Err(ReadToEndError::Read(ReadError::Reset(code))) => {
+                assert_eq!(code, DOQ_PROTOCOL_ERROR)
+            }
+            result => panic!("stream ended with {result:?}"),
+        }
+
+        // Other queries on the connection are still answered
+        let reply = exchange(&connection, &testing::query(0, "example.com.")).await;
+        assert_eq!(testing::answer(&reply.unwrap()), [192, 0, 2, 1]);
+    }
+}
@@ -7,8 +7,20 @@ use bytes::TryGetError;
 pub enum DnsError {
     NotEnoughData(TryGetError),
     InvalidName(&'static str),
-    InvalidType,
     InvalidClass,
+    InvalidRecordData(&'static str),
+}
+
+impl DnsError {
+    /// Name of the kind of error, for labelling metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DnsError::NotEnoughData(_) => "not_enough_data",
+            DnsError::InvalidName(_) => "invalid_name",
+            DnsError::InvalidClass => "invalid_class",
+            DnsError::InvalidRecordData(_) => "invalid_record_data",
+        }
+    }
 }
 
 impl fmt::Display for DnsError {
@@ -16,8 +28,8 @@ impl fmt::Display for DnsError {
         match self {
             DnsError::NotEnoughData(e) => write!(f, "not enough data to parse: {e}"),
             DnsError::InvalidName(s) => write!(f, "invalid name: {s}"),
-            DnsError::InvalidType => write!(f, "invalid type"),
             DnsError::InvalidClass => write!(f, "invalid class"),
+            DnsError::InvalidRecordData(s) => write!(f, "invalid record data: {s}"),
         }
     }
 }
@@ -38,3 +50,5 @@ impl From<TryGetError> for DnsError {
 }
 
 pub type Result<T> = std::result::Result<T, DnsError>;
+
+pub type BoxError = Box<dyn error::Error + Send + Sync>;
@@ -0,0 +1,980 @@
+use std::{io::Cursor, net::SocketAddr, str::FromStr, sync::Arc, time::Duration};
+
+use tokio::time;
+
+use crate::cache::{Cache, CachedAnswer};
+use crate::error::BoxError;
+use crate::inflight::{InFlight, LookupKey};
+use crate::log::Logger;
+use crate::message::{
+    ByteSerialize, DnsHeader, DnsMessage, DnsQuestion, Edns, Name, Opcode, RCODE_FORMAT_ERROR,
+    RCODE_NAME_ERROR, RCODE_NO_ERROR, RCODE_NOT_IMPLEMENTED, RCODE_REFUSED, RCODE_SERVER_FAILURE,
+    ResourceRecord,
+};
+use crate::recursive::Resolver;
+use crate::upstream::{Routes, UpstreamSet};
+
+/// How long a client waits on the upstream before being served a stale answer, as
+/// recommended by RFC 8767.
+const CLIENT_RESPONSE_TIMEOUT: Duration = Duration::from_millis(1800);
+
+/// UDP payload size advertised in replies to EDNS queries, small enough to avoid IP
+/// fragmentation on most paths.
+const EDNS_PAYLOAD_SIZE: u16 = 1232;
+
+/// How queries are sent to the upstream resolvers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ForwardMode {
+    /// Each question is forwarded on its own and the reply is assembled from the answers.
+    #[default]
+    Split,
+    /// The client's message is forwarded whole and the upstream's reply relayed as is.
+    ///
+    /// Only this mode relays EDNS options, in either direction, and the upstream's
+    /// Additional section: answering question by question, in split mode or when
+    /// passthrough falls back to it, leaves them out, along with any records that could
+    /// not be parsed.
+    Passthrough,
+}
+
+impl FromStr for ForwardMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "split" => Ok(ForwardMode::Split),
+            "passthrough" => Ok(ForwardMode::Passthrough),
+            _ => Err(format!("unknown forward mode: {s}")),
+        }
+    }
+}
+
+/// What to reply when some, but not all, of the questions in a message could be answered.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PartialFailure {
+    /// Reply with the error alone, dropping the answers that did succeed.
+    #[default]
+    Fail,
+    /// Reply with the answers that succeeded and leave out the rest.
+    Partial,
+}
+
+impl FromStr for PartialFailure {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(PartialFailure::Fail),
+            "partial" => Ok(PartialFailure::Partial),
+            _ => Err(format!("unknown partial failure policy: {s}")),
+        }
+    }
+}
+
+/// Where a question is sent to be answered.
+#[derive(Debug, Clone)]
+enum Route {
+    Forward(Arc<UpstreamSet>),
+    Recursive(Arc<Resolver>),
+}
+
+/// Where the answer to a question came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// The cache, before the answer expired.
+    Cache,
+    /// The cache, past the answer's expiry, as the upstream failed.
+    StaleCache,
+    /// The upstream resolver at this address.
+    Upstream(SocketAddr),
+    /// Recursive resolution from the root servers down.
+    Recursive,
+}
+
+/// The answer to a single question.
+#[derive(Debug)]
+enum Answer {
+    Cached(CachedAnswer),
+    Reply(DnsMessage<'static>),
+}
+
+/// The sections of a reply assembled from the answers to each question.
+#[derive(Debug, Default)]
+struct Answers {
+    response_code: u8,
+    records: Vec<ResourceRecord<'static>>,
+    authority_records: Vec<ResourceRecord<'static>>,
+}
+
+impl Answers {
+    fn set_response_code(&mut self, response_code: u8) {
+        // The first error seen wins
+        if self.response_code == RCODE_NO_ERROR {
+            self.response_code = response_code;
+        }
+    }
+
+    fn add(&mut self, answer: Answer) {
+        match answer {
+            Answer::Cached(CachedAnswer::Records(records)) => self.records.extend(records),
+            Answer::Cached(CachedAnswer::NxDomain { soa }) => {
+                self.set_response_code(RCODE_NAME_ERROR);
+                self.authority_records.push(soa);
+            }
+            Answer::Cached(CachedAnswer::NoData { soa }) => self.authority_records.push(soa),
+            Answer::Reply(reply) => {
+                self.set_response_code(reply.header.response_code);
+                self.records.extend(reply.records);
+                self.authority_records.extend(reply.authority_records);
+            }
+        }
+    }
+}
+
+/// Answers queries by forwarding them to upstream resolvers, or by resolving them
+/// recursively when they have no route, caching the replies.
+#[derive(Debug)]
+pub struct Forwarder {
+    routes: Routes,
+    resolver: Option<Arc<Resolver>>,
+    cache: Arc<Cache>,
+    mode: ForwardMode,
+    partial_failure: PartialFailure,
+    /// Lookups on their way upstream in split mode.
+    in_flight: InFlight<(DnsMessage<'static>, Source)>,
+    /// Lookups on their way upstream in passthrough mode, as raw replies.
+    in_flight_raw: InFlight<(Vec<u8>, SocketAddr)>,
+    log: Arc<Logger>,
+}
+
+impl Forwarder {
+    pub fn new(
+        routes: Routes,
+        resolver: Option<Resolver>,
+        cache: Arc<Cache>,
+        mode: ForwardMode,
+        partial_failure: PartialFailure,
+        log: Arc<Logger>,
+    ) -> Self {
+        Self {
+            routes,
+            resolver: resolver.map(Arc::new),
+            cache,
+            mode,
+            partial_failure,
+            in_flight: InFlight::new(),
+            in_flight_raw: InFlight::new(),
+            log,
+        }
+    }
+
+    pub fn routes(&self) -> &Routes {
+        &self.routes
+    }
+
+    fn route(&self, name: &Name<'_>) -> Option<Route> {
+        match (self.routes.lookup(name), &self.resolver) {
+            (Some(upstreams), _) => Some(Route::Forward(upstreams.clone())),
+            (None, Some(resolver)) => Some(Route::Recursive(resolver.clone())),
+            (None, None) => None,
+        }
+    }
+
+    /// Answers `message`, whose wire form is `bytes`, and returns the wire form of the
+    /// reply, along with where the answers in it came from.
+    pub async fn handle_query(
+        self: &Arc<Self>,
+        message: DnsMessage<'_>,
+        bytes: &[u8],
+    ) -> Result<(Vec<u8>, Vec<Source>), BoxError> {
+        match self.mode {
+            ForwardMode::Split => self.handle_split_query(message).await,
+            ForwardMode::Passthrough => self.handle_passthrough_query(message, bytes).await,
+        }
+    }
+
+    /// Answers each question on its own, all at once, and assembles the reply from the
+    /// answers in question order.
+    async fn handle_split_query(
+        self: &Arc<Self>,
+        message: DnsMessage<'_>,
+    ) -> Result<(Vec<u8>, Vec<Source>), BoxError> {
+        let lookups: Vec<_> = message
+            .questions
+            .iter()
+            .map(|question| {
+                tokio::spawn(self.clone().answer_question(
+                    question.clone().into_owned(),
+                    message.header.clone(),
+                    message.edns,
+                ))
+            })
+            .collect();
+
+        let mut answers = Answers::default();
+        let mut sources = Vec::new();
+        let mut answered = false;
+        let mut failure = None;
+        for lookup in lookups {
+            match lookup.await {
+                Ok(Ok((answer, source))) => {
+                    answered = true;
+                    answers.add(answer);
+                    sources.push(source);
+                }
+                Ok(Err(response_code)) => {
+                    failure.get_or_insert(response_code);
+                }
+                Err(e) => {
+                    self.log.error(format_args!("Forwarding task failed: {e}"));
+                    failure.get_or_insert(RCODE_SERVER_FAILURE);
+                }
+            }
+        }
+
+        if let Some(response_code) = failure {
+            if self.partial_failure == PartialFailure::Fail || !answered {
+                answers = Answers::default();
+                answers.set_response_code(response_code);
+            }
+        }
+
+        Ok((build_reply(message, answers)?, sources))
+    }
+
+    /// Answers a single question from the cache or upstream, or fails with a response code.
+    async fn answer_question(
+        self: Arc<Self>,
+        question: DnsQuestion<'static>,
+        header: DnsHeader,
+        edns: Option<Edns>,
+    ) -> Result<(Answer, Source), u8> {
+        let Some(route) = self.route(&question.name) else {
+            return Err(RCODE_REFUSED);
+        };
+
+        if let Some(hit) = self.cache.get(&question) {
+            if hit.prefetch {
+                tokio::spawn(self.clone().prefetch(question, header, edns, route.clone()));
+            }
+            return Ok((Answer::Cached(hit.answer), Source::Cache));
+        }
+
+        // The lookup runs in its own task so that it can finish refreshing the cache even
+        // after the client has been answered with stale data
+        let stale = self.cache.get_stale(&question);
+        let lookup = tokio::spawn(self.clone().forward_question(question, header, edns, route));
+
+        let Some(stale) = stale else {
+            return match lookup.await {
+                Ok(Ok((reply, source))) => Ok((Answer::Reply(reply), source)),
+                Ok(Err(e)) => {
+                    self.log.warn(format_args!("Failed forwarding query: {e}"));
+                    Err(RCODE_SERVER_FAILURE)
+                }
+                Err(e) => {
+                    self.log.error(format_args!("Forwarding task failed: {e}"));
+                    Err(RCODE_SERVER_FAILURE)
+                }
+            };
+        };
+
+        match time::timeout(CLIENT_RESPONSE_TIMEOUT, lookup).await {
+            Ok(Ok(Ok((reply, source)))) if reply.header.response_code != RCODE_SERVER_FAILURE => {
+                return Ok((Answer::Reply(reply), source));
+            }
+            Ok(Ok(Ok(_))) => self
+                .log
+                .warn("Upstream failed to resolve query, serving stale answer"),
+            Ok(Ok(Err(e))) => self.log.warn(format_args!(
+                "Failed forwarding query, serving stale answer: {e}"
+            )),
+            Ok(Err(e)) => self.log.error(format_args!(
+                "Forwarding task failed, serving stale answer: {e}"
+            )),
+            Err(_) => self
+                .log
+                .warn("Upstream is slow to respond, serving stale answer"),
+        }
+
+        self.cache.record_stale_hit();
+        Ok((Answer::Cached(stale), Source::StaleCache))
+    }
+
+    /// Forwards the client's message upstream whole and relays the upstream's reply
+    /// untouched, apart from the ID.
+    ///
+    /// Falls back to answering question by question when the questions are cached or
+    /// routed to different upstreams, or when the upstream rejects a message with several
+    /// questions.
+    async fn handle_passthrough_query(
+        self: &Arc<Self>,
+        message: DnsMessage<'_>,
+        bytes: &[u8],
+    ) -> Result<(Vec<u8>, Vec<Source>), BoxError> {
+        let mut routed = message
+            .questions
+            .iter()
+            .map(|q| self.routes.lookup(&q.name));
+        let upstreams = match routed.next() {
+            Some(Some(first)) if routed.all(|u| u.is_some_and(|u| Arc::ptr_eq(u, first))) => first,
+            _ => return self.handle_split_query(message).await,
+        };
+        if message.questions.iter().any(|q| self.cache.contains(q)) {
+            return self.handle_split_query(message).await;
+        }
+
+        let dnssec_ok = message.edns.is_some_and(|edns| edns.dnssec_ok);
+        let reply = match &message.questions[..] {
+            [question] => {
+                let key = LookupKey::new(question, dnssec_ok);
+                let lookup = upstreams.query_bytes(&message, bytes);
+                self.in_flight_raw.coalesce(key, lookup).await
+            }
+            _ => upstreams.query_bytes(&message, bytes).await,
+        };
+        let (mut reply, upstream) = match reply {
+            Ok(reply) => reply,
+            Err(e) => {
+                self.log.warn(format_args!("Failed forwarding query: {e}"));
+                let mut answers = Answers::default();
+                let mut sources = Vec::new();
+                for question in &message.questions {
+                    match self.cache.get_stale(question) {
+                        Some(stale) => {
+                            self.cache.record_stale_hit();
+                            answers.add(Answer::Cached(stale));
+                            sources.push(Source::StaleCache);
+                        }
+                        None => answers.set_response_code(RCODE_SERVER_FAILURE),
+                    }
+                }
+                return Ok((build_reply(message, answers)?, sources));
+            }
+        };
+
+        let response_code = reply[3] & 0b0000_1111;
+        if message.questions.len() > 1
+            && matches!(response_code, RCODE_FORMAT_ERROR | RCODE_NOT_IMPLEMENTED)
+        {
+            // The upstream only takes one question per message
+            return self.handle_split_query(message).await;
+        }
+
+        if let [question] = &message.questions[..] {
+            match DnsMessage::try_parse(&mut Cursor::new(&reply[..])) {
+                Ok(parsed) => self.cache.insert(question, &parsed),
+                Err(e) => self
+                    .log
+                    .warn(format_args!("Not caching unparseable reply: {e}")),
+            }
+            restore_question_casing(&mut reply, question);
+        }
+
+        reply[..2].copy_from_slice(&message.header.id.to_be_bytes());
+
+        Ok((reply, vec![Source::Upstream(upstream)]))
+    }
+
+    /// Sends a single question upstream and caches the reply. When the same question is
+    /// already on its way upstream, its reply is waited for and shared instead.
+    async fn forward_question(
+        self: Arc<Self>,
+        question: DnsQuestion<'static>,
+        mut header: DnsHeader,
+        edns: Option<Edns>,
+        route: Route,
+    ) -> Result<(DnsMessage<'static>, Source), BoxError> {
+        header.question_count = 1;
+        header.answer_record_count = 0;
+        header.authority_record_count = 0;
+        header.additional_record_count = edns.is_some() as u16;
+        let forward_message = DnsMessage {
+            header,
+            questions: vec![question],
+            records: vec![],
+            authority_records: vec![],
+            additional_records: vec![],
+            edns,
+        };
+
+        let question = &forward_message.questions[0];
+        let key = LookupKey::new(question, edns.is_some_and(|edns| edns.dnssec_ok));
+        let lookup = async {
+            let (reply, source) = match &route {
+                Route::Forward(upstreams) => {
+                    let (reply, upstream) = upstreams.query(&forward_message).await?;
+                    (reply, Source::Upstream(upstream))
+                }
+                Route::Recursive(resolver) => {
+                    (resolver.resolve(question).await?, Source::Recursive)
+                }
+            };
+            self.cache.insert(question, &reply);
+            Ok((reply, source))
+        };
+        let (mut reply, source) = self.in_flight.coalesce(key, lookup).await?;
+
+        // The reply may have been to another client's question, with different casing
+        for record in reply.records.iter_mut().chain(&mut reply.authority_records) {
+            if record.name.eq_ignore_ascii_case(&question.name) {
+                record.name = question.name.clone();
+            }
+        }
+        reply.questions = forward_message.questions;
+
+        Ok((reply, source))
+    }
+
+    /// Refreshes a popular cache entry ahead of its expiry.
+    async fn prefetch(
+        self: Arc<Self>,
+        question: DnsQuestion<'static>,
+        header: DnsHeader,
+        edns: Option<Edns>,
+        route: Route,
+    ) {
+        let _prefetching = Prefetching {
+            cache: self.cache.clone(),
+            question: question.clone(),
+        };
+        let log = self.log.clone();
+        if let Err(e) = self.forward_question(question, header, edns, route).await {
+            log.warn(format_args!("Failed prefetching cache entry: {e}"));
+        }
+    }
+}
+
+/// A prefetch of a cache entry in progress, which lets the entry be prefetched again when
+/// dropped, however the prefetch ended.
+struct Prefetching {
+    cache: Arc<Cache>,
+    question: DnsQuestion<'static>,
+}
+
+impl Drop for Prefetching {
+    fn drop(&mut self) {
+        self.cache.end_prefetch(&self.question);
+    }
+}
+
+/// Rewrites the Question section of the raw `reply` to the casing of `question`, as the
+/// reply may have been to another client's identical question.
+fn restore_question_casing(reply: &mut [u8], question: &DnsQuestion<'_>) {
+    let mut wire = Vec::with_capacity(64);
+    if question.serialize(&mut wire).is_err() || reply.get(4..6) != Some(&[0, 1]) {
+        return;
+    }
+
+    if let Some(section) = reply.get_mut(12..12 + wire.len()) {
+        if section.eq_ignore_ascii_case(&wire) {
+            section.copy_from_slice(&wire);
+        }
+    }
+}
+
+/// Builds the reply to `message` from the answers assembled for its questions.
+fn build_reply(message: DnsMessage<'_>, answers: Answers) -> Result<Vec<u8>, BoxError> {
+    let mut header = message.header.clone();
+    header.qr_indicator = true;
+    header.authoritative_answer = false;
+    header.truncation = false;
+    header.recursion_available = false;
+    header.reserved = 0;
+    if header.opcode == Opcode::StandardQuery {
+        header.response_code = answers.response_code;
+    } else {
+        header.response_code = RCODE_NOT_IMPLEMENTED;
+    }
+    // Clients that sent an OPT record get one back (RFC 6891 section 6.1.1)
+    let edns = message.edns.map(|edns| Edns {
+        udp_payload_size: EDNS_PAYLOAD_SIZE,
+        extended_rcode: 0,
+        version: 0,
+        dnssec_ok: edns.dnssec_ok,
+    });
+    header.answer_record_count = answers.records.len() as u16;
+    header.authority_record_count = answers.authority_records.len() as u16;
+    header.additional_record_count = u16::from(edns.is_some());
+    let reply_message = DnsMessage {
+        header,
+        questions: message.questions,
+        records: answers.records,
+        authority_records: answers.authority_records,
+        additional_records: vec![],
+        edns,
+    };
+    let mut reply_buf = Vec::with_capacity(1024);
+    reply_message.serialize(&mut reply_buf)?;
+
+    Ok(reply_buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::Future,
+        sync::atomic::{AtomicBool, Ordering},
+    };
+
+    use super::*;
+    use crate::message::{Class, RData, Type};
+    use crate::testing::{self, StubServer};
+    use crate::upstream::Strategy;
+
+    fn upstreams(upstream: &StubServer) -> UpstreamSet {
+        UpstreamSet::new(
+            vec![upstream.address],
+            Strategy::Failover,
+            &testing::logger(),
+        )
+    }
+
+    fn forwarder(upstream: &StubServer, cache: Cache) -> Arc<Forwarder> {
+        let routes = Routes::new(Some(upstreams(upstream)));
+        forwarder_with(routes, cache, ForwardMode::Split, PartialFailure::Fail)
+    }
+
+    fn forwarder_with(
+        routes: Routes,
+        cache: Cache,
+        mode: ForwardMode,
+        partial_failure: PartialFailure,
+    ) -> Arc<Forwarder> {
+        Arc::new(Forwarder::new(
+            routes,
+            None,
+            Arc::new(cache),
+            mode,
+            partial_failure,
+            testing::logger(),
+        ))
+    }
+
+    /// Sends `query`, in wire form, and returns the reply in wire form.
+    async fn exchange(forwarder: &Arc<Forwarder>, query: &[u8]) -> (Vec<u8>, Vec<Source>) {
+        let message = DnsMessage::try_parse(&mut Cursor::new(query)).unwrap();
+        forwarder.handle_query(message, query).await.unwrap()
+    }
+
+    async fn ask(forwarder: &Arc<Forwarder>, name: &str) -> (DnsMessage<'static>, Vec<Source>) {
+        let (reply, sources) = exchange(forwarder, &testing::query(7, name)).await;
+        (parse(&reply), sources)
+    }
+
+    fn parse(message: &[u8]) -> DnsMessage<'static> {
+        DnsMessage::try_parse(&mut Cursor::new(message))
+            .unwrap()
+            .into_owned()
+    }
+
+    /// A query for the A records of each of `names`, in wire form.
+    fn query(names: &[&str]) -> Vec<u8> {
+        let mut query = parse(&testing::query(7, names[0]));
+        for name in &names[1..] {
+            query.questions.push(DnsQuestion {
+                name: name.parse().unwrap(),
+                qtype: Type::A,
+                class: Class::IN,
+            });
+        }
+        query.header.question_count = names.len() as u16;
+
+        let mut buf = Vec::new();
+        query.serialize(&mut buf).unwrap();
+        buf
+    }
+
+    /// Routes `a.test.` and `b.test.` to upstreams that answer with 192.0.2.1 and
+    /// 192.0.2.2 after `delay`, and `down.test.` to an upstream that is not there.
+    async fn zones(delay: Duration) -> (Routes, [StubServer; 2]) {
+        let answering = |address| {
+            StubServer::start(move |query| async move {
+                time::sleep(delay).await;
+                Some(testing::reply(&query, address))
+            })
+        };
+        let a = answering([192, 0, 2, 1]).await;
+        let b = answering([192, 0, 2, 2]).await;
+        let closed = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let down = closed.local_addr().unwrap();
+        drop(closed);
+
+        let log = testing::logger();
+        let mut routes = Routes::new(None);
+        routes.add_zone("a.test.".parse().unwrap(), upstreams(&a));
+        routes.add_zone("b.test.".parse().unwrap(), upstreams(&b));
+        routes.add_zone(
+            "down.test.".parse().unwrap(),
+            UpstreamSet::new(vec![down], Strategy::Failover, &log),
+        );
+        (routes, [a, b])
+    }
+
+    fn addresses(reply: &DnsMessage<'_>) -> Vec<[u8; 4]> {
+        reply
+            .records
+            .iter()
+            .map(|record| match record.rdata {
+                RData::A { address } => address.to_be_bytes(),
+                _ => panic!("{record:?} is not an A record"),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn questions_are_sent_to_their_upstreams_at_once() {
+        let (routes, [a, b]) = zones(Duration::from_millis(300)).await;
+        let forwarder = forwarder_with(
+            routes,
+            Cache::new(10, 0, 0),
+            ForwardMode::Split,
+            PartialFailure::Fail,
+        );
+
+        let started = time::Instant::now();
+        let query = query(&["www.b.test.", "www.a.test.", "mail.b.test."]);
+        let (reply, sources) = exchange(&forwarder, &query).await;
+        let waited = started.elapsed();
+        assert!(
+            waited < Duration::from_millis(550),
+            "answered after {waited:?}"
+        );
+
+        // Answers are in question order, whichever upstream answered first
+        let reply = parse(&reply);
+        assert_eq!(
+            addresses(&reply),
+            [[192, 0, 2, 2], [192, 0, 2, 1], [192, 0, 2, 2]]
+        );
+        assert_eq!(
+            sources,
+            [b.address, a.address, b.address].map(Source::Upstream)
+        );
+        assert_eq!(a.queries().len(), 1);
+        assert_eq!(b.queries().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn partial_failures_fail_the_whole_message_by_default() {
+        let (routes, _upstreams) = zones(Duration::ZERO).await;
+        let forwarder = forwarder_with(
+            routes,
+            Cache::new(10, 0, 0),
+            ForwardMode::Split,
+            PartialFailure::Fail,
+        );
+
+        for (names, response_code) in [
+            (["www.a.test.", "www.down.test."], RCODE_SERVER_FAILURE),
+            (["www.a.test.", "www.unrouted.example."], RCODE_REFUSED),
+        ] {
+            let (reply, sources) = exchange(&forwarder, &query(&names)).await;
+            let reply = parse(&reply);
+            assert_eq!(reply.header.response_code, response_code);
+            assert_eq!(reply.questions.len(), 2);
+            assert!(reply.records.is_empty());
+            assert_eq!(sources.len(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn partial_failures_keep_the_answers_that_succeeded_when_allowed() {
+        let (routes, _upstreams) = zones(Duration::ZERO).await;
+        let forwarder = forwarder_with(
+            routes,
+            Cache::new(10, 0, 0),
+            ForwardMode::Split,
+            PartialFailure::Partial,
+        );
+
+        let names = [
+            "www.down.test.",
+            "www.b.test.",
+            "www.unrouted.example.",
+            "www.a.test.",
+        ];
+        let (reply, sources) = exchange(&forwarder, &query(&names)).await;
+        let reply = parse(&reply);
+        assert_eq!(reply.header.response_code, RCODE_NO_ERROR);
+        assert_eq!(addresses(&reply), [[192, 0, 2, 2], [192, 0, 2, 1]]);
+        assert_eq!(sources.len(), 2);
+
+        // With nothing answered, there is nothing to keep
+        let names = ["www.down.test.", "www.unrouted.example."];
+        let (reply, sources) = exchange(&forwarder, &query(&names)).await;
+        let reply = parse(&reply);
+        assert_eq!(reply.header.response_code, RCODE_SERVER_FAILURE);
+        assert!(reply.records.is_empty());
+        assert!(sources.is_empty());
+    }
+
+    /// Adds an OPT record carrying a cookie option (RFC 7873) to `message`.
+    fn with_cookie(mut message: Vec<u8>, cookie: &[u8]) -> Vec<u8> {
+        let additional = u16::from_be_bytes([message[10], message[11]]) + 1;
+        message[10..12].copy_from_slice(&additional.to_be_bytes());
+        message.extend_from_slice(&[0, 0, 41, 0x04, 0xd0, 0, 0, 0, 0]);
+        message.extend_from_slice(&(cookie.len() as u16 + 4).to_be_bytes());
+        message.extend_from_slice(&[0, 10]);
+        message.extend_from_slice(&(cookie.len() as u16).to_be_bytes());
+        message.extend_from_slice(cookie);
+        message
+    }
+
+    /// An authoritative answer to `query`, with the AD and CD bits set, a referral in the
+    /// Authority section, and glue and a server cookie in the Additional section.
+    fn detailed_reply(query: &[u8]) -> Vec<u8> {
+        let mut reply = parse(query);
+        let name = reply.questions[0].name.clone();
+        let record = |name: Name<'static>, atype, rdata| ResourceRecord {
+            name,
+            atype,
+            class: Class::IN,
+            ttl: 300,
+            rdata,
+        };
+        let ns: Name<'static> = "ns.example.com.".parse().unwrap();
+        reply.header.qr_indicator = true;
+        reply.header.authoritative_answer = true;
+        reply.header.recursion_available = true;
+        reply.header.reserved = 0b011;
+        reply.records = vec![record(
+            name,
+            Type::A,
+            RData::A {
+                address: 0xc000_0201,
+            },
+        )];
+        reply.authority_records = vec![record(
+            "example.com.".parse().unwrap(),
+            Type::NS,
+            RData::NS {
+                nsdname: ns.clone(),
+            },
+        )];
+        reply.additional_records = vec![record(
+            ns,
+            Type::A,
+            RData::A {
+                address: 0xc000_0235,
+            },
+        )];
+        reply.edns = None;
+        reply.header.answer_record_count = 1;
+        reply.header.authority_record_count = 1;
+        reply.header.additional_record_count = 1;
+
+        let mut buf = Vec::new();
+        reply.serialize(&mut buf).unwrap();
+        with_cookie(buf, b"client-cookie+server-cookie")
+    }
+
+    #[tokio::test]
+    async fn passthrough_relays_messages_whole() {
+        let upstream = StubServer::start(|query| async move { Some(detailed_reply(&query)) }).await;
+        let routes = Routes::new(Some(upstreams(&upstream)));
+        let forwarder = forwarder_with(
+            routes,
+            Cache::new(10, 0, 0),
+            ForwardMode::Passthrough,
+            PartialFailure::Fail,
+        );
+
+        let query = with_cookie(testing::query(7, "WWW.Example.com."), b"client-cookie");
+        let (reply, sources) = exchange(&forwarder, &query).await;
+        assert!(matches!(sources[..], [Source::Upstream(_)]));
+
+        // Apart from the ID and the case of the name, which are randomized on the way
+        let sent = &upstream.queries()[0];
+        assert!(sent[2..].eq_ignore_ascii_case(&query[2..]));
+        let expected = detailed_reply(sent);
+        assert_eq!(reply[..2], query[..2]);
+        assert!(reply[2..].eq_ignore_ascii_case(&expected[2..]));
+        assert_eq!(reply.len(), expected.len());
+        // The question is the client's own
+        let question = 12.."WWW.Example.com.".len() + 1 + 12 + 4;
+        assert_eq!(reply[question.clone()], query[question]);
+
+        let reply = parse(&reply);
+        assert!(reply.header.authoritative_answer);
+        assert_eq!(reply.header.reserved, 0b011);
+        assert_eq!(reply.authority_records.len(), 1);
+        assert_eq!(reply.additional_records.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn passthrough_splits_messages_the_upstream_cannot_take_whole() {
+        let upstream = StubServer::start(|query| async move {
+            match parse(&query).questions.len() {
+                1 => Some(testing::reply(&query, [192, 0, 2, 1])),
+                _ => Some(testing::failure(&query, RCODE_FORMAT_ERROR)),
+            }
+        })
+        .await;
+        let routes = Routes::new(Some(upstreams(&upstream)));
+        let forwarder = forwarder_with(
+            routes,
+            Cache::new(10, 0, 0),
+            ForwardMode::Passthrough,
+            PartialFailure::Fail,
+        );
+
+        let query = query(&["a.example.com.", "b.example.com."]);
+        let (reply, sources) = exchange(&forwarder, &query).await;
+        let reply = parse(&reply);
+        assert_eq!(reply.header.response_code, RCODE_NO_ERROR);
+        assert_eq!(reply.records.len(), 2);
+        assert_eq!(sources.len(), 2);
+        assert_eq!(upstream.queries().len(), 3);
+    }
+
+    /// A stub upstream that answers the first query with a one second TTL, then replies to
+    /// the rest as `later` does.
+    async fn expiring_upstream<F, R>(later: F) -> StubServer
+    where
+        F: Fn(Vec<u8>) -> R + Send + Sync + 'static,
+        R: Future<Output = Option<Vec<u8>>> + Send + 'static,
+    {
+        let answered = Arc::new(AtomicBool::new(false));
+        StubServer::start(move |query| {
+            let first = !answered.swap(true, Ordering::Relaxed);
+            let later = later(query.clone());
+            async move {
+                if first {
+                    Some(testing::reply_with_ttl(&query, [192, 0, 2, 1], 1))
+                } else {
+                    later.await
+                }
+            }
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn stale_answers_are_served_when_the_upstream_fails() {
+        let upstream = expiring_upstream(|query| async move {
+            Some(testing::failure(&query, RCODE_SERVER_FAILURE))
+        })
+        .await;
+        let forwarder = forwarder(&upstream, Cache::new(10, 60, 0));
+
+        let (reply, sources) = ask(&forwarder, "www.example.com.").await;
+        assert_eq!(reply.records[0].ttl, 1);
+        assert!(matches!(sources[..], [Source::Upstream(_)]));
+
+        time::sleep(Duration::from_millis(1100)).await;
+        let (reply, sources) = ask(&forwarder, "www.example.com.").await;
+        assert_eq!(reply.header.response_code, RCODE_NO_ERROR);
+        assert_eq!(reply.records[0].ttl, 30);
+        assert_eq!(sources, [Source::StaleCache]);
+        assert_eq!(forwarder.cache.stats().stale_hits, 1);
+        assert_eq!(upstream.queries().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn stale_answers_are_served_when_the_upstream_is_slow() {
+        let upstream = expiring_upstream(|query| async move {
+            time::sleep(Duration::from_secs(3)).await;
+            Some(testing::reply(&query, [192, 0, 2, 2]))
+        })
+        .await;
+        let forwarder = forwarder(&upstream, Cache::new(10, 60, 0));
+        ask(&forwarder, "www.example.com.").await;
+
+        time::sleep(Duration::from_millis(1100)).await;
+        let started = time::Instant::now();
+        let (reply, sources) = ask(&forwarder, "www.example.com.").await;
+        let waited = started.elapsed();
+        assert!(
+            waited >= CLIENT_RESPONSE_TIMEOUT,
+            "answered after {waited:?}"
+        );
+        assert!(
+            waited < Duration::from_millis(2500),
+            "answered after {waited:?}"
+        );
+        assert_eq!(testing::answer(&serialize(&reply)), [192, 0, 2, 1]);
+        assert_eq!(reply.records[0].ttl, 30);
+        assert_eq!(sources, [Source::StaleCache]);
+    }
+
+    #[tokio::test]
+    async fn answers_past_the_stale_window_are_not_served() {
+        let upstream = expiring_upstream(|query| async move {
+            Some(testing::failure(&query, RCODE_SERVER_FAILURE))
+        })
+        .await;
+        let forwarder = forwarder(&upstream, Cache::new(10, 0, 0));
+        ask(&forwarder, "www.example.com.").await;
+
+        time::sleep(Duration::from_millis(1100)).await;
+        let (reply, sources) = ask(&forwarder, "www.example.com.").await;
+        assert_eq!(reply.header.response_code, RCODE_SERVER_FAILURE);
+        assert!(reply.records.is_empty());
+        assert!(matches!(sources[..], [Source::Upstream(_)]));
+        assert_eq!(forwarder.cache.stats().stale_hits, 0);
+    }
+
+    #[tokio::test]
+    async fn popular_answers_near_expiry_are_refreshed_once() {
+        let upstream =
+            StubServer::start(|query| async move { Some(testing::reply(&query, [192, 0, 2, 1])) })
+                .await;
+        // Every hit is within the last 100% of the TTL
+        let forwarder = forwarder(&upstream, Cache::new(10, 0, 100));
+
+        for _ in 0..6 {
+            ask(&forwarder, "www.example.com.").await;
+        }
+        time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(upstream.queries().len(), 2);
+        assert_eq!(forwarder.cache.stats().prefetches, 1);
+    }
+
+    #[tokio::test]
+    async fn failed_prefetches_are_retried() {
+        let upstream = expiring_upstream(|query| async move {
+            Some(testing::failure(&query, RCODE_SERVER_FAILURE))
+        })
+        .await;
+        let forwarder = forwarder(&upstream, Cache::new(10, 0, 100));
+
+        // A miss, then enough hits to be popular
+        for _ in 0..4 {
+            ask(&forwarder, "www.example.com.").await;
+        }
+        time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(upstream.queries().len(), 2);
+
+        ask(&forwarder, "www.example.com.").await;
+        time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(upstream.queries().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn identical_concurrent_queries_reach_the_upstream_once() {
+        let upstream = StubServer::start(|query| async move {
+            time::sleep(Duration::from_millis(100)).await;
+            Some(testing::reply(&query, [192, 0, 2, 1]))
+        })
+        .await;
+        let forwarder = forwarder(&upstream, Cache::new(10, 0, 0));
+
+        let mut clients = tokio::task::JoinSet::new();
+        for _ in 0..10 {
+            let forwarder = forwarder.clone();
+            clients.spawn(async move { ask(&forwarder, "www.example.com.").await });
+        }
+        for (reply, sources) in clients.join_all().await {
+            assert_eq!(testing::answer(&serialize(&reply)), [192, 0, 2, 1]);
+            assert!(matches!(sources[..], [Source::Upstream(_)]));
+        }
+        assert_eq!(upstream.queries().len(), 1);
+    }
+
+    fn serialize(message: &DnsMessage<'_>) -> Vec<u8> {
+        let mut buf = Vec::new();
+        message.serialize(&mut buf).unwrap();
+        buf
+    }
+}
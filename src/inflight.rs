@@ -0,0 +1,215 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use tokio::sync::broadcast;
+
+use crate::error::BoxError;
+use crate::message::{Class, DnsQuestion, Name, Type};
+
+/// Identifies upstream lookups that can be answered by one and the same query.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct LookupKey {
+    name: Name<'static>,
+    qtype: Type,
+    class: Class,
+    dnssec_ok: bool,
+}
+
+impl LookupKey {
+    pub fn new(question: &DnsQuestion<'_>, dnssec_ok: bool) -> Self {
+        Self {
+            name: question.name.to_ascii_lowercase(),
+            qtype: question.qtype,
+            class: question.class,
+            dnssec_ok,
+        }
+    }
+}
+
+/// Upstream lookups currently in progress, so that identical lookups started meanwhile
+/// wait for the first one to finish instead of querying the upstream again.
+#[derive(Debug)]
+pub struct InFlight<T> {
+    lookups: Mutex<HashMap<LookupKey, broadcast::Sender<Result<T, String>>>>,
+}
+
+impl<T: Clone> InFlight<T> {
+    pub fn new() -> Self {
+        Self {
+            lookups: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Runs `lookup`, unless an identical lookup is already running, in which case its
+    /// result is shared instead. Errors are shared as their message.
+    pub async fn coalesce<F>(&self, key: LookupKey, lookup: F) -> Result<T, BoxError>
+    where
+        F: Future<Output = Result<T, BoxError>>,
+    {
+        let leader = {
+            let mut lookups = self.lookups.lock().unwrap();
+            match lookups.get(&key) {
+                Some(sender) => Err(sender.subscribe()),
+                None => {
+                    lookups.insert(key.clone(), broadcast::channel(1).0);
+                    Ok(Leader {
+                        lookups: &self.lookups,
+                        key: Some(key),
+                    })
+                }
+            }
+        };
+
+        let leader = match leader {
+            Ok(leader) => leader,
+            Err(mut receiver) => {
+                return match receiver.recv().await {
+                    Ok(result) => result.map_err(BoxError::from),
+                    // The first lookup was dropped before finishing, so do it ourselves
+                    Err(_) => lookup.await,
+                };
+            }
+        };
+
+        let result = lookup.await;
+        if let Some(sender) = leader.finish() {
+            // Nobody may be waiting, which is fine
+            let _ = sender.send(match &result {
+                Ok(value) => Ok(value.clone()),
+                Err(e) => Err(e.to_string()),
+            });
+        }
+
+        result
+    }
+}
+
+/// Removes the lookup from the in-flight map when the first lookup finishes or is dropped.
+struct Leader<'a, T> {
+    lookups: &'a Mutex<HashMap<LookupKey, broadcast::Sender<Result<T, String>>>>,
+    key: Option<LookupKey>,
+}
+
+impl<T> Leader<'_, T> {
+    fn finish(mut self) -> Option<broadcast::Sender<Result<T, String>>> {
+        let key = self.key.take()?;
+        self.lookups.lock().unwrap().remove(&key)
+    }
+}
+
+impl<T> Drop for Leader<'_, T> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.lookups.lock().unwrap().remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future,
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+        time::Duration,
+    };
+
+    use tokio::{task::JoinSet, time};
+
+    use super::*;
+
+    fn key(name: &str) -> LookupKey {
+        let question = DnsQuestion {
+            name: name.parse().unwrap(),
+            qtype: Type::A,
+            class: Class::IN,
+        };
+        LookupKey::new(&question, false)
+    }
+
+    #[tokio::test]
+    async fn identical_lookups_run_once_and_share_the_result() {
+        let in_flight = Arc::new(InFlight::new());
+        let lookups = Arc::new(AtomicUsize::new(0));
+
+        let mut waiting = JoinSet::new();
+        for i in 0..10 {
+            let in_flight = in_flight.clone();
+            let lookups = lookups.clone();
+            // Case does not make lookups any different
+            let name = if i % 2 == 0 {
+                "example.com."
+            } else {
+                "EXAMPLE.com."
+            };
+            waiting.spawn(async move {
+                let lookup = async {
+                    lookups.fetch_add(1, Ordering::Relaxed);
+                    time::sleep(Duration::from_millis(50)).await;
+                    Ok(i)
+                };
+                in_flight.coalesce(key(name), lookup).await.unwrap()
+            });
+        }
+
+        let results = waiting.join_all().await;
+        assert_eq!(lookups.load(Ordering::Relaxed), 1);
+        assert_eq!(results.len(), 10);
+        assert!(results.iter().all(|&result| result == results[0]));
+        assert!(in_flight.lookups.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn errors_are_shared_too() {
+        let in_flight = Arc::new(InFlight::<u32>::new());
+        let leader = tokio::spawn({
+            let in_flight = in_flight.clone();
+            async move {
+                let lookup = async {
+                    time::sleep(Duration::from_millis(50)).await;
+                    Err("upstream timed out".into())
+                };
+                in_flight.coalesce(key("example.com."), lookup).await
+            }
+        });
+        time::sleep(Duration::from_millis(10)).await;
+
+        let follower = in_flight.coalesce(key("example.com."), async { Ok(1) });
+        let error = follower.await.unwrap_err();
+        assert_eq!(error.to_string(), "upstream timed out");
+        assert!(leader.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn followers_of_a_dropped_lookup_look_up_themselves() {
+        let in_flight = Arc::new(InFlight::<u32>::new());
+        let leader = tokio::spawn({
+            let in_flight = in_flight.clone();
+            async move {
+                in_flight
+                    .coalesce(key("example.com."), future::pending())
+                    .await
+            }
+        });
+        time::sleep(Duration::from_millis(10)).await;
+
+        let follower = tokio::spawn({
+            let in_flight = in_flight.clone();
+            async move {
+                in_flight
+                    .coalesce(key("example.com."), async { Ok(2) })
+                    .await
+                    .unwrap()
+            }
+        });
+        time::sleep(Duration::from_millis(10)).await;
+        assert!(!follower.is_finished());
+
+        leader.abort();
+        assert!(leader.await.unwrap_err().is_cancelled());
+        let result = time::timeout(Duration::from_secs(1), follower).await;
+        assert_eq!(result.unwrap().unwrap(), 2);
+        assert!(in_flight.lookups.lock().unwrap().is_empty());
+    }
+}
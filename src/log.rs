@@ -0,0 +1,664 @@
+use std::{
+    fmt::{self, Write as _},
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Stdout, Write},
+    mem,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, TryRecvError, TrySendError},
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::config::Config;
+use crate::forward::Source;
+use crate::message::{Type, rcode_name};
+use crate::metrics::ClientTransport;
+
+/// Size a log file may grow to before it is rotated, when none is configured.
+const DEFAULT_MAX_SIZE: u64 = 100 * 1024 * 1024;
+
+/// Number of rotated log files kept, when none is configured.
+const DEFAULT_MAX_FILES: usize = 5;
+
+/// Number of log entries that may be waiting to be written out.
+const QUEUE_SIZE: usize = 65_536;
+
+/// How severe a log entry is. Filtering on a level keeps entries that are at least as
+/// severe.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    /// Also covers every query answered.
+    #[default]
+    Info,
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            _ => Err(format!("unknown log level: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Level::Error => write!(f, "error"),
+            Level::Warn => write!(f, "warn"),
+            Level::Info => write!(f, "info"),
+        }
+    }
+}
+
+/// How log entries are written out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    /// A line of space separated `key=value` fields.
+    #[default]
+    Text,
+    /// A JSON object per line.
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown log format: {s}")),
+        }
+    }
+}
+
+/// A query, and how it was answered.
+#[derive(Debug)]
+pub struct QueryEntry<'a> {
+    pub client: SocketAddr,
+    pub transport: ClientTransport,
+    /// Name and type of the first question, when there is one.
+    pub qname: Option<String>,
+    pub qtype: Option<Type>,
+    /// Response code of the reply, or `None` when the query went unanswered.
+    pub rcode: Option<u8>,
+    pub answers: u16,
+    /// Where the answers came from, one for each question answered.
+    pub sources: &'a [Source],
+    pub latency: Duration,
+}
+
+impl QueryEntry<'_> {
+    /// Whether the answers came from the cache, for queries that had any answers looked up.
+    fn cache_status(&self) -> Option<&'static str> {
+        if self.sources.is_empty() {
+            None
+        } else if self.sources.contains(&Source::StaleCache) {
+            Some("stale")
+        } else if self.sources.iter().all(|source| *source == Source::Cache) {
+            Some("hit")
+        } else {
+            Some("miss")
+        }
+    }
+
+    /// The upstream that was asked for an answer, if any. Messages with several questions
+    /// may have gone to several, of which the first is given.
+    fn upstream(&self) -> Option<String> {
+        self.sources.iter().find_map(|source| match source {
+            Source::Upstream(address) => Some(address.to_string()),
+            Source::Recursive => Some("recursive".to_string()),
+            Source::Cache | Source::StaleCache => None,
+        })
+    }
+}
+
+/// A log file that is rotated once it grows past `max_size`: the file is renamed to
+/// `<path>.1`, older files move up a number, and those past `max_files` are removed.
+#[derive(Debug)]
+struct RotatingFile {
+    path: String,
+    file: BufWriter<File>,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    fn open(path: &str, max_size: u64, max_files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path: path.to_string(),
+            file: BufWriter::new(file),
+            size,
+            max_size,
+            max_files,
+        })
+    }
+
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        match self.max_files {
+            0 => fs::remove_file(&self.path)?,
+            max_files => {
+                // Files missing from the sequence are skipped over
+                for i in (1..max_files).rev() {
+                    let _ = fs::rename(
+                        format!("{}.{i}", self.path),
+                        format!("{}.{}", self.path, i + 1),
+                    );
+                }
+                fs::rename(&self.path, format!("{}.1", self.path))?;
+            }
+        }
+
+        self.file = BufWriter::new(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?,
+        );
+        self.size = 0;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum Output {
+    Stdout(BufWriter<Stdout>),
+    File(RotatingFile),
+}
+
+impl Output {
+    fn open(config: &Config) -> io::Result<Self> {
+        match &config.log_file {
+            Some(path) => Ok(Output::File(RotatingFile::open(
+                path,
+                config.log_max_size.unwrap_or(DEFAULT_MAX_SIZE),
+                config.log_max_files.unwrap_or(DEFAULT_MAX_FILES),
+            )?)),
+            None => Ok(Output::Stdout(BufWriter::new(io::stdout()))),
+        }
+    }
+
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        match self {
+            Output::Stdout(stdout) => stdout.write_all(line),
+            Output::File(file) => file.write(line),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Stdout(stdout) => stdout.flush(),
+            Output::File(file) => file.file.flush(),
+        }
+    }
+
+    /// Flushes, and for files, waits for the data to reach the disk.
+    fn sync(&mut self) -> io::Result<()> {
+        self.flush()?;
+        match self {
+            Output::Stdout(_) => Ok(()),
+            Output::File(file) => file.file.get_ref().sync_data(),
+        }
+    }
+}
+
+/// What the writer thread is asked to do.
+enum Command {
+    Write(String),
+    /// Switch over to a new output, after a reload.
+    Reopen(Output),
+    /// Write out everything sent so far, then acknowledge.
+    Sync(mpsc::Sender<()>),
+}
+
+/// Writes out lines as they are sent, until the logger is dropped. Lines are buffered,
+/// and flushed whenever there are no more waiting. Failures are counted in `failures`,
+/// for the logger to report.
+fn write_out(mut output: Output, commands: mpsc::Receiver<Command>, failures: Arc<AtomicU64>) {
+    let check = |result: io::Result<()>| {
+        if result.is_err() {
+            failures.fetch_add(1, Ordering::Relaxed);
+        }
+    };
+    loop {
+        let command = match commands.try_recv() {
+            Ok(command) => command,
+            Err(TryRecvError::Empty) => {
+                check(output.flush());
+                match commands.recv() {
+                    Ok(command) => command,
+                    Err(_) => break,
+                }
+            }
+            Err(TryRecvError::Disconnected) => break,
+        };
+
+        let result = match command {
+            Command::Write(line) => output.write(line.as_bytes()),
+            Command::Reopen(new) => mem::replace(&mut output, new).flush(),
+            Command::Sync(done) => {
+                let result = output.sync();
+                let _ = done.send(());
+                result
+            }
+        };
+        check(result);
+    }
+
+    check(output.flush());
+}
+
+/// The part of the configuration that decides what is logged, and how.
+#[derive(Debug)]
+struct Settings {
+    level: Level,
+    format: Format,
+    log_queries: bool,
+    /// Fraction of answered queries that are logged.
+    sample: f64,
+}
+
+impl Settings {
+    fn new(config: &Config) -> Self {
+        Self {
+            level: config.log_level,
+            format: config.log_format,
+            log_queries: config.log_queries,
+            sample: config.log_sample.unwrap_or(1.0),
+        }
+    }
+}
+
+/// Writes log entries, and a query log of every query answered, to stdout or a file.
+///
+/// Entries are handed to a thread of their own to be written out, so that logging never
+/// blocks on I/O. Should they come faster than they can be written, those that do not fit
+/// in the queue are dropped and counted.
+#[derive(Debug)]
+pub struct Logger {
+    settings: RwLock<Settings>,
+    commands: mpsc::SyncSender<Command>,
+    /// Entries dropped since the last were reported.
+    dropped: AtomicU64,
+    /// Times the writer thread failed to write out or flush entries since last reported.
+    failures: Arc<AtomicU64>,
+}
+
+impl Logger {
+    pub fn new(config: &Config) -> io::Result<Self> {
+        let output = Output::open(config)?;
+        let (commands, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        let failures = Arc::new(AtomicU64::new(0));
+        thread::Builder::new()
+            .name("log writer".to_string())
+            .spawn({
+                let failures = failures.clone();
+                move || write_out(output, receiver, failures)
+            })?;
+
+        Ok(Self {
+            settings: RwLock::new(Settings::new(config)),
+            commands,
+            dropped: AtomicU64::new(0),
+            failures,
+        })
+    }
+
+    /// Applies the log settings of `config`, reopening the output. Never blocks, so the
+    /// output is left as it is when the queue is full.
+    pub fn reconfigure(&self, config: &Config) -> io::Result<()> {
+        let output = Output::open(config)?;
+        if self.commands.try_send(Command::Reopen(output)).is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "too many entries waiting to be written",
+            ));
+        }
+        *self.settings.write().unwrap() = Settings::new(config);
+
+        Ok(())
+    }
+
+    pub fn error(&self, message: impl fmt::Display) {
+        self.log(Level::Error, message);
+    }
+
+    pub fn warn(&self, message: impl fmt::Display) {
+        self.log(Level::Warn, message);
+    }
+
+    pub fn info(&self, message: impl fmt::Display) {
+        self.log(Level::Info, message);
+    }
+
+    fn log(&self, level: Level, message: impl fmt::Display) {
+        let settings = self.settings.read().unwrap();
+        if level > settings.level {
+            return;
+        }
+
+        let line = entry(settings.format, level, message);
+        drop(settings);
+        self.write(line);
+    }
+
+    /// Logs a query. Unanswered queries are logged as warnings, and answered ones only
+    /// when sampled.
+    pub fn query(&self, entry: &QueryEntry<'_>) {
+        let settings = self.settings.read().unwrap();
+        let level = match entry.rcode {
+            Some(_) => Level::Info,
+            None => Level::Warn,
+        };
+        if !settings.log_queries || level > settings.level {
+            return;
+        }
+        if level == Level::Info && settings.sample < 1.0 && rand::random::<f64>() >= settings.sample
+        {
+            return;
+        }
+        let format = settings.format;
+        drop(settings);
+
+        let qtype = entry.qtype.map(|qtype| qtype.to_string());
+        let rcode = entry.rcode.map(rcode_name);
+        let latency = format!("{:.3}", entry.latency.as_secs_f64() * 1000.0);
+        let fields = [
+            ("client", Some(entry.client.to_string())),
+            ("transport", Some(entry.transport.to_string())),
+            ("qname", entry.qname.clone()),
+            ("qtype", qtype),
+            ("rcode", rcode),
+            ("answers", Some(entry.answers.to_string())),
+            ("upstream", entry.upstream()),
+            ("cache", entry.cache_status().map(String::from)),
+        ];
+
+        let mut line = String::new();
+        match format {
+            Format::Text => {
+                let _ = write!(line, "{} {} query", timestamp(SystemTime::now()), level);
+                for (key, value) in fields {
+                    let _ = write!(line, " {key}=");
+                    match value {
+                        Some(value) => text_value(&mut line, &value),
+                        None => line.push('-'),
+                    }
+                }
+                let _ = write!(line, " latency_ms={latency}");
+            }
+            Format::Json => {
+                let _ = write!(
+                    line,
+                    "{{\"time\":\"{}\",\"level\":\"{}\",\"message\":\"query\"",
+                    timestamp(SystemTime::now()),
+                    level
+                );
+                for (key, value) in fields {
+                    let Some(value) = value else {
+                        continue;
+                    };
+                    let _ = write!(line, ",\"{key}\":");
+                    match key {
+                        "answers" => line.push_str(&value),
+                        _ => json_string(&mut line, &value),
+                    }
+                }
+                let _ = write!(line, ",\"latency_ms\":{latency}}}");
+            }
+        }
+        self.write(line);
+    }
+
+    /// Queues `line` to be written out, first reporting any entries dropped before it and
+    /// any failures to write out earlier ones.
+    fn write(&self, line: String) {
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            let format = self.settings.read().unwrap().format;
+            let note = entry(
+                format,
+                Level::Warn,
+                format_args!("Dropped {dropped} log entries that came too fast to write"),
+            );
+            self.send(note, dropped);
+        }
+        let failures = self.failures.swap(0, Ordering::Relaxed);
+        if failures > 0 {
+            let format = self.settings.read().unwrap().format;
+            let note = entry(
+                format,
+                Level::Error,
+                format_args!("Failed writing out the log {failures} times, entries may be missing"),
+            );
+            self.send(note, 1);
+        }
+
+        self.send(line, 1);
+    }
+
+    /// Queues `line`, which stands for `entries` log entries, counting them as dropped
+    /// when the queue is full.
+    fn send(&self, mut line: String, entries: u64) {
+        line.push('\n');
+        if let Err(TrySendError::Full(_)) = self.commands.try_send(Command::Write(line)) {
+            self.dropped.fetch_add(entries, Ordering::Relaxed);
+        }
+    }
+
+    /// Makes sure everything logged so far has been written out.
+    pub fn flush(&self) {
+        let (done, written) = mpsc::channel();
+        if self.commands.send(Command::Sync(done)).is_ok() {
+            let _ = written.recv();
+        }
+    }
+}
+
+/// Formats a log entry at `level` as a line, without the line break.
+fn entry(format: Format, level: Level, message: impl fmt::Display) -> String {
+    let mut line = String::new();
+    match format {
+        Format::Text => {
+            let _ = write!(
+                line,
+                "{} {} {}",
+                timestamp(SystemTime::now()),
+                level,
+                message
+            );
+        }
+        Format::Json => {
+            let _ = write!(
+                line,
+                "{{\"time\":\"{}\",\"level\":\"{}\",\"message\":",
+                timestamp(SystemTime::now()),
+                level
+            );
+            json_string(&mut line, &message.to_string());
+            line.push('}');
+        }
+    }
+    line
+}
+
+/// Formats `time` as an RFC 3339 timestamp in UTC, to the millisecond.
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = ((secs / 86_400) as i64, secs % 86_400);
+
+    // Converts days since the epoch to a civil date, after Howard Hinnant's algorithm
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Appends `value` to a text log line, escaping whatever would break up the fields.
+fn text_value(line: &mut String, value: &str) {
+    for c in value.chars() {
+        if c.is_whitespace() || c.is_control() || c == '\\' {
+            let _ = write!(line, "\\u{{{:x}}}", c as u32);
+        } else {
+            line.push(c);
+        }
+    }
+}
+
+/// Appends `value` to a JSON log line as a string.
+fn json_string(line: &mut String, value: &str) {
+    line.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            '\n' => line.push_str("\\n"),
+            '\r' => line.push_str("\\r"),
+            '\t' => line.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(line, "\\u{:04x}", c as u32);
+            }
+            c => line.push(c),
+        }
+    }
+    line.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+
+    fn log_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("dns-server-{}-{name}.log", process::id()));
+        let _ = fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    fn config(path: &str, format: Format) -> Config {
+        Config {
+            log_file: Some(path.to_string()),
+            log_format: format,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn flushing_waits_for_queued_entries() {
+        let path = log_path("flush");
+        let log = Logger::new(&config(&path, Format::Text)).unwrap();
+        for i in 0..1000 {
+            log.info(format_args!("entry {i}"));
+        }
+        log.flush();
+
+        let written = fs::read_to_string(&path).unwrap();
+        assert_eq!(written.lines().count(), 1000);
+        assert!(written.lines().last().unwrap().ends_with("info entry 999"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reconfiguring_switches_output_and_format() {
+        let (first, second) = (log_path("first"), log_path("second"));
+        let log = Logger::new(&config(&first, Format::Text)).unwrap();
+        log.warn("before");
+        log.reconfigure(&config(&second, Format::Json)).unwrap();
+        log.warn("after");
+        log.flush();
+
+        assert!(
+            fs::read_to_string(&first)
+                .unwrap()
+                .ends_with("warn before\n")
+        );
+        let after = fs::read_to_string(&second).unwrap();
+        assert!(after.starts_with("{\"time\":"));
+        assert!(after.ends_with("\"level\":\"warn\",\"message\":\"after\"}\n"));
+        fs::remove_file(&first).unwrap();
+        fs::remove_file(&second).unwrap();
+    }
+
+    #[test]
+    fn files_are_rotated_once_they_grow_past_the_limit() {
+        let path = log_path("rotate");
+        let mut file = RotatingFile::open(&path, 100, 2).unwrap();
+        for i in 0..7 {
+            file.write(format!("{i:<39}\n").as_bytes()).unwrap();
+        }
+        file.file.flush().unwrap();
+
+        let read = |path: &str| {
+            fs::read_to_string(path)
+                .unwrap()
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        assert_eq!(read(&path), "6");
+        assert_eq!(read(&format!("{path}.1")), "4 5");
+        assert_eq!(read(&format!("{path}.2")), "2 3");
+        assert!(!fs::exists(format!("{path}.3")).unwrap());
+        for path in [path.clone(), format!("{path}.1"), format!("{path}.2")] {
+            fs::remove_file(path).unwrap();
+        }
+
+        // Without rotated files to keep, the log starts over
+        let mut file = RotatingFile::open(&path, 100, 0).unwrap();
+        for i in 0..3 {
+            file.write(format!("{i:<39}\n").as_bytes()).unwrap();
+        }
+        file.file.flush().unwrap();
+        assert_eq!(read(&path), "2");
+        assert!(!fs::exists(format!("{path}.1")).unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,95 +1,463 @@
 #![warn(rust_2018_idioms)]
 
+mod acl;
+mod cache;
+mod config;
+mod dnstap;
+mod doh;
+mod doq;
 mod error;
+mod forward;
+mod inflight;
+mod log;
 mod message;
+mod metrics;
+mod recursive;
+mod stream;
+#[cfg(test)]
+mod testing;
+mod tls;
+mod udp;
+mod upstream;
 
-use message::{ByteSerialize, Class, DnsMessage, Opcode, RData, ResourceRecord, Type};
+use acl::Acl;
+use cache::Cache;
+use config::Config;
+use dnstap::{Dnstap, MessageType};
+use error::BoxError;
+use forward::{Forwarder, Source};
+use log::{Logger, QueryEntry};
+use message::{
+    ByteSerialize, Class, DnsMessage, Opcode, RCODE_NO_ERROR, RCODE_NOT_IMPLEMENTED, RCODE_REFUSED,
+    RData, ResourceRecord, Type,
+};
+use metrics::{ClientTransport, Metrics};
+use recursive::Resolver;
+use upstream::{Routes, Strategy, Transport, Upstream, UpstreamSet};
 
-use std::{env, io::Cursor, net::SocketAddr, sync::Arc};
+use std::{
+    io::Cursor,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
 
-use tokio::{net::UdpSocket, sync::mpsc};
+use hyper::Uri;
+use tokio::{
+    net::{self, TcpListener},
+    signal::unix::{Signal, SignalKind, signal},
+    sync::Notify,
+    task::JoinSet,
+    time::{self, Instant},
+};
+use tokio_rustls::rustls::{ClientConfig, pki_types::ServerName};
 
-async fn handle_forward_query(
-    sock: Arc<UdpSocket>,
-    addr: SocketAddr,
-    message: DnsMessage<'_>,
-    resolver: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut bufs = Vec::new();
-    for _ in 0..message.header.question_count {
-        bufs.push(Vec::with_capacity(128));
+/// Maximum number of answers held in the cache.
+const CACHE_CAPACITY: usize = 10_000;
+
+/// Port of HTTPS resolvers that do not give one.
+const HTTPS_PORT: u16 = 443;
+
+/// How often cache counters are reported.
+const CACHE_STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long queries being answered at shutdown are waited for. Long enough for upstreams
+/// to time out.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Periodically logs the counters of the current cache, when queries are forwarded.
+async fn report_cache_stats(server: Arc<Server>) {
+    let mut interval = time::interval(CACHE_STATS_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let service = server.service();
+        if service.forwarder.is_none() {
+            continue;
+        }
+        let stats = service.cache.stats();
+        service.log.info(format_args!(
+            "Cache: {} hits, {} negative hits, {} stale hits, {} misses, {} prefetches",
+            stats.hits, stats.negative_hits, stats.stale_hits, stats.misses, stats.prefetches
+        ));
     }
+}
+
+/// What queries are answered with, whichever transport they arrive over.
+struct Service {
+    forwarder: Option<Arc<Forwarder>>,
+    cache: Arc<Cache>,
+    acl: Acl,
+    /// Address answered to every question when there is no forwarder, and its TTL.
+    fallback: (Ipv4Addr, u32),
+    /// Shared by every service, and reconfigured on reload.
+    log: Arc<Logger>,
+}
+
+impl Service {
+    /// Builds the service described by `config`, reusing `cache` if given, and emitting
+    /// messages exchanged with upstreams to `dnstap` if given. Upstreams are health
+    /// checked for as long as the service is around.
+    async fn new(
+        config: &Config,
+        cache: Option<Arc<Cache>>,
+        dnstap: Option<&Arc<Dnstap>>,
+        log: &Arc<Logger>,
+    ) -> Result<Self, BoxError> {
+        let tls_config = tls::client_config(config.tls_ca.as_deref())?;
+        let default = match config.resolvers.is_empty() {
+            true => None,
+            false => Some(
+                resolve_upstreams(&config.resolvers, config.strategy, &tls_config, dnstap, log)
+                    .await?,
+            ),
+        };
+        let mut routes = Routes::new(default);
+        for (zone, addrs) in &config.forward_zones {
+            routes.add_zone(
+                zone.clone(),
+                resolve_upstreams(addrs, config.strategy, &tls_config, dnstap, log).await?,
+            );
+        }
 
-    let mut answers = Vec::new();
-    for (i, buf) in bufs.iter_mut().enumerate() {
-        let mut header = message.header.clone();
-        header.question_count = 1;
-        let forward_message = DnsMessage {
-            header,
-            questions: vec![message.questions[i].clone()],
-            records: vec![],
+        let qname_minimisation = !config.disable_qname_minimisation;
+        let resolver = match (config.recursive, config.root_hints.is_empty()) {
+            (false, _) => None,
+            (true, true) => Some(Resolver::new(
+                recursive::default_root_hints(),
+                qname_minimisation,
+                log.clone(),
+            )),
+            (true, false) => Some(Resolver::new(
+                resolve_addresses(&config.root_hints).await?,
+                qname_minimisation,
+                log.clone(),
+            )),
         };
 
-        forward_message.serialize(buf)?;
+        let cache = cache.unwrap_or_else(|| {
+            Arc::new(Cache::new(
+                config.cache_capacity.unwrap_or(CACHE_CAPACITY),
+                config.stale_window,
+                config.prefetch_percent,
+            ))
+        });
+        let forwarder = match routes.is_empty() && resolver.is_none() {
+            true => None,
+            false => Some(Arc::new(Forwarder::new(
+                routes,
+                resolver,
+                cache.clone(),
+                config.mode,
+                config.partial_failure,
+                log.clone(),
+            ))),
+        };
+        if let Some(forwarder) = &forwarder {
+            for upstreams in forwarder.routes().upstream_sets() {
+                tokio::spawn(upstream::health_check(Arc::downgrade(upstreams)));
+            }
+        }
+
+        Ok(Self {
+            forwarder,
+            cache,
+            acl: config.acl.clone(),
+            fallback: (
+                config.fallback_address.unwrap_or(config::FALLBACK_ADDRESS),
+                config.fallback_ttl.unwrap_or(config::FALLBACK_TTL),
+            ),
+            log: log.clone(),
+        })
+    }
+}
 
-        let forward_sock = UdpSocket::bind("0.0.0.0:2054").await?;
-        forward_sock.connect(resolver).await?;
-        forward_sock.send(buf).await?;
+/// What the listeners share: the service queries are currently answered with, which is
+/// swapped out on reload, the queries being answered, which shutdown waits for, and the
+/// metrics and dnstap output, which outlive reloads.
+struct Server {
+    service: RwLock<Arc<Service>>,
+    metrics: Metrics,
+    dnstap: Option<Arc<Dnstap>>,
+    active: AtomicUsize,
+    stopping: AtomicBool,
+    idle: Notify,
+}
 
-        buf.resize(128, 0);
-        let len = forward_sock.recv(buf).await?;
-        let reply = DnsMessage::try_parse(&mut Cursor::new(&buf[..len]))?;
+impl Server {
+    fn new(service: Service, dnstap: Option<Arc<Dnstap>>) -> Self {
+        Self {
+            service: RwLock::new(Arc::new(service)),
+            metrics: Metrics::default(),
+            dnstap,
+            active: AtomicUsize::new(0),
+            stopping: AtomicBool::new(false),
+            idle: Notify::new(),
+        }
+    }
 
-        answers.extend(reply.records);
+    fn service(&self) -> Arc<Service> {
+        self.service.read().unwrap().clone()
     }
 
-    let mut header = message.header.clone();
-    header.qr_indicator = true;
-    header.authoritative_answer = false;
-    header.truncation = false;
-    header.recursion_available = false;
-    header.reserved = 0;
-    if header.opcode == Opcode::StandardQuery {
-        header.response_code = 0;
-    } else {
-        header.response_code = 4;
+    /// Counts a query as being answered until the returned guard is dropped. Returns
+    /// `None` once shutting down, when no new queries are taken.
+    fn start_query(self: &Arc<Self>) -> Option<ActiveQuery> {
+        // Counted before checking, so that `drain` either sees the query or refuses it
+        self.active.fetch_add(1, Ordering::SeqCst);
+        let query = ActiveQuery(self.clone());
+        match self.stopping.load(Ordering::SeqCst) {
+            true => None,
+            false => Some(query),
+        }
     }
-    header.answer_record_count = answers.len() as u16;
-    let reply_message = DnsMessage {
-        header,
-        questions: message.questions,
-        records: answers,
-    };
-    let mut reply_buf = Vec::with_capacity(1024);
-    reply_message.serialize(&mut reply_buf)?;
-    let len_sent = sock.send_to(&reply_buf, addr).await?;
-    println!("Sent {} bytes to {}", len_sent, addr);
 
-    Ok(())
+    /// Renders the metrics page, including those of the current service.
+    fn render_metrics(&self) -> String {
+        let service = self.service();
+        let upstreams = service
+            .forwarder
+            .iter()
+            .flat_map(|forwarder| forwarder.routes().upstream_sets())
+            .flat_map(|upstreams| upstreams.upstreams())
+            .map(|upstream| (upstream.address, upstream.latency()));
+
+        self.metrics.render(
+            self.active.load(Ordering::SeqCst),
+            service.cache.stats(),
+            upstreams,
+            self.dnstap.as_ref().map(|dnstap| dnstap.dropped()),
+        )
+    }
+
+    /// Stops taking queries, and waits up to `timeout` for those being answered to finish.
+    /// Returns how many are left unanswered.
+    async fn drain(&self, timeout: Duration) -> usize {
+        self.stopping.store(true, Ordering::SeqCst);
+        let deadline = Instant::now() + timeout;
+        loop {
+            let idle = self.idle.notified();
+            if self.active.load(Ordering::SeqCst) == 0 {
+                return 0;
+            }
+            if time::timeout_at(deadline, idle).await.is_err() {
+                return self.active.load(Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+/// A query being answered, see `Server::start_query`.
+struct ActiveQuery(Arc<Server>);
+
+impl Drop for ActiveQuery {
+    fn drop(&mut self) {
+        if self.0.active.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+/// A reply in wire form, which keeps its query counted as being answered until the
+/// transport is done sending it and drops the reply.
+struct Reply {
+    bytes: Vec<u8>,
+    _query: ActiveQuery,
+}
+
+impl AsRef<[u8]> for Reply {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+/// Whether answers cached under `old` are still good under `new`: the cache is set up
+/// the same, and its answers come from the same places.
+fn cache_compatible(old: &Config, new: &Config) -> bool {
+    old.cache_capacity == new.cache_capacity
+        && old.stale_window == new.stale_window
+        && old.prefetch_percent == new.prefetch_percent
+        && old.resolvers == new.resolvers
+        && old.forward_zones == new.forward_zones
+        && old.recursive == new.recursive
+        && old.root_hints == new.root_hints
+}
+
+/// Whether the listeners set up for `old` differ from those `new` asks for.
+fn listeners_changed(old: &Config, new: &Config) -> bool {
+    old.listen != new.listen
+        || old.tls_listen != new.tls_listen
+        || old.https_listen != new.https_listen
+        || old.quic_listen != new.quic_listen
+        || old.metrics_listen != new.metrics_listen
+        || old.dnstap != new.dnstap
+        || old.tls_cert != new.tls_cert
+        || old.tls_key != new.tls_key
+}
+
+/// Re-reads the configuration with `read` on every SIGHUP, and swaps in a service built
+/// from it. Queries already being answered finish with the service they started with. If
+/// the new configuration is invalid, the current service is kept.
+///
+/// Listeners stay as they were started, so changes to them need a restart.
+async fn reload_on_hangup<F>(mut hangups: Signal, server: Arc<Server>, config: Config, read: F)
+where
+    F: Fn() -> Result<Config, BoxError>,
+{
+    let mut applied = config.clone();
+    while hangups.recv().await.is_some() {
+        let new = match read() {
+            Ok(new) => new,
+            Err(e) => {
+                server.service().log.error(format_args!(
+                    "Not reloading, the configuration is invalid: {e}"
+                ));
+                continue;
+            }
+        };
+
+        let cache = match cache_compatible(&applied, &new) {
+            true => Some(server.service().cache.clone()),
+            false => None,
+        };
+        let log = server.service().log.clone();
+        let service = match Service::new(&new, cache, server.dnstap.as_ref(), &log).await {
+            Ok(service) => Arc::new(service),
+            Err(e) => {
+                log.error(format_args!(
+                    "Not reloading, the configuration cannot be applied: {e}"
+                ));
+                continue;
+            }
+        };
+        if let Err(e) = log.reconfigure(&new) {
+            log.error(format_args!(
+                "Not reloading, the log cannot be reopened: {e}"
+            ));
+            continue;
+        }
+        *server.service.write().unwrap() = service.clone();
+
+        if listeners_changed(&config, &new) {
+            service
+                .log
+                .warn("Changes to listeners, certificates and dnstap take effect on restart");
+        }
+        service.log.info("Configuration reloaded");
+        applied = new;
+    }
 }
 
+/// Answers a query in wire form from `client`, which arrived over `transport`, returning
+/// the reply to send back, if any.
 async fn handle(
-    sock: Arc<UdpSocket>,
     bytes: Vec<u8>,
-    addr: SocketAddr,
-    resolver: Option<&'static str>,
-) {
-    let mut bytes = Cursor::new(&*bytes);
+    client: SocketAddr,
+    transport: ClientTransport,
+    server: Arc<Server>,
+) -> Option<Reply> {
+    let query = server.start_query()?;
+    let started = Instant::now();
+    let received = SystemTime::now();
+    let service = server.service();
+    if let Some(dnstap) = &server.dnstap {
+        dnstap.emit(&dnstap::Message {
+            message_type: MessageType::ClientQuery,
+            protocol: transport.into(),
+            query_address: Some(client),
+            response_address: None,
+            query_time: received,
+            query_message: Some(&bytes),
+            response_time: None,
+            response_message: None,
+        });
+    }
 
-    let mut message = match DnsMessage::try_parse(&mut bytes) {
+    let message = match DnsMessage::try_parse(&mut Cursor::new(&bytes[..])) {
         Ok(message) => message,
         Err(e) => {
-            eprintln!("failed parsing packet as DnsMessage: {}", e);
-            return;
+            service
+                .log
+                .warn(format_args!("Failed parsing query from {client}: {e}"));
+            server.metrics.record_parse_failure(&e);
+            return None;
         }
     };
+    let question = message.questions.first();
+    let qname = question.map(|question| question.name.to_string());
+    let qtype = question.map(|question| question.qtype);
+
+    let max_size = match transport {
+        ClientTransport::Udp => udp::max_reply_size(&message),
+        _ => usize::MAX,
+    };
+
+    let answered = answer(message, &bytes, client, &service).await;
+    let (reply, sources) = match answered {
+        Some((reply, sources)) => (Some(udp::truncate(reply, max_size)), sources),
+        None => (None, Vec::new()),
+    };
+    let rcode = reply
+        .as_ref()
+        .and_then(|reply| reply.get(3))
+        .map(|flags| flags & 0x0f);
+    server.metrics.record_query(transport, qtype, rcode);
+    service.log.query(&QueryEntry {
+        client,
+        transport,
+        qname,
+        qtype,
+        rcode,
+        answers: reply
+            .as_ref()
+            .and_then(|reply| reply.get(6..8))
+            .map_or(0, |count| u16::from_be_bytes([count[0], count[1]])),
+        sources: &sources,
+        latency: started.elapsed(),
+    });
+    if let (Some(dnstap), Some(reply)) = (&server.dnstap, &reply) {
+        dnstap.emit(&dnstap::Message {
+            message_type: MessageType::ClientResponse,
+            protocol: transport.into(),
+            query_address: Some(client),
+            response_address: None,
+            query_time: received,
+            query_message: Some(&bytes),
+            response_time: Some(SystemTime::now()),
+            response_message: Some(reply),
+        });
+    }
+
+    reply.map(|bytes| Reply {
+        bytes,
+        _query: query,
+    })
+}
+
+/// Answers `message`, returning the reply along with where the answers in it came from.
+async fn answer(
+    mut message: DnsMessage<'_>,
+    bytes: &[u8],
+    client: SocketAddr,
+    service: &Service,
+) -> Option<(Vec<u8>, Vec<Source>)> {
+    if !service.acl.allows(client.ip()) {
+        return Some((refuse(message), Vec::new()));
+    }
 
-    match resolver {
-        Some(address) => {
-            if let Err(e) = handle_forward_query(sock, addr, message, address).await {
-                eprintln!("failed forwarding query: {}", e);
+    match &service.forwarder {
+        Some(forwarder) => match forwarder.handle_query(message, bytes).await {
+            Ok(answered) => Some(answered),
+            Err(e) => {
+                service
+                    .log
+                    .error(format_args!("Failed forwarding query: {e}"));
+                None
             }
-        }
+        },
 
         None => {
             message.header.qr_indicator = true;
@@ -98,21 +466,22 @@ async fn handle(
             message.header.recursion_available = false;
             message.header.reserved = 0;
             if message.header.opcode == Opcode::StandardQuery {
-                message.header.response_code = 0;
+                message.header.response_code = RCODE_NO_ERROR;
             } else {
-                message.header.response_code = 4;
+                message.header.response_code = RCODE_NOT_IMPLEMENTED;
             }
             message.header.answer_record_count = message.header.question_count;
 
+            let (address, ttl) = service.fallback;
             let mut records = Vec::new();
             for i in 0..message.header.question_count {
                 records.push(ResourceRecord {
                     name: message.questions[i as usize].name.clone(),
                     atype: Type::A,
                     class: Class::IN,
-                    ttl: 60,
+                    ttl,
                     rdata: RData::A {
-                        address: u32::from_be_bytes([8, 8, 8, 8]),
+                        address: u32::from(address),
                     },
                 });
             }
@@ -120,75 +489,340 @@ async fn handle(
 
             let mut buf = Vec::with_capacity(128);
             message.serialize(&mut buf).unwrap();
-            match sock.send_to(&buf, &addr).await {
-                Ok(len) => println!("Sent {} bytes to {}", len, addr),
-                Err(e) => eprintln!("Error sending to {}: {}", addr, e),
-            }
+            Some((buf, Vec::new()))
         }
     }
 }
 
-fn usage(program: &str) {
-    println!("Usage: {program} [--resolver <address>]");
+/// Builds a REFUSED reply to `message`, for clients that may not send queries.
+fn refuse(mut message: DnsMessage<'_>) -> Vec<u8> {
+    message.header.qr_indicator = true;
+    message.header.authoritative_answer = false;
+    message.header.truncation = false;
+    message.header.recursion_available = false;
+    message.header.reserved = 0;
+    message.header.response_code = RCODE_REFUSED;
+    message.header.answer_record_count = 0;
+    message.header.authority_record_count = 0;
+    message.header.additional_record_count = 0;
+    message.records.clear();
+    message.authority_records.clear();
+    message.additional_records.clear();
+    message.edns = None;
+
+    let mut buf = Vec::with_capacity(64);
+    message.serialize(&mut buf).unwrap();
+    buf
+}
+
+/// Resolves a server address given on the command line.
+async fn resolve_address(addr: &str) -> Result<SocketAddr, BoxError> {
+    Ok(net::lookup_host(addr)
+        .await?
+        .next()
+        .ok_or_else(|| format!("server address {addr} did not resolve"))?)
 }
 
-fn parse_args() -> Option<&'static str> {
-    let mut resolver: Option<&'static str> = None;
+/// Resolves server addresses given on the command line.
+async fn resolve_addresses(addrs: &[String]) -> Result<Vec<SocketAddr>, BoxError> {
+    let mut resolved = Vec::new();
+    for addr in addrs {
+        resolved.push(resolve_address(addr).await?);
+    }
 
-    let program = env::args().next().unwrap();
-    let mut args = env::args().skip(1);
-    while let Some(arg) = args.next() {
-        match arg.as_str() {
-            "--help" => {
-                usage(&program);
-                std::process::exit(0);
-            }
+    Ok(resolved)
+}
 
-            "--resolver" => match args.next() {
-                Some(addr) => {
-                    resolver = Some(addr.leak());
-                }
-                None => {
-                    usage(&program);
-                    std::process::exit(1);
-                }
-            },
-
-            _ => {
-                usage(&program);
-                std::process::exit(1);
-            }
-        }
+/// Resolves upstream addresses given on the command line. Those of the form
+/// `tls://<host>:<port>` are queried over TLS, expecting a certificate for `<host>`, and
+/// those of the form `https://<host>[:<port>]/<path>` over HTTPS. Messages exchanged with
+/// them are emitted to `dnstap`, if given.
+async fn resolve_upstreams(
+    addrs: &[String],
+    strategy: Strategy,
+    tls_config: &Arc<ClientConfig>,
+    dnstap: Option<&Arc<Dnstap>>,
+    log: &Arc<Logger>,
+) -> Result<UpstreamSet, BoxError> {
+    let mut upstreams = Vec::new();
+    for addr in addrs {
+        let upstream = if let Some(addr) = addr.strip_prefix("tls://") {
+            let (host, _) = addr
+                .rsplit_once(':')
+                .ok_or_else(|| format!("TLS resolver {addr} has no port"))?;
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            let transport = Transport::Tls {
+                config: tls_config.clone(),
+                server_name: ServerName::try_from(host.to_string())?,
+            };
+            Upstream::new(resolve_address(addr).await?, transport, log.clone())
+        } else if addr.starts_with("https://") {
+            let uri: Uri = addr.parse()?;
+            let host = uri
+                .host()
+                .ok_or_else(|| format!("HTTPS resolver {addr} has no host"))?;
+            let address = format!("{host}:{}", uri.port_u16().unwrap_or(HTTPS_PORT));
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            let transport = Transport::Https {
+                config: tls_config.clone(),
+                server_name: ServerName::try_from(host.to_string())?,
+                uri,
+            };
+            Upstream::new(resolve_address(&address).await?, transport, log.clone())
+        } else {
+            Upstream::new(resolve_address(addr).await?, Transport::Udp, log.clone())
+        };
+        upstreams.push(upstream);
     }
 
-    resolver
+    let upstreams = UpstreamSet::with_upstreams(upstreams, strategy);
+    Ok(match dnstap {
+        Some(dnstap) => upstreams.with_dnstap(dnstap.clone()),
+        None => upstreams,
+    })
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let resolver = parse_args();
-
-    let sock = UdpSocket::bind("127.0.0.1:2053").await?;
-    let sock = Arc::new(sock);
-    let (tx, mut rx) = mpsc::channel::<(Vec<u8>, SocketAddr)>(1000);
-
-    tokio::spawn({
-        let send_sock = sock.clone();
-        async move {
-            while let Some((bytes, addr)) = rx.recv().await {
-                tokio::spawn(handle(send_sock.clone(), bytes, addr, resolver));
-            }
-        }
-    });
+async fn main() -> Result<(), BoxError> {
+    if config::help() {
+        return Ok(());
+    }
+    let config = config::read()?;
 
-    let mut buf = [0; 512];
-    loop {
-        match sock.recv_from(&mut buf).await {
-            Ok((len, addr)) => {
-                println!("Received {} bytes from {}", len, addr);
-                tx.send((buf[..len].to_vec(), addr)).await?;
+    let log = Arc::new(Logger::new(&config)?);
+    let dnstap = config
+        .dnstap
+        .clone()
+        .map(|output| Arc::new(Dnstap::new(output, log.clone())));
+    let service = Service::new(&config, None, dnstap.as_ref(), &log).await?;
+    let server = Arc::new(Server::new(service, dnstap));
+    let hangups = signal(SignalKind::hangup())?;
+    let mut interrupts = signal(SignalKind::interrupt())?;
+    let mut terminations = signal(SignalKind::terminate())?;
+
+    let server_config = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::server_config(cert, key)?),
+        _ => None,
+    };
+
+    let mut listeners = JoinSet::new();
+    if let (Some(addr), Some(server_config)) = (&config.tls_listen, &server_config) {
+        let listener = TcpListener::bind(addr).await?;
+        let server = server.clone();
+        let serve = tls::serve(
+            listener,
+            server_config.clone(),
+            move |bytes, client| handle(bytes, client, ClientTransport::Tls, server.clone()),
+            log.clone(),
+        );
+        listeners.spawn(async move {
+            serve.await;
+            Ok(())
+        });
+    }
+
+    if let (Some(addr), Some(server_config)) = (&config.https_listen, &server_config) {
+        let listener = TcpListener::bind(addr).await?;
+        let server = server.clone();
+        let serve = doh::serve(
+            listener,
+            server_config.clone(),
+            move |bytes, client| handle(bytes, client, ClientTransport::Https, server.clone()),
+            log.clone(),
+        );
+        listeners.spawn(async move {
+            serve.await;
+            Ok(())
+        });
+    }
+
+    if let (Some(addr), Some(server_config)) = (&config.quic_listen, &server_config) {
+        let endpoint = doq::endpoint(resolve_address(addr).await?, server_config.clone())?;
+        let server = server.clone();
+        let serve = doq::serve(
+            endpoint,
+            move |bytes, client| handle(bytes, client, ClientTransport::Quic, server.clone()),
+            log.clone(),
+        );
+        listeners.spawn(async move {
+            serve.await;
+            Ok(())
+        });
+    }
+
+    for &addr in &config.listen {
+        let socket = udp::bind(addr)?;
+        let server = server.clone();
+        listeners.spawn(udp::serve(
+            socket,
+            move |bytes, client| handle(bytes, client, ClientTransport::Udp, server.clone()),
+            log.clone(),
+        ));
+    }
+
+    if let Some(addr) = &config.metrics_listen {
+        let listener = TcpListener::bind(addr).await?;
+        let server = server.clone();
+        let serve = metrics::serve(listener, move || server.render_metrics(), log.clone());
+        listeners.spawn(async move {
+            serve.await;
+            Ok(())
+        });
+    }
+
+    tokio::spawn(report_cache_stats(server.clone()));
+    tokio::spawn(reload_on_hangup(
+        hangups,
+        server.clone(),
+        config,
+        config::read,
+    ));
+
+    // Listeners only ever stop when they fail
+    let failed = tokio::select! {
+        Some(result) = listeners.join_next() => Some(result),
+        _ = interrupts.recv() => None,
+        _ = terminations.recv() => None,
+    };
+
+    // Stop reading queries, and give those already read a chance to be answered.
+    // Connections accepted earlier stay open, but have further queries turned away.
+    listeners.abort_all();
+    let service = server.service();
+    service.log.info("Shutting down");
+    let unanswered = server.drain(SHUTDOWN_TIMEOUT).await;
+    if let Some(dnstap) = &server.dnstap {
+        dnstap.finish().await;
+    }
+    service.log.flush();
+
+    if let Some(result) = failed {
+        result??;
+    }
+    if unanswered > 0 {
+        return Err(format!("Shut down with {unanswered} queries unanswered").into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use nix::sys::signal::{self as signals, Signal as SignalNumber};
+
+    use super::*;
+    use crate::testing::{self, StubServer};
+
+    /// Asks `server` for the address of `name`.
+    async fn ask(server: &Arc<Server>, name: &str) -> [u8; 4] {
+        let client = "127.0.0.1:5353".parse().unwrap();
+        let query = testing::query(1, name);
+        let reply = handle(query, client, ClientTransport::Udp, server.clone()).await;
+        testing::answer(&reply.unwrap().bytes)
+    }
+
+    /// Sends ourselves a SIGHUP and waits for a new service to be swapped in.
+    async fn hang_up(server: &Server) -> Arc<Service> {
+        let before = server.service();
+        signals::raise(SignalNumber::SIGHUP).unwrap();
+        for _ in 0..100 {
+            let service = server.service();
+            if !Arc::ptr_eq(&service, &before) {
+                return service;
             }
-            Err(e) => eprintln!("Error receiving: {e}"),
+            time::sleep(Duration::from_millis(10)).await;
         }
+        panic!("the configuration was not reloaded");
+    }
+
+    #[tokio::test]
+    async fn draining_waits_for_replies_to_be_sent() {
+        let service = Service::new(&Config::default(), None, None, &testing::logger())
+            .await
+            .unwrap();
+        let server = Arc::new(Server::new(service, None));
+        let client = "127.0.0.1:5353".parse().unwrap();
+
+        let reply = handle(
+            testing::query(1, "example.com."),
+            client,
+            ClientTransport::Udp,
+            server.clone(),
+        )
+        .await
+        .unwrap();
+        // The transport still has the reply to send
+        assert_eq!(server.drain(Duration::from_millis(50)).await, 1);
+
+        drop(reply);
+        assert_eq!(server.drain(Duration::from_millis(50)).await, 0);
+
+        let refused = handle(
+            testing::query(2, "example.com."),
+            client,
+            ClientTransport::Udp,
+            server.clone(),
+        )
+        .await;
+        assert!(refused.is_none());
+    }
+
+    #[tokio::test]
+    async fn hangups_swap_in_the_changed_config_file() {
+        let first = StubServer::start(|query: Vec<u8>| async move {
+            Some(testing::reply(&query, [192, 0, 2, 1]))
+        })
+        .await;
+        let second = StubServer::start(|query: Vec<u8>| async move {
+            Some(testing::reply(&query, [192, 0, 2, 2]))
+        })
+        .await;
+        let path = env::temp_dir().join(format!("dns-server-reload-{}.toml", process::id()));
+        let write = |resolver: SocketAddr, fallback_ttl: u32| {
+            let contents = format!(
+                r#"
+                [upstream]
+                resolvers = ["{resolver}"]
+
+                [fallback]
+                ttl = {fallback_ttl}
+
+                [log]
+                level = "error"
+                "#
+            );
+            fs::write(&path, contents).unwrap();
+        };
+        let args = ["dns-server", "--config", path.to_str().unwrap()].map(String::from);
+        let read = move || config::from_args(args.clone().into_iter());
+
+        write(first.address, 60);
+        let config = read().unwrap();
+        let service = Service::new(&config, None, None, &testing::logger())
+            .await
+            .unwrap();
+        let server = Arc::new(Server::new(service, None));
+        let hangups = signal(SignalKind::hangup()).unwrap();
+        tokio::spawn(reload_on_hangup(hangups, server.clone(), config, read));
+        assert_eq!(ask(&server, "example.com.").await, [192, 0, 2, 1]);
+        let cache = server.service().cache.clone();
+
+        // Nothing the cache depends on changed, so it is kept
+        write(first.address, 30);
+        let service = hang_up(&server).await;
+        assert_eq!(service.fallback.1, 30);
+        assert!(Arc::ptr_eq(&service.cache, &cache));
+
+        // Answers cached from the old resolver would be stale
+        write(second.address, 30);
+        let service = hang_up(&server).await;
+        assert!(!Arc::ptr_eq(&service.cache, &cache));
+        assert_eq!(ask(&server, "example.com.").await, [192, 0, 2, 2]);
+        assert_eq!(first.queries().len(), 1);
+        assert_eq!(second.queries().len(), 1);
+
+        fs::remove_file(&path).unwrap();
     }
 }
@@ -1,6 +1,8 @@
 use std::{
     borrow::Cow,
+    fmt,
     io::{Cursor, Write},
+    str::FromStr,
 };
 
 use bytes::{Buf, TryGetError};
@@ -31,6 +33,31 @@ pub enum Opcode {
     Invalid,
 }
 
+/// Largest DNS message, as bounded by the length prefix over streams and the size of a
+/// UDP datagram.
+pub const MAX_MESSAGE_SIZE: usize = u16::MAX as usize;
+
+/// Response codes (RFC 1035 section 4.1.1).
+pub const RCODE_NO_ERROR: u8 = 0;
+pub const RCODE_FORMAT_ERROR: u8 = 1;
+pub const RCODE_SERVER_FAILURE: u8 = 2;
+pub const RCODE_NAME_ERROR: u8 = 3;
+pub const RCODE_NOT_IMPLEMENTED: u8 = 4;
+pub const RCODE_REFUSED: u8 = 5;
+
+/// The mnemonic of a response code, or its number when it has none here.
+pub fn rcode_name(rcode: u8) -> String {
+    match rcode {
+        RCODE_NO_ERROR => "NOERROR".to_string(),
+        RCODE_FORMAT_ERROR => "FORMERR".to_string(),
+        RCODE_SERVER_FAILURE => "SERVFAIL".to_string(),
+        RCODE_NAME_ERROR => "NXDOMAIN".to_string(),
+        RCODE_NOT_IMPLEMENTED => "NOTIMP".to_string(),
+        RCODE_REFUSED => "REFUSED".to_string(),
+        _ => rcode.to_string(),
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DnsHeader {
     /// A random ID assigned to query packets. Response packets must reply with the same ID.
@@ -136,86 +163,159 @@ impl ByteSerialize for DnsHeader {
 }
 
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Type {
     /// a host address
-    A = 1,
+    A,
     /// an authoritative name server
-    NS = 2,
+    NS,
     /// a mail destination (Obsolete - use MX)
-    MD = 3,
+    MD,
     /// a mail forwarder (Obsolete - use MX)
-    MF = 4,
+    MF,
     /// the canonical name for an alias
-    CNAME = 5,
+    CNAME,
     /// marks the start of a zone of authority
-    SOA = 6,
+    SOA,
     /// a mailbox domain name (EXPERIMENTAL)
-    MB = 7,
+    MB,
     /// a mail group member (EXPERIMENTAL)
-    MG = 8,
+    MG,
     /// a mail rename domain name (EXPERIMENTAL)
-    MR = 9,
+    MR,
     /// a null RR (EXPERIMENTAL)
-    NULL = 10,
+    NULL,
     /// a well known service description
-    WKS = 11,
+    WKS,
     /// a domain name pointer
-    PTR = 12,
+    PTR,
     /// host information
-    HINFO = 13,
+    HINFO,
     /// mailbox or mail list information
-    MINFO = 14,
+    MINFO,
     /// mail exchange
-    MX = 15,
+    MX,
     /// text strings
-    TXT = 16,
+    TXT,
+    /// an IPv6 host address
+    AAAA,
+    /// a server selection record
+    SRV,
+    /// the EDNS pseudo-record, only found in the additional section
+    OPT,
+    /// a delegation signer
+    DS,
+    /// a DNSSEC signature
+    RRSIG,
+    /// the next secure record
+    NSEC,
+    /// a DNSSEC public key
+    DNSKEY,
+    /// the hashed next secure record
+    NSEC3,
 
     // Question Type Only
     /// A request for a transfer of an entire zone
-    AXFR = 252,
+    AXFR,
     /// A request for mailbox-related records (MB, MG or MR)
-    MAILB = 253,
+    MAILB,
     /// A request for mail agent RRs (Obsolete - see MX)
-    MAILA = 254,
+    MAILA,
     /// A request for all records
-    Wildcard = 255,
+    Wildcard,
+
+    /// A type not known here, whose records are kept as raw data
+    Unknown(u16),
 }
 
-impl TryFrom<u16> for Type {
-    type Error = DnsError;
+impl From<u16> for Type {
+    fn from(value: u16) -> Self {
+        match value {
+            1 => Type::A,
+            2 => Type::NS,
+            3 => Type::MD,
+            4 => Type::MF,
+            5 => Type::CNAME,
+            6 => Type::SOA,
+            7 => Type::MB,
+            8 => Type::MG,
+            9 => Type::MR,
+            10 => Type::NULL,
+            11 => Type::WKS,
+            12 => Type::PTR,
+            13 => Type::HINFO,
+            14 => Type::MINFO,
+            15 => Type::MX,
+            16 => Type::TXT,
+            28 => Type::AAAA,
+            33 => Type::SRV,
+            41 => Type::OPT,
+            43 => Type::DS,
+            46 => Type::RRSIG,
+            47 => Type::NSEC,
+            48 => Type::DNSKEY,
+            50 => Type::NSEC3,
 
-    fn try_from(value: u16) -> std::result::Result<Self, Self::Error> {
+            252 => Type::AXFR,
+            253 => Type::MAILB,
+            254 => Type::MAILA,
+            255 => Type::Wildcard,
+
+            value => Type::Unknown(value),
+        }
+    }
+}
+
+impl From<Type> for u16 {
+    fn from(value: Type) -> Self {
         match value {
-            1 => Ok(Type::A),
-            2 => Ok(Type::NS),
-            3 => Ok(Type::MD),
-            4 => Ok(Type::MF),
-            5 => Ok(Type::CNAME),
-            6 => Ok(Type::SOA),
-            7 => Ok(Type::MB),
-            8 => Ok(Type::MG),
-            9 => Ok(Type::MR),
-            10 => Ok(Type::NULL),
-            11 => Ok(Type::WKS),
-            12 => Ok(Type::PTR),
-            13 => Ok(Type::HINFO),
-            14 => Ok(Type::MINFO),
-            15 => Ok(Type::MX),
-            16 => Ok(Type::TXT),
+            Type::A => 1,
+            Type::NS => 2,
+            Type::MD => 3,
+            Type::MF => 4,
+            Type::CNAME => 5,
+            Type::SOA => 6,
+            Type::MB => 7,
+            Type::MG => 8,
+            Type::MR => 9,
+            Type::NULL => 10,
+            Type::WKS => 11,
+            Type::PTR => 12,
+            Type::HINFO => 13,
+            Type::MINFO => 14,
+            Type::MX => 15,
+            Type::TXT => 16,
+            Type::AAAA => 28,
+            Type::SRV => 33,
+            Type::OPT => 41,
+            Type::DS => 43,
+            Type::RRSIG => 46,
+            Type::NSEC => 47,
+            Type::DNSKEY => 48,
+            Type::NSEC3 => 50,
+
+            Type::AXFR => 252,
+            Type::MAILB => 253,
+            Type::MAILA => 254,
+            Type::Wildcard => 255,
 
-            252 => Ok(Type::AXFR),
-            253 => Ok(Type::MAILB),
-            254 => Ok(Type::MAILA),
-            255 => Ok(Type::Wildcard),
+            Type::Unknown(value) => value,
+        }
+    }
+}
 
-            _ => Err(DnsError::InvalidType),
+impl fmt::Display for Type {
+    /// Writes the mnemonic of the type, or `TYPE<number>` for unknown types (RFC 3597).
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Unknown(value) => write!(f, "TYPE{value}"),
+            known => write!(f, "{known:?}"),
         }
     }
 }
 
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Class {
     /// The Internet
     IN = 1,
@@ -248,82 +348,210 @@ impl TryFrom<u16> for Class {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Label<'packet> {
     section: Cow<'packet, [u8]>,
 }
 
+impl Label<'_> {
+    pub fn into_owned(self) -> Label<'static> {
+        Label {
+            section: Cow::Owned(self.section.into_owned()),
+        }
+    }
+}
+
 impl ByteSerialize for Label<'_> {
     fn serialize<W: Write>(&self, buf: &mut W) -> std::io::Result<()> {
         buf.write_all(&self.section)
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// Maximum number of compression pointers followed while parsing a single name.
+const MAX_POINTER_JUMPS: usize = 16;
+
+/// Maximum length of a name in wire format, including length octets and the root label.
+const MAX_NAME_LENGTH: usize = 255;
+
+/// Maximum length of a single label.
+const MAX_LABEL_LENGTH: usize = 63;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Name<'packet> {
     pub labels: Vec<Label<'packet>>,
 }
 
 impl<'packet> Name<'packet> {
     pub fn try_parse(buf: &mut Cursor<&'packet [u8]>) -> Result<Self> {
+        let packet: &'packet [u8] = buf.get_ref();
+        let mut pos = buf.position() as usize;
+        // Position just past the name in the original stream, set on the first pointer
+        let mut end = None;
+        let mut jumps = 0;
+        let mut length = 1;
+
         let mut labels = Vec::new();
         loop {
-            match buf.try_get_u8()? {
-                0 => break,
+            let b = *packet.get(pos).ok_or(DnsError::NotEnoughData(TryGetError {
+                requested: 1,
+                available: 0,
+            }))?;
+
+            match b {
+                0 => {
+                    pos += 1;
+                    break;
+                }
 
                 // compressed label
                 p if p & 0b1100_0000 == 0b1100_0000 => {
-                    let b1 = p & 0b0011_1111;
-                    let b2 = buf.try_get_u8()?;
-                    let offset = u16::from_be_bytes([b1, b2]) as usize;
+                    let b2 = *packet
+                        .get(pos + 1)
+                        .ok_or(DnsError::NotEnoughData(TryGetError {
+                            requested: 1,
+                            available: 0,
+                        }))?;
+                    let offset = u16::from_be_bytes([p & 0b0011_1111, b2]) as usize;
 
                     // check offset bounds
-                    if offset >= buf.get_ref().len() {
+                    if offset >= packet.len() {
                         return Err(DnsError::InvalidName("reference out of bounds"));
                     }
 
-                    let packet = buf.get_ref();
-                    let pos = packet[offset..]
-                        .iter()
-                        .position(|&b| b == 0)
-                        .ok_or(DnsError::InvalidName("null terminator missing"))?;
+                    jumps += 1;
+                    if jumps > MAX_POINTER_JUMPS {
+                        return Err(DnsError::InvalidName("too many compression pointers"));
+                    }
 
-                    labels.push(Label {
-                        section: Cow::from(&packet[offset..offset + pos]),
-                    });
+                    end.get_or_insert(pos + 2);
+                    pos = offset;
+                }
 
-                    break;
+                p if p & 0b1100_0000 != 0 => {
+                    return Err(DnsError::InvalidName("unsupported label type"));
                 }
 
                 // uncompressed label
                 len => {
-                    if buf.remaining() < len as usize {
+                    let len = len as usize;
+                    let available = packet.len() - pos - 1;
+                    if available < len {
                         return Err(DnsError::NotEnoughData(TryGetError {
-                            requested: len as usize,
-                            available: buf.remaining(),
+                            requested: len,
+                            available,
                         }));
                     }
-                    let pos = buf.position() as usize;
-                    let len = len as usize;
-                    let section = &buf.get_ref()[pos - 1..pos + len];
-                    buf.advance(len);
+
+                    length += len + 1;
+                    if length > MAX_NAME_LENGTH {
+                        return Err(DnsError::InvalidName("name too long"));
+                    }
 
                     labels.push(Label {
-                        section: Cow::from(section),
+                        section: Cow::from(&packet[pos..pos + len + 1]),
                     });
+                    pos += len + 1;
                 }
             }
+        }
+
+        buf.set_position(end.unwrap_or(pos) as u64);
+
+        Ok(Self { labels })
+    }
+
+    pub fn into_owned(self) -> Name<'static> {
+        Name {
+            labels: self.labels.into_iter().map(Label::into_owned).collect(),
+        }
+    }
+
+    pub fn eq_ignore_ascii_case(&self, other: &Name<'_>) -> bool {
+        self.labels.len() == other.labels.len()
+            && self
+                .labels
+                .iter()
+                .zip(&other.labels)
+                .all(|(a, b)| a.section.eq_ignore_ascii_case(&b.section))
+    }
+
+    /// Whether this name is `zone` or falls below it, ignoring case.
+    pub fn is_subdomain_of(&self, zone: &Name<'_>) -> bool {
+        self.labels.len() >= zone.labels.len()
+            && self
+                .labels
+                .iter()
+                .rev()
+                .zip(zone.labels.iter().rev())
+                .all(|(a, b)| a.section.eq_ignore_ascii_case(&b.section))
+    }
+
+    /// Returns a copy of this name with all ASCII letters lowercased, suitable for
+    /// case-insensitive comparisons and lookups.
+    pub fn to_ascii_lowercase(&self) -> Name<'static> {
+        Name {
+            labels: self
+                .labels
+                .iter()
+                .map(|label| Label {
+                    section: Cow::Owned(label.section.to_ascii_lowercase()),
+                })
+                .collect(),
+        }
+    }
+}
 
-            if labels.len() > 30 {
-                // Constrain the maximum number of labels allowed
-                break;
+impl FromStr for Name<'static> {
+    type Err = DnsError;
+
+    /// Parses a name in dotted notation, such as `www.example.com.`. The trailing dot is
+    /// optional, and `.` alone is the root.
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.strip_suffix('.').unwrap_or(s);
+        if s.is_empty() {
+            return Ok(Self { labels: vec![] });
+        }
+
+        let mut length = 1;
+        let mut labels = Vec::new();
+        for label in s.split('.') {
+            if label.is_empty() {
+                return Err(DnsError::InvalidName("empty label"));
+            }
+            if label.len() > MAX_LABEL_LENGTH {
+                return Err(DnsError::InvalidName("label too long"));
             }
+
+            length += label.len() + 1;
+            if length > MAX_NAME_LENGTH {
+                return Err(DnsError::InvalidName("name too long"));
+            }
+
+            let mut section = Vec::with_capacity(label.len() + 1);
+            section.push(label.len() as u8);
+            section.extend_from_slice(label.as_bytes());
+            labels.push(Label {
+                section: Cow::Owned(section),
+            });
         }
 
         Ok(Self { labels })
     }
 }
 
+impl fmt::Display for Name<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.labels.is_empty() {
+            return f.write_str(".");
+        }
+        for label in &self.labels {
+            write!(f, "{}.", String::from_utf8_lossy(&label.section[1..]))?;
+        }
+
+        Ok(())
+    }
+}
+
 impl ByteSerialize for Name<'_> {
     fn serialize<W: Write>(&self, buf: &mut W) -> std::io::Result<()> {
         for label in &self.labels {
@@ -343,36 +571,209 @@ pub struct DnsQuestion<'packet> {
 impl<'packet> DnsQuestion<'packet> {
     pub fn try_parse(buf: &mut Cursor<&'packet [u8]>) -> Result<Self> {
         let name = Name::try_parse(buf)?;
-        let qtype = Type::try_from(buf.try_get_u16()?)?;
+        let qtype = Type::from(buf.try_get_u16()?);
         let class = Class::try_from(buf.try_get_u16()?)?;
 
         Ok(Self { name, qtype, class })
     }
+
+    pub fn into_owned(self) -> DnsQuestion<'static> {
+        DnsQuestion {
+            name: self.name.into_owned(),
+            qtype: self.qtype,
+            class: self.class,
+        }
+    }
 }
 
 impl ByteSerialize for DnsQuestion<'_> {
     fn serialize<W: Write>(&self, buf: &mut W) -> std::io::Result<()> {
         self.name.serialize(buf)?;
 
-        let qtype = (self.qtype as u16).to_be_bytes();
+        let qtype = u16::from(self.qtype).to_be_bytes();
         let class = (self.class as u16).to_be_bytes();
         buf.write_all(&[qtype[0], qtype[1], class[0], class[1]])
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum RData {
-    A { address: u32 },
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RData<'packet> {
+    A {
+        address: u32,
+    },
+    NS {
+        nsdname: Name<'packet>,
+    },
+    CNAME {
+        cname: Name<'packet>,
+    },
+    SOA {
+        mname: Name<'packet>,
+        rname: Name<'packet>,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+    },
+    PTR {
+        ptrdname: Name<'packet>,
+    },
+    MX {
+        preference: u16,
+        exchange: Name<'packet>,
+    },
+    AAAA {
+        address: u128,
+    },
+    /// Record data of a type whose contents are kept opaque.
+    Raw {
+        data: Cow<'packet, [u8]>,
+    },
+}
+
+impl<'packet> RData<'packet> {
+    pub fn try_parse(buf: &mut Cursor<&'packet [u8]>, rtype: Type, rdlength: u16) -> Result<Self> {
+        let rdlength = rdlength as usize;
+        if buf.remaining() < rdlength {
+            return Err(DnsError::NotEnoughData(TryGetError {
+                requested: rdlength,
+                available: buf.remaining(),
+            }));
+        }
+        let end = buf.position() as usize + rdlength;
+
+        let rdata = match rtype {
+            Type::A if rdlength == 4 => RData::A {
+                address: buf.get_u32(),
+            },
+            Type::A => return Err(DnsError::InvalidRecordData("A record must be 4 bytes")),
+            Type::AAAA if rdlength == 16 => RData::AAAA {
+                address: buf.get_u128(),
+            },
+            Type::AAAA => return Err(DnsError::InvalidRecordData("AAAA record must be 16 bytes")),
+            Type::NS => RData::NS {
+                nsdname: Name::try_parse(buf)?,
+            },
+            Type::CNAME => RData::CNAME {
+                cname: Name::try_parse(buf)?,
+            },
+            Type::PTR => RData::PTR {
+                ptrdname: Name::try_parse(buf)?,
+            },
+            Type::MX => RData::MX {
+                preference: buf.try_get_u16()?,
+                exchange: Name::try_parse(buf)?,
+            },
+            Type::SOA => RData::SOA {
+                mname: Name::try_parse(buf)?,
+                rname: Name::try_parse(buf)?,
+                serial: buf.try_get_u32()?,
+                refresh: buf.try_get_u32()?,
+                retry: buf.try_get_u32()?,
+                expire: buf.try_get_u32()?,
+                minimum: buf.try_get_u32()?,
+            },
+            _ => {
+                let packet: &'packet [u8] = buf.get_ref();
+                let data = &packet[end - rdlength..end];
+                buf.advance(rdlength);
+                RData::Raw {
+                    data: Cow::from(data),
+                }
+            }
+        };
+
+        if buf.position() as usize != end {
+            return Err(DnsError::InvalidRecordData(
+                "length does not match contents",
+            ));
+        }
+
+        Ok(rdata)
+    }
+
+    pub fn into_owned(self) -> RData<'static> {
+        match self {
+            RData::A { address } => RData::A { address },
+            RData::NS { nsdname } => RData::NS {
+                nsdname: nsdname.into_owned(),
+            },
+            RData::CNAME { cname } => RData::CNAME {
+                cname: cname.into_owned(),
+            },
+            RData::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => RData::SOA {
+                mname: mname.into_owned(),
+                rname: rname.into_owned(),
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            },
+            RData::PTR { ptrdname } => RData::PTR {
+                ptrdname: ptrdname.into_owned(),
+            },
+            RData::MX {
+                preference,
+                exchange,
+            } => RData::MX {
+                preference,
+                exchange: exchange.into_owned(),
+            },
+            RData::AAAA { address } => RData::AAAA { address },
+            RData::Raw { data } => RData::Raw {
+                data: Cow::Owned(data.into_owned()),
+            },
+        }
+    }
 }
 
-impl ByteSerialize for RData {
+impl ByteSerialize for RData<'_> {
     fn serialize<W: Write>(&self, buf: &mut W) -> std::io::Result<()> {
-        match *self {
-            RData::A { address } => {
-                let address = address.to_be_bytes();
-                buf.write_all(&[0, 4, address[0], address[1], address[2], address[3]])
+        let mut data = Vec::with_capacity(64);
+        match self {
+            RData::A { address } => data.write_all(&address.to_be_bytes())?,
+            RData::NS { nsdname } => nsdname.serialize(&mut data)?,
+            RData::CNAME { cname } => cname.serialize(&mut data)?,
+            RData::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                mname.serialize(&mut data)?;
+                rname.serialize(&mut data)?;
+                for value in [serial, refresh, retry, expire, minimum] {
+                    data.write_all(&value.to_be_bytes())?;
+                }
+            }
+            RData::PTR { ptrdname } => ptrdname.serialize(&mut data)?,
+            RData::MX {
+                preference,
+                exchange,
+            } => {
+                data.write_all(&preference.to_be_bytes())?;
+                exchange.serialize(&mut data)?;
             }
+            RData::AAAA { address } => data.write_all(&address.to_be_bytes())?,
+            RData::Raw { data: raw } => data.write_all(raw)?,
         }
+
+        buf.write_all(&(data.len() as u16).to_be_bytes())?;
+        buf.write_all(&data)
     }
 }
 
@@ -382,36 +783,45 @@ pub struct ResourceRecord<'packet> {
     pub atype: Type,
     pub class: Class,
     pub ttl: u32,
-    pub rdata: RData,
+    pub rdata: RData<'packet>,
 }
 
 impl<'packet> ResourceRecord<'packet> {
     pub fn try_parse(buf: &mut Cursor<&'packet [u8]>) -> Result<Self> {
         let name = Name::try_parse(buf)?;
 
-        let atype = Type::try_from(buf.try_get_u16()?)?;
+        let atype = Type::from(buf.try_get_u16()?);
         let class = Class::try_from(buf.try_get_u16()?)?;
         let ttl = buf.try_get_u32()?;
 
-        // This assumes atype is a Type::A
-        let _rdlength = buf.try_get_u16()?;
-        let address = buf.try_get_u32()?;
+        let rdlength = buf.try_get_u16()?;
+        let rdata = RData::try_parse(buf, atype, rdlength)?;
 
         Ok(Self {
             name,
             atype,
             class,
             ttl,
-            rdata: RData::A { address },
+            rdata,
         })
     }
+
+    pub fn into_owned(self) -> ResourceRecord<'static> {
+        ResourceRecord {
+            name: self.name.into_owned(),
+            atype: self.atype,
+            class: self.class,
+            ttl: self.ttl,
+            rdata: self.rdata.into_owned(),
+        }
+    }
 }
 
 impl ByteSerialize for ResourceRecord<'_> {
     fn serialize<W: Write>(&self, buf: &mut W) -> std::io::Result<()> {
         self.name.serialize(buf)?;
 
-        let atype = (self.atype as u16).to_be_bytes();
+        let atype = u16::from(self.atype).to_be_bytes();
         let class = (self.class as u16).to_be_bytes();
         let ttl = self.ttl.to_be_bytes();
         buf.write_all(&[
@@ -422,16 +832,65 @@ impl ByteSerialize for ResourceRecord<'_> {
     }
 }
 
+/// The EDNS(0) OPT pseudo-record of a message (RFC 6891). Options are not kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edns {
+    /// Largest UDP payload the sender can receive.
+    pub udp_payload_size: u16,
+    /// Upper eight bits of the extended response code.
+    pub extended_rcode: u8,
+    pub version: u8,
+    /// The sender wants DNSSEC records in the reply.
+    pub dnssec_ok: bool,
+}
+
+impl Edns {
+    const DNSSEC_OK: u32 = 1 << 15;
+
+    /// Parses the fixed fields of an OPT record, given the `class` and `ttl` it was sent
+    /// with.
+    fn from_fields(class: u16, ttl: u32) -> Self {
+        Self {
+            udp_payload_size: class,
+            extended_rcode: (ttl >> 24) as u8,
+            version: (ttl >> 16) as u8,
+            dnssec_ok: ttl & Self::DNSSEC_OK != 0,
+        }
+    }
+}
+
+impl ByteSerialize for Edns {
+    fn serialize<W: Write>(&self, buf: &mut W) -> std::io::Result<()> {
+        let mut ttl = (self.extended_rcode as u32) << 24 | (self.version as u32) << 16;
+        if self.dnssec_ok {
+            ttl |= Self::DNSSEC_OK;
+        }
+
+        // Owned by the root, with no options
+        buf.write_all(&[0])?;
+        buf.write_all(&u16::from(Type::OPT).to_be_bytes())?;
+        buf.write_all(&self.udp_payload_size.to_be_bytes())?;
+        buf.write_all(&ttl.to_be_bytes())?;
+        buf.write_all(&0u16.to_be_bytes())
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DnsMessage<'packet> {
     pub header: DnsHeader,
     pub questions: Vec<DnsQuestion<'packet>>,
     pub records: Vec<ResourceRecord<'packet>>,
+    pub authority_records: Vec<ResourceRecord<'packet>>,
+    /// The Additional section, apart from the OPT record. Records that cannot be parsed
+    /// are skipped, as nothing depends on them, and left out of the header's count.
+    pub additional_records: Vec<ResourceRecord<'packet>>,
+    /// The OPT record from the Additional section.
+    pub edns: Option<Edns>,
 }
 
 impl<'packet> DnsMessage<'packet> {
     pub fn try_parse(buf: &mut Cursor<&'packet [u8]>) -> Result<Self> {
-        let header = DnsHeader::try_parse(buf)?;
+        let mut header = DnsHeader::try_parse(buf)?;
 
         let mut questions = Vec::new();
         for _ in 0..header.question_count {
@@ -445,12 +904,80 @@ impl<'packet> DnsMessage<'packet> {
             records.push(record);
         }
 
+        let mut authority_records = Vec::new();
+        for _ in 0..header.authority_record_count {
+            let record = ResourceRecord::try_parse(buf)?;
+            authority_records.push(record);
+        }
+
+        let mut additional_records = Vec::new();
+        let mut edns = None;
+        for _ in 0..header.additional_record_count {
+            let start = buf.position();
+            Name::try_parse(buf)?;
+            let atype = buf.try_get_u16()?;
+            let class = buf.try_get_u16()?;
+            let ttl = buf.try_get_u32()?;
+            let rdlength = buf.try_get_u16()? as usize;
+            if buf.remaining() < rdlength {
+                return Err(DnsError::NotEnoughData(TryGetError {
+                    requested: rdlength,
+                    available: buf.remaining(),
+                }));
+            }
+            buf.advance(rdlength);
+            let end = buf.position();
+
+            if Type::from(atype) == Type::OPT {
+                edns = Some(Edns::from_fields(class, ttl));
+                continue;
+            }
+
+            buf.set_position(start);
+            if let Ok(record) = ResourceRecord::try_parse(buf) {
+                additional_records.push(record);
+            }
+            buf.set_position(end);
+        }
+        header.additional_record_count =
+            additional_records.len() as u16 + u16::from(edns.is_some());
+
         Ok(Self {
             header,
             questions,
             records,
+            authority_records,
+            additional_records,
+            edns,
         })
     }
+
+    pub fn into_owned(self) -> DnsMessage<'static> {
+        DnsMessage {
+            header: self.header,
+            questions: self
+                .questions
+                .into_iter()
+                .map(DnsQuestion::into_owned)
+                .collect(),
+            records: self
+                .records
+                .into_iter()
+                .map(ResourceRecord::into_owned)
+                .collect(),
+            authority_records: self
+                .authority_records
+                .into_iter()
+                .map(ResourceRecord::into_owned)
+                .collect(),
+            additional_records: self
+                .additional_records
+                .into_iter()
+                .map(ResourceRecord::into_owned)
+                .collect(),
+            edns: self.edns,
+        }
+    }
 }
 
 impl ByteSerialize for DnsMessage<'_> {
@@ -462,7 +989,106 @@ impl ByteSerialize for DnsMessage<'_> {
         for record in &self.records {
             record.serialize(buf)?;
         }
+        for record in &self.authority_records {
+            record.serialize(buf)?;
+        }
+        for record in &self.additional_records {
+            record.serialize(buf)?;
+        }
+        if let Some(edns) = &self.edns {
+            edns.serialize(buf)?;
+        }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A reply for `example.com.` with the given answers, header counts taken from the
+    /// arguments as given.
+    fn reply(answers: &[(u16, &[u8])], additional: &[(u16, u16, &[u8])]) -> Vec<u8> {
+        let mut buf = vec![0x12, 0x34, 0x81, 0x80, 0, 1];
+        buf.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&[0, 0]);
+        buf.extend_from_slice(&(additional.len() as u16).to_be_bytes());
+        buf.extend_from_slice(b"\x07example\x03com\x00\x01\x01\x00\x01");
+        for (rtype, rdata) in answers {
+            buf.extend_from_slice(&[0xc0, 12]);
+            buf.extend_from_slice(&rtype.to_be_bytes());
+            buf.extend_from_slice(&[0, 1, 0, 0, 0, 60]);
+            buf.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            buf.extend_from_slice(rdata);
+        }
+        for (rtype, class, rdata) in additional {
+            buf.extend_from_slice(&[0xc0, 12]);
+            buf.extend_from_slice(&rtype.to_be_bytes());
+            buf.extend_from_slice(&class.to_be_bytes());
+            buf.extend_from_slice(&[0, 0, 0, 60]);
+            buf.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            buf.extend_from_slice(rdata);
+        }
+        buf
+    }
+
+    fn parse(buf: &[u8]) -> DnsMessage<'_> {
+        DnsMessage::try_parse(&mut Cursor::new(buf)).unwrap()
+    }
+
+    #[test]
+    fn unknown_types_are_kept_as_raw_data() {
+        let caa = b"\x00\x05issue\x0bexample.net";
+        let buf = reply(&[(257, caa), (1, &[192, 0, 2, 1])], &[]);
+
+        let message = parse(&buf);
+        assert_eq!(message.records[0].atype, Type::Unknown(257));
+        assert_eq!(
+            message.records[0].rdata,
+            RData::Raw {
+                data: Cow::from(&caa[..])
+            }
+        );
+        assert_eq!(message.records[1].atype, Type::A);
+
+        let mut serialized = Vec::new();
+        message.serialize(&mut serialized).unwrap();
+        assert_eq!(parse(&serialized), message);
+    }
+
+    #[test]
+    fn unknown_question_types_parse() {
+        let mut buf = reply(&[], &[]);
+        buf[2] = 0x01;
+        buf[3] = 0x00;
+        let qtype = buf.len() - 4;
+        buf[qtype..qtype + 2].copy_from_slice(&65u16.to_be_bytes());
+
+        let message = parse(&buf);
+        assert_eq!(message.questions[0].qtype, Type::Unknown(65));
+        assert_eq!(message.questions[0].qtype.to_string(), "TYPE65");
+    }
+
+    #[test]
+    fn skipped_additional_records_are_not_counted() {
+        let buf = reply(
+            &[],
+            &[
+                (1, 1, &[192, 0, 2, 1]),
+                // An A record of the wrong length, which is skipped
+                (1, 1, &[192, 0, 2]),
+                (41, 1232, &[]),
+            ],
+        );
+
+        let message = parse(&buf);
+        assert_eq!(message.additional_records.len(), 1);
+        assert!(message.edns.is_some());
+        assert_eq!(message.header.additional_record_count, 2);
+
+        let mut serialized = Vec::new();
+        message.serialize(&mut serialized).unwrap();
+        assert_eq!(parse(&serialized), message);
+    }
+}
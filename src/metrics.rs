@@ -0,0 +1,453 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
+    fmt::{self, Write},
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{
+    Method, Request, Response, StatusCode, body::Incoming, header::CONTENT_TYPE,
+    server::conn::http1, service::service_fn,
+};
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpListener;
+
+use crate::cache::CacheStats;
+use crate::error::DnsError;
+use crate::log::Logger;
+use crate::message::{Type, rcode_name};
+use crate::stream;
+
+/// Path that metrics are served on.
+const PATH: &str = "/metrics";
+
+/// Media type of the Prometheus text exposition format.
+const EXPOSITION_FORMAT: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// The transport a query arrived over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ClientTransport {
+    Udp,
+    Tls,
+    Https,
+    Quic,
+}
+
+impl fmt::Display for ClientTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientTransport::Udp => write!(f, "udp"),
+            ClientTransport::Tls => write!(f, "tls"),
+            ClientTransport::Https => write!(f, "https"),
+            ClientTransport::Quic => write!(f, "quic"),
+        }
+    }
+}
+
+/// A histogram of durations, counting each into the first bucket it fits.
+#[derive(Debug, Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|&bound| secs <= bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            buckets: self
+                .buckets
+                .each_ref()
+                .map(|bucket| bucket.load(Ordering::Relaxed)),
+            count: self.count.load(Ordering::Relaxed),
+            sum_micros: self.sum_micros.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A point-in-time copy of a [Histogram].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum_micros: u64,
+}
+
+impl HistogramSnapshot {
+    fn add(&mut self, other: &HistogramSnapshot) {
+        for (bucket, other) in self.buckets.iter_mut().zip(other.buckets) {
+            *bucket += other;
+        }
+        self.count += other.count;
+        self.sum_micros += other.sum_micros;
+    }
+}
+
+/// What queries are counted by: transport, query type and response code. Queries left
+/// unanswered have no response code.
+type QueryLabels = (ClientTransport, Option<Type>, Option<u8>);
+
+/// Counters of the queries served, kept for as long as the server runs.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    queries: Mutex<HashMap<QueryLabels, u64>>,
+    /// Queries that could not be parsed, by the kind of error.
+    parse_failures: Mutex<HashMap<&'static str, u64>>,
+}
+
+impl Metrics {
+    pub fn record_query(&self, transport: ClientTransport, qtype: Option<Type>, rcode: Option<u8>) {
+        *self
+            .queries
+            .lock()
+            .unwrap()
+            .entry((transport, qtype, rcode))
+            .or_default() += 1;
+    }
+
+    pub fn record_parse_failure(&self, error: &DnsError) {
+        *self
+            .parse_failures
+            .lock()
+            .unwrap()
+            .entry(error.kind())
+            .or_default() += 1;
+    }
+
+    /// Renders every metric in the Prometheus text exposition format, along with the
+    /// given gauges of the current service and the dnstap messages dropped, when dnstap
+    /// is emitted. Upstreams appearing more than once, such as in several zones, are
+    /// added up.
+    pub fn render(
+        &self,
+        in_flight: usize,
+        cache: CacheStats,
+        upstreams: impl IntoIterator<Item = (SocketAddr, HistogramSnapshot)>,
+        dnstap_dropped: Option<u64>,
+    ) -> String {
+        let mut out = String::new();
+
+        let queries = self.queries.lock().unwrap().clone();
+        let mut queries: Vec<_> = queries.into_iter().collect();
+        queries.sort_by_key(|&((transport, qtype, rcode), _)| {
+            (transport, qtype.map(u16::from), rcode)
+        });
+        header(
+            &mut out,
+            "dns_queries_total",
+            "counter",
+            "Queries received.",
+        );
+        for ((transport, qtype, rcode), count) in queries {
+            let qtype = match qtype {
+                Some(qtype) => qtype.to_string(),
+                None => "none".to_string(),
+            };
+            let rcode = match rcode {
+                Some(rcode) => rcode_name(rcode),
+                None => "none".to_string(),
+            };
+            let _ = writeln!(
+                out,
+                "dns_queries_total{{transport=\"{transport}\",qtype=\"{}\",rcode=\"{}\"}} {count}",
+                LabelValue(&qtype),
+                LabelValue(&rcode)
+            );
+        }
+
+        let parse_failures = self.parse_failures.lock().unwrap().clone();
+        let mut parse_failures: Vec<_> = parse_failures.into_iter().collect();
+        parse_failures.sort();
+        header(
+            &mut out,
+            "dns_query_parse_failures_total",
+            "counter",
+            "Queries that could not be parsed.",
+        );
+        for (error, count) in parse_failures {
+            let _ = writeln!(
+                out,
+                "dns_query_parse_failures_total{{error=\"{}\"}} {count}",
+                LabelValue(error)
+            );
+        }
+
+        header(
+            &mut out,
+            "dns_queries_in_flight",
+            "gauge",
+            "Queries being answered.",
+        );
+        let _ = writeln!(out, "dns_queries_in_flight {in_flight}");
+
+        for (name, help, value) in [
+            (
+                "dns_cache_hits_total",
+                "Lookups answered with cached records.",
+                cache.hits,
+            ),
+            (
+                "dns_cache_negative_hits_total",
+                "Lookups answered with a cached NXDOMAIN or NODATA.",
+                cache.negative_hits,
+            ),
+            (
+                "dns_cache_stale_hits_total",
+                "Answers served past their expiry because the upstream failed.",
+                cache.stale_hits,
+            ),
+            (
+                "dns_cache_misses_total",
+                "Lookups that had to go upstream.",
+                cache.misses,
+            ),
+            (
+                "dns_cache_prefetches_total",
+                "Popular entries refreshed ahead of their expiry.",
+                cache.prefetches,
+            ),
+        ] {
+            header(&mut out, name, "counter", help);
+            let _ = writeln!(out, "{name} {value}");
+        }
+
+        let mut latencies = BTreeMap::new();
+        for (address, latency) in upstreams {
+            latencies
+                .entry(address)
+                .or_insert_with(HistogramSnapshot::default)
+                .add(&latency);
+        }
+        header(
+            &mut out,
+            "dns_upstream_latency_seconds",
+            "histogram",
+            "Time upstream resolvers took to reply.",
+        );
+        for (address, latency) in latencies {
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(latency.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "dns_upstream_latency_seconds_bucket{{resolver=\"{address}\",le=\"{bound}\"}} {cumulative}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "dns_upstream_latency_seconds_bucket{{resolver=\"{address}\",le=\"+Inf\"}} {}",
+                latency.count
+            );
+            let _ = writeln!(
+                out,
+                "dns_upstream_latency_seconds_sum{{resolver=\"{address}\"}} {}",
+                latency.sum_micros as f64 / 1_000_000.0
+            );
+            let _ = writeln!(
+                out,
+                "dns_upstream_latency_seconds_count{{resolver=\"{address}\"}} {}",
+                latency.count
+            );
+        }
+
+        if let Some(dropped) = dnstap_dropped {
+            header(
+                &mut out,
+                "dns_dnstap_dropped_total",
+                "counter",
+                "dnstap messages dropped because the output fell behind.",
+            );
+            let _ = writeln!(out, "dns_dnstap_dropped_total {dropped}");
+        }
+
+        out
+    }
+}
+
+/// A label value, escaped as the text exposition format requires.
+struct LabelValue<'a>(&'a str);
+
+impl fmt::Display for LabelValue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '\\' => f.write_str("\\\\")?,
+                '"' => f.write_str("\\\"")?,
+                '\n' => f.write_str("\\n")?,
+                c => f.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// Serves metrics over plain HTTP on `listener` at `/metrics`, as rendered by `render`
+/// for every request.
+pub async fn serve<R>(listener: TcpListener, render: R, log: Arc<Logger>)
+where
+    R: Fn() -> String + Clone + Send + 'static,
+{
+    loop {
+        let (stream, addr) = stream::accept(&listener, "metrics", &log).await;
+
+        let render = render.clone();
+        let log = log.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |request| {
+                let render = render.clone();
+                async move { Ok::<_, Infallible>(respond(request, render)) }
+            });
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                log.warn(format_args!("Metrics connection from {addr} failed: {e}"));
+            }
+        });
+    }
+}
+
+fn respond(request: Request<Incoming>, render: impl Fn() -> String) -> Response<Full<Bytes>> {
+    let status = if request.uri().path() != PATH {
+        StatusCode::NOT_FOUND
+    } else if request.method() != Method::GET {
+        StatusCode::METHOD_NOT_ALLOWED
+    } else {
+        return Response::builder()
+            .header(CONTENT_TYPE, EXPOSITION_FORMAT)
+            .body(Full::new(Bytes::from(render())))
+            .unwrap();
+    };
+
+    Response::builder()
+        .status(status)
+        .body(Full::new(Bytes::new()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(metrics: &Metrics, upstreams: Vec<(SocketAddr, HistogramSnapshot)>) -> String {
+        let cache = CacheStats {
+            hits: 5,
+            negative_hits: 1,
+            stale_hits: 0,
+            misses: 2,
+            prefetches: 0,
+        };
+        metrics.render(3, cache, upstreams, Some(4))
+    }
+
+    #[test]
+    fn counters_are_rendered_with_their_labels() {
+        let metrics = Metrics::default();
+        metrics.record_query(ClientTransport::Udp, Some(Type::A), Some(0));
+        metrics.record_query(ClientTransport::Udp, Some(Type::A), Some(0));
+        metrics.record_query(ClientTransport::Tls, Some(Type::AAAA), Some(3));
+        metrics.record_query(ClientTransport::Udp, None, None);
+        metrics.record_parse_failure(&DnsError::InvalidClass);
+
+        let out = render(&metrics, vec![]);
+        let expected = [
+            "# HELP dns_queries_total Queries received.",
+            "# TYPE dns_queries_total counter",
+            "dns_queries_total{transport=\"udp\",qtype=\"none\",rcode=\"none\"} 1",
+            "dns_queries_total{transport=\"udp\",qtype=\"A\",rcode=\"NOERROR\"} 2",
+            "dns_queries_total{transport=\"tls\",qtype=\"AAAA\",rcode=\"NXDOMAIN\"} 1",
+            "# TYPE dns_query_parse_failures_total counter",
+            "dns_query_parse_failures_total{error=\"invalid_class\"} 1",
+            "# TYPE dns_queries_in_flight gauge",
+            "dns_queries_in_flight 3",
+            "dns_cache_hits_total 5",
+            "dns_cache_misses_total 2",
+            "dns_dnstap_dropped_total 4",
+        ];
+        let mut rest = out.as_str();
+        for line in expected {
+            let at = rest
+                .find(&format!("{line}\n"))
+                .unwrap_or_else(|| panic!("{line:?} missing or out of order in:\n{out}"));
+            rest = &rest[at + line.len()..];
+        }
+    }
+
+    #[test]
+    fn histograms_have_cumulative_buckets_a_sum_and_a_count() {
+        let address: SocketAddr = "192.0.2.1:53".parse().unwrap();
+        let latency = Histogram::default();
+        latency.observe(Duration::from_micros(500));
+        latency.observe(Duration::from_millis(20));
+        latency.observe(Duration::from_millis(30));
+        latency.observe(Duration::from_secs(10));
+        let other = Histogram::default();
+        other.observe(Duration::from_millis(3));
+
+        // The same upstream in two zones is added up
+        let out = render(
+            &Metrics::default(),
+            vec![(address, latency.snapshot()), (address, other.snapshot())],
+        );
+        let lines: Vec<_> = out
+            .lines()
+            .filter(|line| line.starts_with("dns_upstream_latency_seconds"))
+            .collect();
+        let bucket = |le, count| {
+            format!(
+                "dns_upstream_latency_seconds_bucket{{resolver=\"{address}\",le=\"{le}\"}} {count}"
+            )
+        };
+        assert_eq!(lines.len(), LATENCY_BUCKETS.len() + 3);
+        assert_eq!(lines[0], bucket("0.001", 1));
+        assert_eq!(lines[1], bucket("0.0025", 1));
+        assert_eq!(lines[2], bucket("0.005", 2));
+        assert_eq!(lines[4], bucket("0.025", 3));
+        assert_eq!(lines[5], bucket("0.05", 4));
+        assert_eq!(lines[11], bucket("5", 4));
+        assert_eq!(lines[12], bucket("+Inf", 5));
+        assert_eq!(
+            lines[13],
+            format!("dns_upstream_latency_seconds_sum{{resolver=\"{address}\"}} 10.0535")
+        );
+        assert_eq!(
+            lines[14],
+            format!("dns_upstream_latency_seconds_count{{resolver=\"{address}\"}} 5")
+        );
+        assert!(out.contains("# TYPE dns_upstream_latency_seconds histogram\n"));
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        let escaped = LabelValue("C:\\dir \"quoted\"\nnext").to_string();
+        assert_eq!(escaped, "C:\\\\dir \\\"quoted\\\"\\nnext");
+        assert_eq!(LabelValue("TYPE65280").to_string(), "TYPE65280");
+    }
+}
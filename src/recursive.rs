@@ -0,0 +1,844 @@
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::time;
+
+use crate::error::BoxError;
+use crate::log::Logger;
+use crate::message::{
+    Class, DnsHeader, DnsMessage, DnsQuestion, Name, Opcode, RCODE_NAME_ERROR, RCODE_NO_ERROR,
+    RData, ResourceRecord, Type,
+};
+use crate::upstream::{Strategy, UpstreamSet};
+
+/// Port the default root servers are queried on.
+const DNS_PORT: u16 = 53;
+
+/// Addresses of a.root-servers.net through m.root-servers.net, used when no root hints
+/// are given.
+const DEFAULT_ROOT_HINTS: [Ipv4Addr; 13] = [
+    Ipv4Addr::new(198, 41, 0, 4),
+    Ipv4Addr::new(170, 247, 170, 2),
+    Ipv4Addr::new(192, 33, 4, 12),
+    Ipv4Addr::new(199, 7, 91, 13),
+    Ipv4Addr::new(192, 203, 230, 10),
+    Ipv4Addr::new(192, 5, 5, 241),
+    Ipv4Addr::new(192, 112, 36, 4),
+    Ipv4Addr::new(198, 97, 190, 53),
+    Ipv4Addr::new(192, 36, 148, 17),
+    Ipv4Addr::new(192, 58, 128, 30),
+    Ipv4Addr::new(193, 0, 14, 129),
+    Ipv4Addr::new(199, 7, 83, 42),
+    Ipv4Addr::new(202, 12, 27, 33),
+];
+
+/// Referrals followed while resolving a single name.
+const MAX_REFERRALS: usize = 16;
+
+/// Minimised queries sent while resolving a single name, after which the full name is
+/// sent, so that names with many labels do not take a query per label (RFC 9156).
+const MAX_MINIMISED_QUERIES: usize = 10;
+
+/// CNAME records followed from the name in the question.
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// How deep lookups of name server addresses may nest, for delegations without glue.
+const MAX_NESTED_LOOKUPS: usize = 4;
+
+/// Queries sent to name servers on behalf of a single question, including those needed
+/// to find the addresses of name servers.
+const MAX_QUERIES: u32 = 64;
+
+/// Overall time allowed for resolving a single question, across all the queries it takes.
+const RESOLUTION_DEADLINE: Duration = Duration::from_secs(10);
+
+/// Number of zones whose name servers are remembered.
+const MAX_DELEGATIONS: usize = 10_000;
+
+/// Upper bound on how long the name servers of a zone are remembered.
+const MAX_DELEGATION_TTL: u32 = 24 * 60 * 60;
+
+pub fn default_root_hints() -> Vec<SocketAddr> {
+    DEFAULT_ROOT_HINTS
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip.into(), DNS_PORT))
+        .collect()
+}
+
+#[derive(Debug)]
+struct Delegation {
+    servers: Arc<UpstreamSet>,
+    expires: Instant,
+}
+
+/// The work left for resolving a single question.
+#[derive(Debug)]
+struct Budget {
+    queries_left: u32,
+}
+
+impl Budget {
+    fn spend(&mut self) -> Result<(), BoxError> {
+        if self.queries_left == 0 {
+            return Err("too many queries needed to resolve question".into());
+        }
+        self.queries_left -= 1;
+
+        Ok(())
+    }
+}
+
+/// Resolves questions iteratively, starting at the root servers and following referrals
+/// down to the name servers of the zone that holds the answer.
+#[derive(Debug)]
+pub struct Resolver {
+    roots: Arc<UpstreamSet>,
+    /// Port that name servers learned from referrals are queried on. Only ever other than
+    /// 53 for a hierarchy of test servers.
+    port: u16,
+    /// Only send name servers as much of the name as they need to see (RFC 9156).
+    qname_minimisation: bool,
+    /// Name servers of the zones seen in referrals, keyed by lowercased zone name.
+    delegations: Mutex<HashMap<Name<'static>, Delegation>>,
+    log: Arc<Logger>,
+}
+
+impl Resolver {
+    pub fn new(root_hints: Vec<SocketAddr>, qname_minimisation: bool, log: Arc<Logger>) -> Self {
+        Self {
+            port: DNS_PORT,
+            roots: Arc::new(UpstreamSet::new(root_hints, Strategy::LowestRtt, &log)),
+            qname_minimisation,
+            delegations: Mutex::new(HashMap::new()),
+            log,
+        }
+    }
+
+    /// Queries name servers learned from referrals on `port` instead of 53.
+    #[cfg(test)]
+    fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Resolves `question`, following CNAME chains across zones, and returns the answer in
+    /// the form of a reply.
+    pub async fn resolve(
+        &self,
+        question: &DnsQuestion<'_>,
+    ) -> Result<DnsMessage<'static>, BoxError> {
+        let mut budget = Budget {
+            queries_left: MAX_QUERIES,
+        };
+        let lookup = self.lookup(question.clone().into_owned(), &mut budget, 0);
+        time::timeout(RESOLUTION_DEADLINE, lookup)
+            .await
+            .map_err(|_| format!("resolving {} took too long", question.name))?
+    }
+
+    async fn lookup(
+        &self,
+        question: DnsQuestion<'static>,
+        budget: &mut Budget,
+        depth: usize,
+    ) -> Result<DnsMessage<'static>, BoxError> {
+        if depth > MAX_NESTED_LOOKUPS {
+            return Err("name server lookups nested too deeply".into());
+        }
+
+        let mut records = Vec::new();
+        let mut cnames = 0;
+        let mut name = question.name.clone();
+        loop {
+            let queried = name.clone();
+            let (reply, zone) = self
+                .iterate(&queried, question.qtype, question.class, budget, depth)
+                .await?;
+
+            // Follow the CNAME chain for as long as the reply has the records for it. Records
+            // for names outside the zone that answered are not trusted, as its servers have
+            // no authority over them, so such names are looked up afresh.
+            while name.is_subdomain_of(&zone) {
+                let owned_by =
+                    |record: &&ResourceRecord<'static>| record.name.eq_ignore_ascii_case(&name);
+                let answers: Vec<_> = reply
+                    .records
+                    .iter()
+                    .filter(owned_by)
+                    .filter(|record| {
+                        question.qtype == Type::Wildcard || record.atype == question.qtype
+                    })
+                    .cloned()
+                    .collect();
+                if !answers.is_empty() {
+                    records.extend(answers);
+                    return Ok(answer(&question, RCODE_NO_ERROR, records, vec![]));
+                }
+
+                let cname = reply
+                    .records
+                    .iter()
+                    .filter(owned_by)
+                    .find_map(|record| match &record.rdata {
+                        RData::CNAME { cname } => Some((record.clone(), cname.clone())),
+                        _ => None,
+                    });
+                let Some((record, target)) = cname else {
+                    break;
+                };
+                cnames += 1;
+                if cnames > MAX_CNAME_CHAIN {
+                    return Err(format!("CNAME chain from {} is too long", question.name).into());
+                }
+                records.push(record);
+                name = target;
+            }
+
+            if name.eq_ignore_ascii_case(&queried) {
+                let response_code = reply.header.response_code;
+                return Ok(answer(
+                    &question,
+                    response_code,
+                    records,
+                    reply.authority_records,
+                ));
+            }
+            // The chain continues in another zone
+        }
+    }
+
+    /// Asks name servers about `name` until one answers it, starting from the closest
+    /// zone with known name servers and following referrals.
+    ///
+    /// With QNAME minimisation, each zone's name servers are only told about the name one
+    /// label below the zone, until the name servers of the zone holding `name` are found.
+    ///
+    /// Returns the reply along with the zone whose name servers gave it.
+    async fn iterate(
+        &self,
+        name: &Name<'static>,
+        qtype: Type,
+        class: Class,
+        budget: &mut Budget,
+        depth: usize,
+    ) -> Result<(DnsMessage<'static>, Name<'static>), BoxError> {
+        let (mut zone, mut servers) = self.closest_delegation(name);
+        let mut referrals = 0;
+        let mut minimised_queries = 0;
+        // Number of trailing labels of `name` sent in the next minimised query
+        let mut minimised_len = zone.labels.len() + 1;
+        loop {
+            let minimise = self.qname_minimisation
+                && minimised_len < name.labels.len()
+                && minimised_queries < MAX_MINIMISED_QUERIES;
+            let question = match minimise {
+                true => DnsQuestion {
+                    name: Name {
+                        labels: name.labels[name.labels.len() - minimised_len..].to_vec(),
+                    },
+                    qtype: Type::A,
+                    class,
+                },
+                false => DnsQuestion {
+                    name: name.clone(),
+                    qtype,
+                    class,
+                },
+            };
+
+            budget.spend()?;
+            let (reply, _) = servers.query(&query(question)).await?;
+            if minimise {
+                minimised_queries += 1;
+            }
+            match reply.header.response_code {
+                RCODE_NO_ERROR if minimise => {}
+                // Some servers deny that empty non-terminals exist, so fall back to asking
+                // about the full name
+                _ if minimise => {
+                    minimised_len = name.labels.len();
+                    continue;
+                }
+                RCODE_NAME_ERROR => return Ok((reply, zone)),
+                RCODE_NO_ERROR if !reply.records.is_empty() => return Ok((reply, zone)),
+                RCODE_NO_ERROR => {}
+                response_code => {
+                    return Err(format!(
+                        "name servers for {zone} failed with response code {response_code}"
+                    )
+                    .into());
+                }
+            }
+
+            let Some((child, ns_names, ttl)) = referral(&reply, name, &zone) else {
+                // No zone starts at the minimised name, so go a label further down
+                if minimise {
+                    minimised_len += 1;
+                    continue;
+                }
+                // An authoritative reply without records means the name has none of the type
+                if reply.header.authoritative_answer {
+                    return Ok((reply, zone));
+                }
+                return Err(format!("lame delegation for {zone}").into());
+            };
+
+            referrals += 1;
+            if referrals > MAX_REFERRALS {
+                return Err(format!("too many referrals resolving {name}").into());
+            }
+
+            let mut addrs = glue(&reply, &ns_names, &zone, self.port);
+            if addrs.is_empty() {
+                addrs = self
+                    .resolve_name_servers(&child, &ns_names, budget, depth)
+                    .await?;
+            }
+
+            servers = Arc::new(UpstreamSet::new(addrs, Strategy::LowestRtt, &self.log));
+            self.remember_delegation(&child, servers.clone(), ttl);
+            minimised_len = child.labels.len() + 1;
+            zone = child;
+        }
+    }
+
+    /// Looks up the addresses of the name servers of `zone`, for referrals that came
+    /// without glue.
+    async fn resolve_name_servers(
+        &self,
+        zone: &Name<'static>,
+        ns_names: &[Name<'static>],
+        budget: &mut Budget,
+        depth: usize,
+    ) -> Result<Vec<SocketAddr>, BoxError> {
+        for ns_name in ns_names {
+            let question = DnsQuestion {
+                name: ns_name.clone(),
+                qtype: Type::A,
+                class: Class::IN,
+            };
+            match Box::pin(self.lookup(question, budget, depth + 1)).await {
+                Ok(reply) => {
+                    let addrs = addresses(reply.records.iter(), self.port);
+                    if !addrs.is_empty() {
+                        return Ok(addrs);
+                    }
+                }
+                Err(e) => self
+                    .log
+                    .warn(format_args!("Failed resolving name server {ns_name}: {e}")),
+            }
+        }
+
+        Err(format!("no addresses found for any name server of {zone}").into())
+    }
+
+    /// The deepest zone above `name` with name servers that are still remembered, or the
+    /// root.
+    fn closest_delegation(&self, name: &Name<'_>) -> (Name<'static>, Arc<UpstreamSet>) {
+        let now = Instant::now();
+        let name = name.to_ascii_lowercase();
+
+        let mut delegations = self.delegations.lock().unwrap();
+        for start in 0..name.labels.len() {
+            let zone = Name {
+                labels: name.labels[start..].to_vec(),
+            };
+            match delegations.get(&zone) {
+                Some(delegation) if delegation.expires > now => {
+                    return (zone, delegation.servers.clone());
+                }
+                Some(_) => {
+                    delegations.remove(&zone);
+                }
+                None => {}
+            }
+        }
+
+        (Name { labels: vec![] }, self.roots.clone())
+    }
+
+    fn remember_delegation(&self, zone: &Name<'_>, servers: Arc<UpstreamSet>, ttl: u32) {
+        let now = Instant::now();
+        let ttl = Duration::from_secs(ttl.min(MAX_DELEGATION_TTL) as u64);
+
+        let mut delegations = self.delegations.lock().unwrap();
+        if delegations.len() >= MAX_DELEGATIONS {
+            delegations.retain(|_, delegation| delegation.expires > now);
+        }
+        if delegations.len() < MAX_DELEGATIONS {
+            delegations.insert(
+                zone.to_ascii_lowercase(),
+                Delegation {
+                    servers,
+                    expires: now + ttl,
+                },
+            );
+        }
+    }
+}
+
+/// Builds a non-recursive query for `question`.
+fn query(question: DnsQuestion<'static>) -> DnsMessage<'static> {
+    DnsMessage {
+        header: DnsHeader {
+            id: 0,
+            qr_indicator: false,
+            opcode: Opcode::StandardQuery,
+            authoritative_answer: false,
+            truncation: false,
+            recursion_desired: false,
+            recursion_available: false,
+            reserved: 0,
+            response_code: 0,
+            question_count: 1,
+            answer_record_count: 0,
+            authority_record_count: 0,
+            additional_record_count: 0,
+        },
+        questions: vec![question],
+        records: vec![],
+        authority_records: vec![],
+        additional_records: vec![],
+        edns: None,
+    }
+}
+
+/// Builds the reply to `question` from the records gathered while resolving it.
+fn answer(
+    question: &DnsQuestion<'static>,
+    response_code: u8,
+    records: Vec<ResourceRecord<'static>>,
+    authority_records: Vec<ResourceRecord<'static>>,
+) -> DnsMessage<'static> {
+    DnsMessage {
+        header: DnsHeader {
+            id: 0,
+            qr_indicator: true,
+            opcode: Opcode::StandardQuery,
+            authoritative_answer: false,
+            truncation: false,
+            recursion_desired: true,
+            recursion_available: true,
+            reserved: 0,
+            response_code,
+            question_count: 1,
+            answer_record_count: records.len() as u16,
+            authority_record_count: authority_records.len() as u16,
+            additional_record_count: 0,
+        },
+        questions: vec![question.clone()],
+        records,
+        authority_records,
+        additional_records: vec![],
+        edns: None,
+    }
+}
+
+/// Finds a referral to a zone between `zone` and `name` in the Authority section of
+/// `reply`, and returns the zone along with the names and TTL of its name servers.
+fn referral(
+    reply: &DnsMessage<'static>,
+    name: &Name<'_>,
+    zone: &Name<'_>,
+) -> Option<(Name<'static>, Vec<Name<'static>>, u32)> {
+    // Only a zone below the one asked can be delegated to, which rules out loops
+    let child = reply
+        .authority_records
+        .iter()
+        .filter(|record| record.atype == Type::NS)
+        .map(|record| &record.name)
+        .find(|owner| {
+            name.is_subdomain_of(owner)
+                && owner.is_subdomain_of(zone)
+                && owner.labels.len() > zone.labels.len()
+        })?;
+
+    let mut ttl = u32::MAX;
+    let mut ns_names = Vec::new();
+    for record in &reply.authority_records {
+        if let RData::NS { nsdname } = &record.rdata {
+            if record.name.eq_ignore_ascii_case(child) {
+                ttl = ttl.min(record.ttl);
+                ns_names.push(nsdname.clone());
+            }
+        }
+    }
+
+    Some((child.clone(), ns_names, ttl))
+}
+
+/// The addresses of `ns_names` given in the Additional section of a referral from `zone`.
+/// Addresses for names outside of `zone` are not trusted, as the servers of `zone` have no
+/// authority over them.
+fn glue(
+    reply: &DnsMessage<'static>,
+    ns_names: &[Name<'static>],
+    zone: &Name<'_>,
+    port: u16,
+) -> Vec<SocketAddr> {
+    let records = reply.additional_records.iter().filter(|record| {
+        record.name.is_subdomain_of(zone)
+            && ns_names
+                .iter()
+                .any(|ns_name| record.name.eq_ignore_ascii_case(ns_name))
+    });
+    addresses(records, port)
+}
+
+/// The IPv4 and IPv6 addresses in `records`, as addresses of name servers listening on
+/// `port`.
+fn addresses<'a>(
+    records: impl Iterator<Item = &'a ResourceRecord<'static>>,
+    port: u16,
+) -> Vec<SocketAddr> {
+    records
+        .filter_map(|record| match record.rdata {
+            RData::A { address } => Some(SocketAddr::new(Ipv4Addr::from(address).into(), port)),
+            RData::AAAA { address } => Some(SocketAddr::new(Ipv6Addr::from(address).into(), port)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use tokio::net::UdpSocket;
+
+    use super::*;
+    use crate::message::ByteSerialize;
+    use crate::testing;
+
+    fn name(name: &str) -> Name<'static> {
+        name.parse().unwrap()
+    }
+
+    fn record(owner: &str, atype: Type, rdata: RData<'static>) -> ResourceRecord<'static> {
+        ResourceRecord {
+            name: name(owner),
+            atype,
+            class: Class::IN,
+            ttl: 300,
+            rdata,
+        }
+    }
+
+    fn a(owner: &str, address: [u8; 4]) -> ResourceRecord<'static> {
+        let address = u32::from_be_bytes(address);
+        record(owner, Type::A, RData::A { address })
+    }
+
+    fn ns(zone: &str, server: &str) -> ResourceRecord<'static> {
+        let nsdname = name(server);
+        record(zone, Type::NS, RData::NS { nsdname })
+    }
+
+    fn aaaa(owner: &str, address: Ipv6Addr) -> ResourceRecord<'static> {
+        let address = u128::from(address);
+        record(owner, Type::AAAA, RData::AAAA { address })
+    }
+
+    fn cname(owner: &str, target: &str) -> ResourceRecord<'static> {
+        let cname = name(target);
+        record(owner, Type::CNAME, RData::CNAME { cname })
+    }
+
+    /// The questions the name servers of a hierarchy were asked, as `<zone> <name> <type>`,
+    /// in the order they were asked.
+    type Queries = Arc<Mutex<Vec<String>>>;
+
+    /// Serves `records` authoritatively for `zone` on `address`, adding the questions it is
+    /// asked to `queries`. Questions below a zone delegated with an NS record get a
+    /// referral, with glue, and the others an answer, along with the records for any CNAME
+    /// target, wherever it is.
+    ///
+    /// With `deny_empty_non_terminals`, names without records of their own get NXDOMAIN,
+    /// even when there are names below them, as some name servers wrongly do.
+    async fn authority(
+        address: SocketAddr,
+        zone: &str,
+        records: Vec<ResourceRecord<'static>>,
+        queries: &Queries,
+        deny_empty_non_terminals: bool,
+    ) {
+        let zone = name(zone);
+        let socket = UdpSocket::bind(address).await.unwrap();
+        let queries = queries.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0; 512];
+            loop {
+                let (len, client) = socket.recv_from(&mut buf).await.unwrap();
+                let query = DnsMessage::try_parse(&mut Cursor::new(&buf[..len])).unwrap();
+                let question = &query.questions[0];
+                queries.lock().unwrap().push(format!(
+                    "{zone} {} {}",
+                    question.name.to_ascii_lowercase(),
+                    question.qtype
+                ));
+
+                let delegated: Vec<_> = records
+                    .iter()
+                    .filter(|record| {
+                        record.atype == Type::NS
+                            && !record.name.eq_ignore_ascii_case(&zone)
+                            && question.name.is_subdomain_of(&record.name)
+                    })
+                    .cloned()
+                    .collect();
+                let mut reply = answer(
+                    &question.clone().into_owned(),
+                    RCODE_NO_ERROR,
+                    vec![],
+                    vec![],
+                );
+                reply.header.id = query.header.id;
+                reply.header.recursion_available = false;
+                if delegated.is_empty() {
+                    let mut owner = question.name.clone().into_owned();
+                    while let Some(record) = records.iter().find(|record| {
+                        record.name.eq_ignore_ascii_case(&owner) && record.atype == Type::CNAME
+                    }) {
+                        reply.records.push(record.clone());
+                        let RData::CNAME { cname } = &record.rdata else {
+                            unreachable!()
+                        };
+                        owner = cname.clone();
+                    }
+                    reply.records.extend(
+                        records
+                            .iter()
+                            .filter(|record| {
+                                record.name.eq_ignore_ascii_case(&owner)
+                                    && record.atype == question.qtype
+                            })
+                            .cloned(),
+                    );
+                    reply.header.authoritative_answer = true;
+                    let exists = records
+                        .iter()
+                        .any(|record| record.name.eq_ignore_ascii_case(&question.name));
+                    if deny_empty_non_terminals && !exists {
+                        reply.header.response_code = RCODE_NAME_ERROR;
+                    }
+                } else {
+                    reply.additional_records = records
+                        .iter()
+                        .filter(|record| {
+                            matches!(record.atype, Type::A | Type::AAAA)
+                                && delegated.iter().any(|ns| {
+                                    matches!(&ns.rdata, RData::NS { nsdname } if nsdname.eq_ignore_ascii_case(&record.name))
+                                })
+                        })
+                        .cloned()
+                        .collect();
+                    reply.authority_records = delegated;
+                }
+                reply.header.answer_record_count = reply.records.len() as u16;
+                reply.header.authority_record_count = reply.authority_records.len() as u16;
+                reply.header.additional_record_count = reply.additional_records.len() as u16;
+
+                let mut wire = Vec::new();
+                reply.serialize(&mut wire).unwrap();
+                socket.send_to(&wire, client).await.unwrap();
+            }
+        });
+    }
+
+    /// Starts a root, a `test.` TLD, and the zones below it on loopback addresses sharing
+    /// a port, and returns a resolver pointed at the root, along with the questions the
+    /// name servers get asked.
+    async fn hierarchy(qname_minimisation: bool) -> (Resolver, Queries) {
+        // Any free port will do, as long as the other servers can have it too
+        let root = UdpSocket::bind("127.0.0.2:0").await.unwrap();
+        let port = root.local_addr().unwrap().port();
+        drop(root);
+        let address = |last: u8| SocketAddr::from(([127, 0, 0, last], port));
+        let queries = Queries::default();
+
+        authority(
+            address(2),
+            ".",
+            vec![ns("test.", "ns.test."), a("ns.test.", [127, 0, 0, 3])],
+            &queries,
+            false,
+        )
+        .await;
+        authority(
+            address(3),
+            "test.",
+            vec![
+                ns("example.test.", "ns.example.test."),
+                a("ns.example.test.", [127, 0, 0, 4]),
+                ns("victim.test.", "ns.victim.test."),
+                a("ns.victim.test.", [127, 0, 0, 5]),
+                ns("v6.test.", "ns.v6.test."),
+                aaaa("ns.v6.test.", Ipv6Addr::LOCALHOST),
+                ns("broken.test.", "ns.broken.test."),
+                a("ns.broken.test.", [127, 0, 0, 6]),
+            ],
+            &queries,
+            false,
+        )
+        .await;
+        authority(
+            address(4),
+            "example.test.",
+            vec![
+                a("www.example.test.", [192, 0, 2, 1]),
+                a("deep.below.example.test.", [192, 0, 2, 2]),
+                cname("alias.example.test.", "www.victim.test."),
+                // Not for this zone to say, and must not be believed
+                a("www.victim.test.", [203, 0, 113, 66]),
+            ],
+            &queries,
+            false,
+        )
+        .await;
+        authority(
+            address(5),
+            "victim.test.",
+            vec![a("www.victim.test.", [192, 0, 2, 99])],
+            &queries,
+            false,
+        )
+        .await;
+        authority(
+            SocketAddr::from((Ipv6Addr::LOCALHOST, port)),
+            "v6.test.",
+            vec![a("www.v6.test.", [192, 0, 2, 6])],
+            &queries,
+            false,
+        )
+        .await;
+        authority(
+            address(6),
+            "broken.test.",
+            vec![a("www.deep.below.broken.test.", [192, 0, 2, 7])],
+            &queries,
+            true,
+        )
+        .await;
+
+        let resolver =
+            Resolver::new(vec![address(2)], qname_minimisation, testing::logger()).with_port(port);
+        (resolver, queries)
+    }
+
+    fn addresses_of(reply: &DnsMessage<'_>) -> Vec<Ipv4Addr> {
+        reply
+            .records
+            .iter()
+            .filter_map(|record| match record.rdata {
+                RData::A { address } => Some(Ipv4Addr::from(address)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn question(qname: &str) -> DnsQuestion<'static> {
+        DnsQuestion {
+            name: name(qname),
+            qtype: Type::A,
+            class: Class::IN,
+        }
+    }
+
+    #[tokio::test]
+    async fn follows_referrals_from_the_root() {
+        for qname_minimisation in [true, false] {
+            let (resolver, _) = hierarchy(qname_minimisation).await;
+            let reply = resolver
+                .resolve(&question("www.example.test."))
+                .await
+                .unwrap();
+            assert_eq!(reply.header.response_code, RCODE_NO_ERROR);
+            assert_eq!(addresses_of(&reply), [Ipv4Addr::new(192, 0, 2, 1)]);
+        }
+    }
+
+    #[tokio::test]
+    async fn cname_targets_outside_the_zone_are_resolved_afresh() {
+        let (resolver, _) = hierarchy(true).await;
+        let reply = resolver
+            .resolve(&question("alias.example.test."))
+            .await
+            .unwrap();
+        assert_eq!(reply.records[0].atype, Type::CNAME);
+        assert_eq!(addresses_of(&reply), [Ipv4Addr::new(192, 0, 2, 99)]);
+    }
+
+    #[tokio::test]
+    async fn name_servers_with_only_ipv6_glue_are_reached() {
+        let (resolver, _) = hierarchy(true).await;
+        let reply = resolver.resolve(&question("www.v6.test.")).await.unwrap();
+        assert_eq!(addresses_of(&reply), [Ipv4Addr::new(192, 0, 2, 6)]);
+    }
+
+    #[test]
+    fn referrals_are_followed_on_port_53_whatever_the_root_hints_port() {
+        let resolver = Resolver::new(
+            vec!["127.0.0.1:5300".parse().unwrap()],
+            true,
+            testing::logger(),
+        );
+        assert_eq!(resolver.port, DNS_PORT);
+    }
+
+    /// Resolves `qname` and returns the questions the name servers were asked for it.
+    async fn questions_asked(
+        qname_minimisation: bool,
+        qname: &str,
+        address: [u8; 4],
+    ) -> Vec<String> {
+        let (resolver, queries) = hierarchy(qname_minimisation).await;
+        let reply = resolver.resolve(&question(qname)).await.unwrap();
+        assert_eq!(addresses_of(&reply), [Ipv4Addr::from(address)]);
+        queries.lock().unwrap().clone()
+    }
+
+    #[tokio::test]
+    async fn minimised_names_go_down_a_label_at_a_time() {
+        let asked = questions_asked(true, "deep.below.example.test.", [192, 0, 2, 2]).await;
+        assert_eq!(
+            asked,
+            [
+                ". test. A",
+                "test. example.test. A",
+                // An empty non-terminal, which has no zone of its own
+                "example.test. below.example.test. A",
+                "example.test. deep.below.example.test. A",
+            ]
+        );
+
+        let asked = questions_asked(false, "deep.below.example.test.", [192, 0, 2, 2]).await;
+        assert_eq!(
+            asked,
+            [
+                ". deep.below.example.test. A",
+                "test. deep.below.example.test. A",
+                "example.test. deep.below.example.test. A",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn denied_empty_non_terminals_fall_back_to_the_full_name() {
+        let asked = questions_asked(true, "www.deep.below.broken.test.", [192, 0, 2, 7]).await;
+        assert_eq!(
+            asked,
+            [
+                ". test. A",
+                "test. broken.test. A",
+                "broken.test. below.broken.test. A",
+                // Not deep.below.broken.test., which would be denied too
+                "broken.test. www.deep.below.broken.test. A",
+            ]
+        );
+    }
+}
@@ -0,0 +1,105 @@
+use std::{net::SocketAddr, time::Duration};
+
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time,
+};
+
+use crate::log::Logger;
+use crate::message::MAX_MESSAGE_SIZE;
+
+/// Largest DNS message with its length prefix, as sent over a stream.
+pub const MAX_FRAMED_SIZE: usize = 2 + MAX_MESSAGE_SIZE;
+
+/// How long to wait before accepting connections again after failing to, so that running
+/// out of file descriptors does not spin the accept loop.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Accepts the next connection on `listener`, logging failures to accept `what`
+/// connections and backing off after each.
+pub async fn accept(listener: &TcpListener, what: &str, log: &Logger) -> (TcpStream, SocketAddr) {
+    loop {
+        match listener.accept().await {
+            Ok(accepted) => return accepted,
+            Err(e) => {
+                log.error(format_args!("Error accepting {what} connection: {e}"));
+                time::sleep(ACCEPT_ERROR_BACKOFF).await;
+            }
+        }
+    }
+}
+
+/// `message` with the two byte length prefix it is sent with over streams (RFC 1035
+/// section 4.2.2).
+pub fn frame(message: &[u8]) -> io::Result<Vec<u8>> {
+    let len = u16::try_from(message.len()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "message too large for a stream",
+        )
+    })?;
+    let mut framed = Vec::with_capacity(message.len() + 2);
+    framed.extend_from_slice(&len.to_be_bytes());
+    framed.extend_from_slice(message);
+
+    Ok(framed)
+}
+
+/// Strips the length prefix from `data`, which holds a single framed message, checking
+/// that it matches the length of the message.
+pub fn unframe(data: &[u8]) -> Option<&[u8]> {
+    let (len, message) = data.split_at_checked(2)?;
+    if usize::from(u16::from_be_bytes([len[0], len[1]])) != message.len() {
+        return None;
+    }
+
+    Some(message)
+}
+
+/// Reads a message framed with a two byte length prefix from `reader`.
+pub async fn read_framed<R: AsyncReadExt + Unpin>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let len = reader.read_u16().await?;
+    let mut message = vec![0; len as usize];
+    reader.read_exact(&mut message).await?;
+
+    Ok(message)
+}
+
+/// Writes `message` to `writer`, framed with a two byte length prefix, and flushes it.
+pub async fn write_framed<W: AsyncWriteExt + Unpin>(
+    writer: &mut W,
+    message: &[u8],
+) -> io::Result<()> {
+    writer.write_all(&frame(message)?).await?;
+    writer.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn framing_round_trips() {
+        let framed = frame(b"message").unwrap();
+        assert_eq!(framed[..2], [0, 7]);
+        assert_eq!(unframe(&framed), Some(&b"message"[..]));
+
+        assert_eq!(unframe(&framed[..framed.len() - 1]), None);
+        assert_eq!(unframe(&[0]), None);
+        assert!(frame(&vec![0; MAX_MESSAGE_SIZE + 1]).is_err());
+    }
+
+    #[tokio::test]
+    async fn framed_messages_are_read_one_at_a_time() {
+        let (mut client, mut server) = io::duplex(64);
+        write_framed(&mut client, b"first").await.unwrap();
+        write_framed(&mut client, b"second").await.unwrap();
+        drop(client);
+
+        assert_eq!(read_framed(&mut server).await.unwrap(), b"first");
+        assert_eq!(read_framed(&mut server).await.unwrap(), b"second");
+        let end = read_framed(&mut server).await.unwrap_err();
+        assert_eq!(end.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,256 @@
+//! Helpers shared by tests.
+
+use std::{
+    fs,
+    future::Future,
+    io::Cursor,
+    net::SocketAddr,
+    path::PathBuf,
+    process,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    task::JoinHandle,
+    time,
+};
+use tokio_rustls::rustls::{ClientConfig, ServerConfig, pki_types::ServerName};
+
+use crate::config::Config;
+use crate::log::{Level, Logger};
+use crate::message::{
+    ByteSerialize, Class, DnsHeader, DnsMessage, DnsQuestion, MAX_MESSAGE_SIZE, Name, Opcode,
+    RData, ResourceRecord, Type,
+};
+use crate::tls;
+
+/// A self-signed certificate for `localhost`, written out as PEM files that are removed
+/// when it is dropped.
+pub struct TestCertificate {
+    dir: PathBuf,
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+impl TestCertificate {
+    pub fn new() -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "dns-server-test-{}-{}",
+            process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&dir).unwrap();
+
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        fs::write(&cert_path, certified.cert.pem()).unwrap();
+        fs::write(&key_path, certified.signing_key.serialize_pem()).unwrap();
+
+        Self {
+            cert_path: cert_path.to_string_lossy().into_owned(),
+            key_path: key_path.to_string_lossy().into_owned(),
+            dir,
+        }
+    }
+
+    /// The name the certificate is for.
+    pub fn server_name(&self) -> ServerName<'static> {
+        ServerName::try_from("localhost").unwrap()
+    }
+
+    /// TLS configuration for serving with the certificate.
+    pub fn server_config(&self) -> Arc<ServerConfig> {
+        tls::server_config(&self.cert_path, &self.key_path).unwrap()
+    }
+
+    /// TLS configuration for connecting to servers that present the certificate.
+    pub fn client_config(&self) -> Arc<ClientConfig> {
+        tls::client_config(Some(&self.cert_path)).unwrap()
+    }
+}
+
+impl Drop for TestCertificate {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// A query for the A records of `name`, in wire form.
+pub fn query(id: u16, name: &str) -> Vec<u8> {
+    let message = DnsMessage {
+        header: DnsHeader {
+            id,
+            qr_indicator: false,
+            opcode: Opcode::StandardQuery,
+            authoritative_answer: false,
+            truncation: false,
+            recursion_desired: true,
+            recursion_available: false,
+            reserved: 0,
+            response_code: 0,
+            question_count: 1,
+            answer_record_count: 0,
+            authority_record_count: 0,
+            additional_record_count: 0,
+        },
+        questions: vec![DnsQuestion {
+            name: name.parse::<Name<'static>>().unwrap(),
+            qtype: Type::A,
+            class: Class::IN,
+        }],
+        records: vec![],
+        authority_records: vec![],
+        additional_records: vec![],
+        edns: None,
+    };
+
+    let mut buf = Vec::new();
+    message.serialize(&mut buf).unwrap();
+    buf
+}
+
+/// Answers `query` with a single A record holding `address`.
+pub fn reply(query: &[u8], address: [u8; 4]) -> Vec<u8> {
+    reply_with_ttl(query, address, 60)
+}
+
+/// Answers `query` with a single A record holding `address`, to be cached for `ttl`
+/// seconds.
+pub fn reply_with_ttl(query: &[u8], address: [u8; 4], ttl: u32) -> Vec<u8> {
+    let mut message = DnsMessage::try_parse(&mut Cursor::new(query)).unwrap();
+    message.header.qr_indicator = true;
+    message.header.recursion_available = true;
+    message.header.answer_record_count = 1;
+    message.records = vec![ResourceRecord {
+        name: message.questions[0].name.clone(),
+        atype: Type::A,
+        class: Class::IN,
+        ttl,
+        rdata: RData::A {
+            address: u32::from_be_bytes(address),
+        },
+    }];
+
+    let mut buf = Vec::new();
+    message.serialize(&mut buf).unwrap();
+    buf
+}
+
+/// Answers `query` with no records and `response_code`.
+pub fn failure(query: &[u8], response_code: u8) -> Vec<u8> {
+    let mut message = DnsMessage::try_parse(&mut Cursor::new(query)).unwrap();
+    message.header.qr_indicator = true;
+    message.header.recursion_available = true;
+    message.header.response_code = response_code;
+
+    let mut buf = Vec::new();
+    message.serialize(&mut buf).unwrap();
+    buf
+}
+
+/// Answers queries for `slow.example.` with 192.0.2.2 after a delay, and any others with
+/// 192.0.2.1 straight away, for transports to show that replies may arrive out of order.
+pub async fn respond(query: Vec<u8>, _client: SocketAddr) -> Option<Vec<u8>> {
+    if qname(&query) == "slow.example." {
+        time::sleep(Duration::from_millis(200)).await;
+        Some(reply(&query, [192, 0, 2, 2]))
+    } else {
+        Some(reply(&query, [192, 0, 2, 1]))
+    }
+}
+
+/// A TCP listener on a free loopback port, along with its address.
+pub async fn listener() -> (TcpListener, SocketAddr) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    (listener, address)
+}
+
+/// The address in the first A record of `reply`.
+pub fn answer(reply: &[u8]) -> [u8; 4] {
+    let message = DnsMessage::try_parse(&mut Cursor::new(reply)).unwrap();
+    match message.records.first().map(|record| &record.rdata) {
+        Some(RData::A { address }) => address.to_be_bytes(),
+        _ => panic!("reply has no A record"),
+    }
+}
+
+/// The name in the question of `query`.
+pub fn qname(query: &[u8]) -> String {
+    let message = DnsMessage::try_parse(&mut Cursor::new(query)).unwrap();
+    message.questions[0].name.to_string()
+}
+
+/// A logger for the code under test, which only logs errors.
+pub fn logger() -> Arc<Logger> {
+    let config = Config {
+        log_level: Level::Error,
+        ..Config::default()
+    };
+    Arc::new(Logger::new(&config).unwrap())
+}
+
+/// A DNS server on a free loopback UDP port, standing in for an upstream resolver. Each
+/// query is answered, concurrently, with what the server's function returns for it, or
+/// ignored on `None`.
+pub struct StubServer {
+    pub address: SocketAddr,
+    queries: Arc<Mutex<Vec<Vec<u8>>>>,
+    task: JoinHandle<()>,
+}
+
+impl StubServer {
+    pub async fn start<F, R>(respond: F) -> Self
+    where
+        F: Fn(Vec<u8>) -> R + Send + Sync + 'static,
+        R: Future<Output = Option<Vec<u8>>> + Send + 'static,
+    {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let address = socket.local_addr().unwrap();
+        let queries = Arc::new(Mutex::new(Vec::new()));
+
+        let received = queries.clone();
+        let task = tokio::spawn(async move {
+            let mut buf = vec![0; MAX_MESSAGE_SIZE];
+            loop {
+                let Ok((len, client)) = socket.recv_from(&mut buf).await else {
+                    return;
+                };
+                let query = buf[..len].to_vec();
+                received.lock().unwrap().push(query.clone());
+
+                let reply = respond(query);
+                let socket = socket.clone();
+                tokio::spawn(async move {
+                    if let Some(reply) = reply.await {
+                        let _ = socket.send_to(&reply, client).await;
+                    }
+                });
+            }
+        });
+
+        Self {
+            address,
+            queries,
+            task,
+        }
+    }
+
+    /// Every query received so far, in wire form.
+    pub fn queries(&self) -> Vec<Vec<u8>> {
+        self.queries.lock().unwrap().clone()
+    }
+}
+
+impl Drop for StubServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
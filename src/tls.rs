@@ -0,0 +1,356 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{self, BufReader},
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncWriteExt, WriteHalf},
+    net::{TcpListener, TcpStream},
+    sync::{self, mpsc, oneshot},
+    task::JoinHandle,
+    time,
+};
+use tokio_rustls::{
+    TlsAcceptor, TlsConnector, client,
+    rustls::{
+        ClientConfig, RootCertStore, ServerConfig,
+        pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+    },
+};
+
+use crate::error::BoxError;
+use crate::log::Logger;
+use crate::stream::{self, read_framed, write_framed};
+
+/// How long a client has to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a client connection may take to send its next query, in full, before it is
+/// closed. Trickling a query in byte by byte does not keep the connection open.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Number of replies that may be waiting to be written to a single client connection.
+const REPLY_QUEUE_SIZE: usize = 64;
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, BoxError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(format!("no certificates found in {path}").into());
+    }
+
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>, BoxError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| format!("no private key found in {path}").into())
+}
+
+/// Builds the TLS configuration for serving clients from PEM files holding the
+/// certificate chain and its private key.
+pub fn server_config(cert_path: &str, key_path: &str) -> Result<Arc<ServerConfig>, BoxError> {
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(load_certs(cert_path)?, load_key(key_path)?)?;
+
+    Ok(Arc::new(config))
+}
+
+/// Builds the TLS configuration for connecting to upstreams, trusting the usual web
+/// certificate authorities, along with those in the PEM file at `ca_path`, if given.
+pub fn client_config(ca_path: Option<&str>) -> Result<Arc<ClientConfig>, BoxError> {
+    let mut roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    if let Some(ca_path) = ca_path {
+        for cert in load_certs(ca_path)? {
+            roots.add(cert)?;
+        }
+    }
+
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    Ok(Arc::new(config))
+}
+
+/// Serves DNS over TLS (RFC 7858) on `listener`, answering each query with `handler`.
+///
+/// Queries on a connection are answered concurrently, and their replies written back as
+/// soon as they are ready, which may be out of order. A reply is held on to until it
+/// has been flushed.
+pub async fn serve<H, F, R>(
+    listener: TcpListener,
+    config: Arc<ServerConfig>,
+    handler: H,
+    log: Arc<Logger>,
+) where
+    H: Fn(Vec<u8>, SocketAddr) -> F + Clone + Send + 'static,
+    F: Future<Output = Option<R>> + Send + 'static,
+    R: AsRef<[u8]> + Send + 'static,
+{
+    let acceptor = TlsAcceptor::from(config);
+    loop {
+        let (stream, addr) = stream::accept(&listener, "TLS", &log).await;
+        let acceptor = acceptor.clone();
+        let handler = handler.clone();
+        let log = log.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_connection(stream, addr, acceptor, handler).await {
+                log.warn(format_args!("TLS connection from {addr} failed: {e}"));
+            }
+        });
+    }
+}
+
+async fn serve_connection<H, F, R>(
+    stream: TcpStream,
+    addr: SocketAddr,
+    acceptor: TlsAcceptor,
+    handler: H,
+) -> io::Result<()>
+where
+    H: Fn(Vec<u8>, SocketAddr) -> F + Clone + Send + 'static,
+    F: Future<Output = Option<R>> + Send + 'static,
+    R: AsRef<[u8]> + Send + 'static,
+{
+    let stream = time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))??;
+    let (mut reader, mut writer) = tokio::io::split(stream);
+
+    let (tx, mut rx) = mpsc::channel::<R>(REPLY_QUEUE_SIZE);
+    let writing = tokio::spawn(async move {
+        while let Some(reply) = rx.recv().await {
+            write_framed(&mut writer, reply.as_ref()).await?;
+        }
+        writer.shutdown().await
+    });
+
+    loop {
+        let query = match time::timeout(IDLE_TIMEOUT, read_framed(&mut reader)).await {
+            Ok(Ok(query)) => query,
+            // The client closed the connection or let it sit idle
+            Ok(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Ok(Err(e)) => return Err(e),
+            Err(_) => break,
+        };
+
+        let handler = handler.clone();
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            if let Some(reply) = handler(query, addr).await {
+                // The connection may have failed meanwhile
+                let _ = tx.send(reply).await;
+            }
+        });
+    }
+
+    // Replies still being worked on are written before the connection is closed
+    drop(tx);
+    writing.await.map_err(io::Error::other)?
+}
+
+type Pending = Arc<Mutex<HashMap<u16, oneshot::Sender<Vec<u8>>>>>;
+
+/// A TLS connection to an upstream, shared by any number of outstanding queries. Replies
+/// are matched up with queries by ID, so they may arrive in any order.
+pub struct TlsConnection {
+    writer: sync::Mutex<WriteHalf<client::TlsStream<TcpStream>>>,
+    pending: Pending,
+    closed: Arc<AtomicBool>,
+    reading: JoinHandle<()>,
+}
+
+impl TlsConnection {
+    pub async fn connect(
+        address: SocketAddr,
+        config: Arc<ClientConfig>,
+        server_name: ServerName<'static>,
+    ) -> io::Result<Self> {
+        let stream = TcpStream::connect(address).await?;
+        let stream = TlsConnector::from(config)
+            .connect(server_name, stream)
+            .await?;
+        let (mut reader, writer) = tokio::io::split(stream);
+
+        let pending = Pending::default();
+        let closed = Arc::new(AtomicBool::new(false));
+        let reading = tokio::spawn({
+            let pending = pending.clone();
+            let closed = closed.clone();
+            async move {
+                while let Ok(reply) = read_framed(&mut reader).await {
+                    let Some(id) = reply.get(..2).map(|id| u16::from_be_bytes([id[0], id[1]]))
+                    else {
+                        continue;
+                    };
+                    if let Some(waiting) = pending.lock().unwrap().remove(&id) {
+                        let _ = waiting.send(reply);
+                    }
+                }
+
+                // Wake up everyone still waiting, as their replies will not arrive
+                closed.store(true, Ordering::Relaxed);
+                pending.lock().unwrap().clear();
+            }
+        });
+
+        Ok(Self {
+            writer: sync::Mutex::new(writer),
+            pending,
+            closed,
+            reading,
+        })
+    }
+
+    /// Whether the upstream closed the connection, or it failed.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    /// Sends `query`, in wire form, and waits for its reply. The query goes out under an
+    /// ID that is unique on the connection, and the reply is given back the original ID.
+    pub async fn exchange(&self, query: &[u8]) -> io::Result<Vec<u8>> {
+        if query.len() < 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "query too short",
+            ));
+        }
+
+        let (tx, rx) = oneshot::channel();
+        let id = {
+            let mut pending = self.pending.lock().unwrap();
+            let id = loop {
+                let id = rand::random();
+                if !pending.contains_key(&id) {
+                    break id;
+                }
+            };
+            pending.insert(id, tx);
+            id
+        };
+        // Stop waiting for the reply if the caller gives up
+        let _waiting = Waiting {
+            pending: &self.pending,
+            id,
+        };
+
+        let mut query = query.to_vec();
+        let original_id = [query[0], query[1]];
+        query[..2].copy_from_slice(&id.to_be_bytes());
+        write_framed(&mut *self.writer.lock().await, &query).await?;
+
+        let mut reply = rx.await.map_err(|_| {
+            io::Error::new(io::ErrorKind::ConnectionAborted, "TLS connection closed")
+        })?;
+        reply[..2].copy_from_slice(&original_id);
+
+        Ok(reply)
+    }
+}
+
+impl fmt::Debug for TlsConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsConnection")
+            .field("closed", &self.is_closed())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for TlsConnection {
+    fn drop(&mut self) {
+        self.reading.abort();
+    }
+}
+
+/// Removes a query from those waiting for a reply once it is answered or given up on.
+struct Waiting<'a> {
+    pending: &'a Pending,
+    id: u16,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::testing::{self, TestCertificate};
+
+    /// Serves DoT on a local port, answering `slow.example.` only after a delay.
+    async fn server(certificate: &TestCertificate) -> SocketAddr {
+        let (listener, address) = testing::listener().await;
+        tokio::spawn(serve(
+            listener,
+            certificate.server_config(),
+            testing::respond,
+            testing::logger(),
+        ));
+        address
+    }
+
+    #[tokio::test]
+    async fn replies_are_matched_to_queries_out_of_order() {
+        let certificate = TestCertificate::new();
+        let address = server(&certificate).await;
+        let connection = TlsConnection::connect(
+            address,
+            certificate.client_config(),
+            certificate.server_name(),
+        )
+        .await
+        .unwrap();
+
+        let slow = async {
+            let reply = connection
+                .exchange(&testing::query(1, "slow.example."))
+                .await;
+            (reply, time::Instant::now())
+        };
+        let fast = async {
+            // Let the slow query go out first
+            time::sleep(Duration::from_millis(50)).await;
+            let reply = connection
+                .exchange(&testing::query(2, "fast.example."))
+                .await;
+            (reply, time::Instant::now())
+        };
+        let ((slow, slow_done), (fast, fast_done)) = tokio::join!(slow, fast);
+        let (slow, fast) = (slow.unwrap(), fast.unwrap());
+
+        assert_eq!(slow[..2], 1u16.to_be_bytes());
+        assert_eq!(testing::answer(&slow), [192, 0, 2, 2]);
+        assert_eq!(fast[..2], 2u16.to_be_bytes());
+        assert_eq!(testing::answer(&fast), [192, 0, 2, 1]);
+        assert!(fast_done < slow_done);
+        assert!(!connection.is_closed());
+    }
+
+    #[tokio::test]
+    async fn untrusted_certificates_are_refused() {
+        let certificate = TestCertificate::new();
+        let address = server(&certificate).await;
+        let other = TestCertificate::new();
+        let connection =
+            TlsConnection::connect(address, other.client_config(), other.server_name()).await;
+        assert!(connection.is_err());
+    }
+}
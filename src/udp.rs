@@ -0,0 +1,459 @@
+use std::{
+    io::{self, Cursor, IoSlice, IoSliceMut},
+    net::{SocketAddr, SocketAddrV4, SocketAddrV6},
+    os::fd::AsRawFd,
+    sync::Arc,
+};
+
+use nix::sys::socket::{self as nix_socket, ControlMessage, MsgFlags, SockaddrStorage};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{io::Interest, net::UdpSocket, sync::Semaphore};
+
+use crate::log::Logger;
+use crate::message::{ByteSerialize, DnsMessage, MAX_MESSAGE_SIZE};
+
+/// Largest reply to a client that did not advertise a payload size with EDNS.
+const MIN_PAYLOAD_SIZE: usize = 512;
+
+/// Most queries answered at once. Past this, queries are left in the socket's receive
+/// buffer, and dropped by the kernel once it fills up, rather than piling up as tasks.
+const MAX_CONCURRENT_QUERIES: usize = 1000;
+
+/// Opens a UDP socket on `address`. IPv6 sockets only ever accept IPv6, whatever the
+/// system default, so that IPv4 on the same port is left to a socket of its own.
+pub fn bind(address: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )?;
+    if address.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+
+    UdpSocket::from_std(socket.into())
+}
+
+/// Serves plain DNS on `socket`, answering each query with `handler`. A reply is held
+/// on to until it has been sent.
+///
+/// Replies are sent from the local address the query arrived on, so that clients of a
+/// socket bound to a wildcard address on a host with several addresses recognise them.
+pub async fn serve<H, F, R>(socket: UdpSocket, handler: H, log: Arc<Logger>) -> io::Result<()>
+where
+    H: Fn(Vec<u8>, SocketAddr) -> F + Clone + Send + 'static,
+    F: Future<Output = Option<R>> + Send + 'static,
+    R: AsRef<[u8]> + Send + 'static,
+{
+    receive_destinations(&socket)?;
+    let socket = Arc::new(socket);
+    let limit = Arc::new(Semaphore::new(MAX_CONCURRENT_QUERIES));
+
+    let mut buf = vec![0; MAX_MESSAGE_SIZE];
+    loop {
+        let permit = limit.clone().acquire_owned().await.unwrap();
+        let (len, client, destination) = match receive(&socket, &mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                log.error(format_args!("Error receiving: {e}"));
+                continue;
+            }
+        };
+
+        let socket = socket.clone();
+        let handler = handler.clone();
+        let log = log.clone();
+        let query = buf[..len].to_vec();
+        tokio::spawn(async move {
+            let _permit = permit;
+            let Some(reply) = handler(query, client).await else {
+                return;
+            };
+            if let Err(e) = send(&socket, reply.as_ref(), client, destination).await {
+                log.warn(format_args!("Error sending to {client}: {e}"));
+            }
+        });
+    }
+}
+
+/// Largest reply `query` may be sent over UDP: the payload size it advertised with EDNS,
+/// or 512 bytes without.
+pub fn max_reply_size(query: &DnsMessage<'_>) -> usize {
+    query.edns.map_or(MIN_PAYLOAD_SIZE, |edns| {
+        (edns.udp_payload_size as usize).max(MIN_PAYLOAD_SIZE)
+    })
+}
+
+/// Cuts `reply` down to its header, question and OPT record, with the truncation flag
+/// set, when it is larger than `max_size`, so that the client retries over TCP.
+pub fn truncate(reply: Vec<u8>, max_size: usize) -> Vec<u8> {
+    if reply.len() <= max_size {
+        return reply;
+    }
+
+    if let Ok(mut message) = DnsMessage::try_parse(&mut Cursor::new(&reply[..])) {
+        message.header.truncation = true;
+        message.header.answer_record_count = 0;
+        message.header.authority_record_count = 0;
+        message.header.additional_record_count = u16::from(message.edns.is_some());
+        message.records.clear();
+        message.authority_records.clear();
+        message.additional_records.clear();
+
+        let mut buf = Vec::with_capacity(MIN_PAYLOAD_SIZE);
+        if message.serialize(&mut buf).is_ok() && buf.len() <= max_size {
+            return buf;
+        }
+    }
+
+    // Just the header, for replies that cannot be parsed
+    let mut header = reply[..12].to_vec();
+    header[2] |= 0b0000_0010;
+    header[4..].fill(0);
+    header
+}
+
+/// The local address a query arrived on, which its reply is sent from.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[derive(Clone, Copy)]
+enum Destination {
+    V4(nix::libc::in_pktinfo),
+    V6(nix::libc::in6_pktinfo),
+}
+
+/// Elsewhere replies are sent from whichever address the kernel picks.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+#[derive(Clone, Copy)]
+enum Destination {}
+
+/// Has the kernel report the local address each datagram arrives on.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn receive_destinations(socket: &UdpSocket) -> io::Result<()> {
+    use nix_socket::sockopt::{Ipv4PacketInfo, Ipv6RecvPacketInfo};
+
+    match socket.local_addr()? {
+        SocketAddr::V4(_) => nix_socket::setsockopt(socket, Ipv4PacketInfo, &true)?,
+        SocketAddr::V6(_) => nix_socket::setsockopt(socket, Ipv6RecvPacketInfo, &true)?,
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn receive_destinations(_socket: &UdpSocket) -> io::Result<()> {
+    Ok(())
+}
+
+/// Receives a datagram into `buf`, returning its length, where it came from, and the
+/// local address it was sent to.
+async fn receive(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Option<Destination>)> {
+    let mut control = nix::cmsg_space!(nix::libc::in6_pktinfo);
+    socket
+        .async_io(Interest::READABLE, || {
+            let mut iov = [IoSliceMut::new(buf)];
+            let received = nix_socket::recvmsg::<SockaddrStorage>(
+                socket.as_raw_fd(),
+                &mut iov,
+                Some(&mut control),
+                MsgFlags::empty(),
+            )?;
+
+            let client = received
+                .address
+                .as_ref()
+                .and_then(socket_addr)
+                .ok_or_else(|| io::Error::other("datagram without a source address"))?;
+            let destination = received.cmsgs()?.find_map(destination);
+            Ok((received.bytes, client, destination))
+        })
+        .await
+}
+
+fn socket_addr(address: &SockaddrStorage) -> Option<SocketAddr> {
+    if let Some(address) = address.as_sockaddr_in() {
+        return Some(SocketAddrV4::from(*address).into());
+    }
+    address
+        .as_sockaddr_in6()
+        .map(|address| SocketAddrV6::from(*address).into())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn destination(message: nix_socket::ControlMessageOwned) -> Option<Destination> {
+    use nix_socket::ControlMessageOwned;
+
+    match message {
+        ControlMessageOwned::Ipv4PacketInfo(info) => Some(Destination::V4(info)),
+        ControlMessageOwned::Ipv6PacketInfo(info) => Some(Destination::V6(info)),
+        _ => None,
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn destination(_message: nix_socket::ControlMessageOwned) -> Option<Destination> {
+    None
+}
+
+async fn send(
+    socket: &UdpSocket,
+    message: &[u8],
+    client: SocketAddr,
+    source: Option<Destination>,
+) -> io::Result<()> {
+    let client = SockaddrStorage::from(client);
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let source = source.map(|source| match source {
+        // The interface is left to routing, as with any other datagram
+        Destination::V4(mut info) => {
+            info.ipi_ifindex = 0;
+            Destination::V4(info)
+        }
+        Destination::V6(info) => Destination::V6(info),
+    });
+    let control: Vec<_> = source.iter().map(control_message).collect();
+
+    socket
+        .async_io(Interest::WRITABLE, || {
+            nix_socket::sendmsg(
+                socket.as_raw_fd(),
+                &[IoSlice::new(message)],
+                &control,
+                MsgFlags::empty(),
+                Some(&client),
+            )?;
+            Ok(())
+        })
+        .await
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn control_message(source: &Destination) -> ControlMessage<'_> {
+    match source {
+        Destination::V4(info) => ControlMessage::Ipv4PacketInfo(info),
+        Destination::V6(info) => ControlMessage::Ipv6PacketInfo(info),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn control_message(source: &Destination) -> ControlMessage<'_> {
+    match *source {}
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::message::{
+        Class, DnsHeader, DnsQuestion, Edns, Name, Opcode, RData, ResourceRecord,
+        Type as RecordType,
+    };
+    use crate::testing;
+
+    /// A reply to an A query for `example.com.` with `answers` addresses.
+    fn reply(answers: u32, edns: Option<Edns>) -> DnsMessage<'static> {
+        let name: Name<'static> = "example.com.".parse().unwrap();
+        let records: Vec<_> = (0..answers)
+            .map(|i| ResourceRecord {
+                name: name.clone(),
+                atype: RecordType::A,
+                class: Class::IN,
+                ttl: 60,
+                rdata: RData::A { address: i },
+            })
+            .collect();
+        DnsMessage {
+            header: DnsHeader {
+                id: 0x1234,
+                qr_indicator: true,
+                opcode: Opcode::StandardQuery,
+                authoritative_answer: false,
+                truncation: false,
+                recursion_desired: true,
+                recursion_available: true,
+                reserved: 0,
+                response_code: 0,
+                question_count: 1,
+                answer_record_count: records.len() as u16,
+                authority_record_count: 0,
+                additional_record_count: u16::from(edns.is_some()),
+            },
+            questions: vec![DnsQuestion {
+                name,
+                qtype: RecordType::A,
+                class: Class::IN,
+            }],
+            records,
+            authority_records: vec![],
+            additional_records: vec![],
+            edns,
+        }
+    }
+
+    fn wire(message: &DnsMessage<'_>) -> Vec<u8> {
+        let mut buf = Vec::new();
+        message.serialize(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn replies_within_the_limit_are_left_alone() {
+        let buf = wire(&reply(3, None));
+        assert_eq!(truncate(buf.clone(), MIN_PAYLOAD_SIZE), buf);
+    }
+
+    #[test]
+    fn oversized_replies_are_cut_to_the_question() {
+        let edns = Edns {
+            udp_payload_size: 1232,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+        };
+        let buf = wire(&reply(100, Some(edns)));
+        assert!(buf.len() > 1232);
+
+        let truncated = truncate(buf, 1232);
+        let message = DnsMessage::try_parse(&mut Cursor::new(&truncated[..])).unwrap();
+        assert!(message.header.truncation);
+        assert_eq!(message.header.id, 0x1234);
+        assert_eq!(message.questions.len(), 1);
+        assert!(message.records.is_empty());
+        assert_eq!(message.edns, Some(edns));
+    }
+
+    #[test]
+    fn the_limit_follows_the_advertised_payload_size() {
+        let mut query = reply(0, None);
+        assert_eq!(max_reply_size(&query), 512);
+
+        for (advertised, limit) in [(4096, 4096), (100, 512)] {
+            query.edns = Some(Edns {
+                udp_payload_size: advertised,
+                extended_rcode: 0,
+                version: 0,
+                dnssec_ok: false,
+            });
+            assert_eq!(max_reply_size(&query), limit);
+        }
+    }
+
+    #[tokio::test]
+    async fn replies_larger_than_the_mtu_are_sent() {
+        let edns = Edns {
+            udp_payload_size: 4096,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+        };
+        let big = wire(&reply(100, Some(edns)));
+        // Larger than the MTU of any Ethernet link on the way
+        assert!(big.len() > 1500 && big.len() <= 4096);
+
+        let socket = bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = socket.local_addr().unwrap();
+        tokio::spawn(serve(
+            socket,
+            {
+                let big = big.clone();
+                move |_, _| {
+                    let big = big.clone();
+                    async move { Some(big) }
+                }
+            },
+            testing::logger(),
+        ));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client
+            .send_to(&wire(&reply(0, Some(edns))), address)
+            .await
+            .unwrap();
+        let mut buf = vec![0; MAX_MESSAGE_SIZE];
+        let len = tokio::time::timeout(std::time::Duration::from_secs(5), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(buf[..len], big);
+    }
+
+    #[tokio::test]
+    async fn replies_come_from_the_address_queried() {
+        let socket = bind("0.0.0.0:0".parse().unwrap()).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        tokio::spawn(serve(
+            socket,
+            |query, _| async move { Some(testing::reply(&query, [192, 0, 2, 1])) },
+            testing::logger(),
+        ));
+
+        // Left to itself, the kernel would reply to a loopback client from 127.0.0.1
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = SocketAddr::from(([127, 0, 0, 2], port));
+        client
+            .send_to(&testing::query(1, "example.com."), server)
+            .await
+            .unwrap();
+        let mut buf = vec![0; MAX_MESSAGE_SIZE];
+        let (len, from) = tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            client.recv_from(&mut buf),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(from, server);
+        assert_eq!(testing::answer(&buf[..len]), [192, 0, 2, 1]);
+    }
+
+    #[tokio::test]
+    async fn queries_beyond_the_limit_wait_for_earlier_ones() {
+        let socket = bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = socket.local_addr().unwrap();
+        let started = Arc::new(AtomicUsize::new(0));
+        let gate = Arc::new(Semaphore::new(0));
+        tokio::spawn(serve(
+            socket,
+            {
+                let started = started.clone();
+                let gate = gate.clone();
+                move |query, _| {
+                    started.fetch_add(1, Ordering::Relaxed);
+                    let gate = gate.clone();
+                    async move {
+                        gate.acquire().await.unwrap().forget();
+                        Some(testing::reply(&query, [192, 0, 2, 1]))
+                    }
+                }
+            },
+            testing::logger(),
+        ));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let queries = MAX_CONCURRENT_QUERIES + 50;
+        // In batches, so as not to overflow the server's receive buffer
+        for batch in (0..queries).collect::<Vec<_>>().chunks(100) {
+            for &id in batch {
+                let query = testing::query(id as u16, "example.com.");
+                client.send_to(&query, address).await.unwrap();
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(started.load(Ordering::Relaxed), MAX_CONCURRENT_QUERIES);
+
+        gate.add_permits(queries);
+        let mut buf = vec![0; MAX_MESSAGE_SIZE];
+        for _ in 0..queries {
+            tokio::time::timeout(std::time::Duration::from_secs(5), client.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        assert_eq!(started.load(Ordering::Relaxed), queries);
+    }
+}
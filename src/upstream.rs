@@ -0,0 +1,1254 @@
+use std::{
+    future,
+    io::{self, Cursor},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        Arc, Mutex, Weak,
+        atomic::{AtomicUsize, Ordering},
+    },
+    task::Poll,
+    time::{Duration, SystemTime},
+};
+
+use hyper::Uri;
+use tokio::{
+    net::{TcpStream, UdpSocket},
+    sync,
+    time::{self, Instant},
+};
+use tokio_rustls::rustls::{ClientConfig, pki_types::ServerName};
+
+use crate::dnstap::{self, Dnstap, MessageType, SocketProtocol};
+use crate::doh::HttpsConnection;
+use crate::error::BoxError;
+use crate::log::Logger;
+use crate::message::{
+    ByteSerialize, Class, DnsHeader, DnsMessage, DnsQuestion, MAX_MESSAGE_SIZE, Name, Opcode,
+    RCODE_REFUSED, RCODE_SERVER_FAILURE, Type,
+};
+use crate::metrics::{Histogram, HistogramSnapshot};
+use crate::stream::{read_framed, write_framed};
+use crate::tls::TlsConnection;
+
+/// How long to wait for the first reply from an upstream before retransmitting. The
+/// timeout doubles with every retransmission.
+const ATTEMPT_TIMEOUT: Duration = Duration::from_millis(800);
+
+/// Number of times a query is sent upstream before giving up.
+const ATTEMPTS: usize = 3;
+
+/// Overall time allowed for resolving a question upstream, across all attempts.
+const QUERY_DEADLINE: Duration = Duration::from_secs(4);
+
+/// Number of random source ports tried before letting the OS pick one.
+const RANDOM_PORT_ATTEMPTS: usize = 8;
+
+/// Number of idle TCP connections kept open to each upstream for reuse.
+const MAX_IDLE_CONNECTIONS: usize = 4;
+
+/// How long an idle TCP connection is kept for reuse. Upstreams and middleboxes tend to
+/// drop connections left idle for longer anyway.
+const MAX_IDLE_TIME: Duration = Duration::from_secs(10);
+
+/// How long a reused TCP connection has to deliver the reply before a new connection is
+/// tried instead, for connections that were dropped without notice.
+const REUSED_CONNECTION_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Consecutive failures after which an upstream is considered down.
+const MAX_CONSECUTIVE_FAILURES: u32 = 3;
+
+/// How often upstreams that are down are probed to see whether they have recovered.
+const PROBE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Queries in a row answered only with the query name's case changed, after which an
+/// upstream is taken not to preserve case. A single spoofed reply is not enough.
+const MAX_CASE_MISMATCHES: u32 = 3;
+
+/// How long case randomization stays off for an upstream that did not preserve case,
+/// before it is tried again.
+const CASE_RANDOMIZATION_RETRY: Duration = Duration::from_secs(30 * 60);
+
+/// How queries are spread across a set of upstreams.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Strategy {
+    /// Always prefer the first healthy upstream, in the order they were configured.
+    #[default]
+    Failover,
+    /// Rotate through the healthy upstreams.
+    RoundRobin,
+    /// Prefer the healthy upstream with the lowest smoothed round-trip time.
+    LowestRtt,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "failover" => Ok(Strategy::Failover),
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "lowest-rtt" => Ok(Strategy::LowestRtt),
+            _ => Err(format!("unknown upstream strategy: {s}")),
+        }
+    }
+}
+
+/// How queries reach an upstream.
+#[derive(Debug, Clone)]
+pub enum Transport {
+    /// Plain DNS over UDP, retried over TCP when the reply is truncated.
+    Udp,
+    /// DNS over TLS (RFC 7858), with the upstream's certificate checked against
+    /// `server_name`.
+    Tls {
+        config: Arc<ClientConfig>,
+        server_name: ServerName<'static>,
+    },
+    /// DNS over HTTPS (RFC 8484), POSTing queries to `uri`.
+    Https {
+        config: Arc<ClientConfig>,
+        server_name: ServerName<'static>,
+        uri: Uri,
+    },
+}
+
+#[derive(Debug, Default)]
+struct Health {
+    consecutive_failures: u32,
+    /// Smoothed round-trip time, unknown until the first reply.
+    srtt: Option<Duration>,
+}
+
+/// Whether an upstream echoes the query name with its case intact, which case
+/// randomization depends on.
+#[derive(Debug, Default)]
+struct CaseHealth {
+    /// Replies in a row with the case changed.
+    mismatches: u32,
+    /// When randomization was turned off, if it is.
+    disabled_at: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct Upstream {
+    pub address: SocketAddr,
+    transport: Transport,
+    health: Mutex<Health>,
+    /// TCP connections left open after a previous exchange, with when they went idle.
+    idle_connections: Mutex<Vec<(TcpStream, Instant)>>,
+    case_health: Mutex<CaseHealth>,
+    /// The TLS connection shared by all queries, for DNS over TLS upstreams.
+    tls_connection: sync::Mutex<Option<Arc<TlsConnection>>>,
+    /// The HTTP/2 connection shared by all queries, for DNS over HTTPS upstreams.
+    https_connection: sync::Mutex<Option<Arc<HttpsConnection>>>,
+    /// Round-trip times of every reply.
+    latency: Histogram,
+    /// Where queries and replies exchanged with the upstream are emitted, if anywhere.
+    dnstap: Option<Arc<Dnstap>>,
+    log: Arc<Logger>,
+}
+
+impl Upstream {
+    pub fn new(address: SocketAddr, transport: Transport, log: Arc<Logger>) -> Self {
+        Self {
+            address,
+            transport,
+            health: Mutex::new(Health::default()),
+            idle_connections: Mutex::new(Vec::new()),
+            case_health: Mutex::new(CaseHealth::default()),
+            tls_connection: sync::Mutex::new(None),
+            https_connection: sync::Mutex::new(None),
+            latency: Histogram::default(),
+            dnstap: None,
+            log,
+        }
+    }
+
+    pub fn latency(&self) -> HistogramSnapshot {
+        self.latency.snapshot()
+    }
+
+    fn is_up(&self) -> bool {
+        self.health.lock().unwrap().consecutive_failures < MAX_CONSECUTIVE_FAILURES
+    }
+
+    fn srtt(&self) -> Duration {
+        // Upstreams that have not been measured yet sort first so that they get measured
+        self.health.lock().unwrap().srtt.unwrap_or_default()
+    }
+
+    fn record_success(&self, rtt: Duration) {
+        self.latency.observe(rtt);
+        let mut health = self.health.lock().unwrap();
+        if health.consecutive_failures >= MAX_CONSECUTIVE_FAILURES {
+            self.log
+                .info(format_args!("Upstream {} is back up", self.address));
+        }
+        health.consecutive_failures = 0;
+        health.srtt = Some(match health.srtt {
+            Some(srtt) => (srtt.saturating_mul(7).saturating_add(rtt) / 8).min(QUERY_DEADLINE),
+            None => rtt.min(QUERY_DEADLINE),
+        });
+    }
+
+    fn record_failure(&self) {
+        let mut health = self.health.lock().unwrap();
+        health.consecutive_failures = health.consecutive_failures.saturating_add(1);
+        if health.consecutive_failures == MAX_CONSECUTIVE_FAILURES {
+            self.log
+                .warn(format_args!("Upstream {} is down", self.address));
+        }
+        // Penalize the upstream so the lowest-RTT strategy moves away from it. No reply
+        // takes longer than the query deadline, so the penalty stops growing there.
+        health.srtt = health
+            .srtt
+            .map(|srtt| srtt.saturating_mul(2).min(QUERY_DEADLINE));
+    }
+
+    /// Emits a query sent to the upstream at `query_time`, or with `response`, the reply
+    /// to it.
+    fn tap(
+        &self,
+        protocol: SocketProtocol,
+        query: &[u8],
+        query_time: SystemTime,
+        response: Option<&[u8]>,
+    ) {
+        let Some(dnstap) = &self.dnstap else {
+            return;
+        };
+        let message_type = match response {
+            Some(_) => MessageType::ForwarderResponse,
+            None => MessageType::ForwarderQuery,
+        };
+        dnstap.emit(&dnstap::Message {
+            message_type,
+            protocol,
+            query_address: None,
+            response_address: Some(self.address),
+            query_time,
+            query_message: response.is_none().then_some(query),
+            response_time: response.map(|_| SystemTime::now()),
+            response_message: response,
+        });
+    }
+
+    /// Whether to randomize the case of query names, turning it back on once it has been
+    /// off for a while.
+    fn randomizes_case(&self) -> bool {
+        let mut case_health = self.case_health.lock().unwrap();
+        if let Some(disabled_at) = case_health.disabled_at {
+            if disabled_at.elapsed() < CASE_RANDOMIZATION_RETRY {
+                return false;
+            }
+            *case_health = CaseHealth::default();
+        }
+
+        true
+    }
+
+    fn record_case_preserved(&self) {
+        self.case_health.lock().unwrap().mismatches = 0;
+    }
+
+    /// Counts a query that got a reply with the case changed, turning randomization off
+    /// for a while after several in a row.
+    fn record_case_mismatch(&self) {
+        let mut case_health = self.case_health.lock().unwrap();
+        case_health.mismatches += 1;
+        if case_health.mismatches >= MAX_CASE_MISMATCHES && case_health.disabled_at.is_none() {
+            case_health.disabled_at = Some(Instant::now());
+            self.log.warn(format_args!(
+                "Upstream {} does not preserve the case of query names, not randomizing it for a while",
+                self.address
+            ));
+        }
+    }
+
+    /// Sends `bytes`, the wire form of `query`, and waits for the matching reply. Stray
+    /// packets and replies that do not match the query's ID and question are ignored.
+    ///
+    /// With `randomize`, the case of the query name is randomized, and replies must echo
+    /// it exactly. A reply that matches in all but case is taken as the upstream not
+    /// preserving case, and the query is sent again straight away as given, on the same
+    /// socket and with the same ID, so that it still has those to guess.
+    async fn exchange(
+        &self,
+        query: &DnsMessage<'_>,
+        bytes: &[u8],
+        randomize: bool,
+    ) -> io::Result<Vec<u8>> {
+        let sock = bind_random_port(self.address).await?;
+        sock.connect(self.address).await?;
+
+        let mut sent = bytes.to_vec();
+        let mut case_randomized = randomize;
+        if case_randomized {
+            randomize_case(&mut sent);
+        }
+        let mut sent_at = SystemTime::now();
+        sock.send(&sent).await?;
+        self.tap(SocketProtocol::Udp, &sent, sent_at, None);
+
+        let mut buf = vec![0; MAX_MESSAGE_SIZE];
+        loop {
+            let len = sock.recv(&mut buf).await?;
+            if !is_reply_to(&buf[..len], query) {
+                self.log.warn(format_args!(
+                    "Ignoring mismatched reply from {}",
+                    self.address
+                ));
+                continue;
+            }
+            if case_randomized && !echoes_question(&buf[..len], &sent) {
+                self.record_case_mismatch();
+                self.log.warn(format_args!(
+                    "Reply from {} changed the case of the query name, querying again without randomizing it",
+                    self.address
+                ));
+                case_randomized = false;
+                sent = bytes.to_vec();
+                sent_at = SystemTime::now();
+                sock.send(&sent).await?;
+                self.tap(SocketProtocol::Udp, &sent, sent_at, None);
+                continue;
+            }
+            if case_randomized {
+                self.record_case_preserved();
+            }
+
+            buf.truncate(len);
+            self.tap(SocketProtocol::Udp, &sent, sent_at, Some(&buf));
+            return Ok(buf);
+        }
+    }
+
+    /// Exchanges `bytes`, the wire form of `query`, over the upstream's transport. Over UDP,
+    /// the case of the query name is randomized if the upstream preserves it, and truncated
+    /// replies are retried over TCP.
+    async fn attempt(&self, query: &DnsMessage<'_>, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        match self.transport {
+            Transport::Udp => {
+                let reply = self.exchange(query, bytes, self.randomizes_case()).await?;
+                if is_truncated(&reply) {
+                    return self.exchange_tcp(query, bytes).await;
+                }
+                Ok(reply)
+            }
+            Transport::Tls { .. } => self.exchange_tls(query, bytes).await,
+            Transport::Https { .. } => self.exchange_https(query, bytes).await,
+        }
+    }
+
+    /// Like [Upstream::exchange], but over TCP, for replies too large for UDP. An idle
+    /// connection is reused when there is one.
+    async fn exchange_tcp(&self, query: &DnsMessage<'_>, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let sent_at = SystemTime::now();
+        self.tap(SocketProtocol::Tcp, bytes, sent_at, None);
+
+        let idle = self.idle_connection();
+        if let Some(mut stream) = idle {
+            // The upstream may have closed the connection while it sat idle, in which case
+            // a new one is opened below
+            let reused = exchange_framed(&mut stream, query, bytes);
+            if let Ok(Ok(reply)) = time::timeout(REUSED_CONNECTION_TIMEOUT, reused).await {
+                self.release_connection(stream);
+                self.tap(SocketProtocol::Tcp, bytes, sent_at, Some(&reply));
+                return Ok(reply);
+            }
+        }
+
+        let mut stream = TcpStream::connect(self.address).await?;
+        let reply = exchange_framed(&mut stream, query, bytes).await?;
+        self.release_connection(stream);
+        self.tap(SocketProtocol::Tcp, bytes, sent_at, Some(&reply));
+
+        Ok(reply)
+    }
+
+    /// Like [Upstream::exchange], but over the upstream's TLS connection, which is opened
+    /// if there is none yet and shared with any other outstanding queries.
+    async fn exchange_tls(&self, query: &DnsMessage<'_>, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let Transport::Tls {
+            config,
+            server_name,
+        } = &self.transport
+        else {
+            return Err(io::Error::other("upstream does not use TLS"));
+        };
+
+        let connection = {
+            let mut tls_connection = self.tls_connection.lock().await;
+            match &*tls_connection {
+                Some(connection) if !connection.is_closed() => connection.clone(),
+                _ => {
+                    let connection =
+                        TlsConnection::connect(self.address, config.clone(), server_name.clone())
+                            .await?;
+                    tls_connection.insert(Arc::new(connection)).clone()
+                }
+            }
+        };
+
+        let sent_at = SystemTime::now();
+        self.tap(SocketProtocol::Dot, bytes, sent_at, None);
+        let reply = connection.exchange(bytes).await?;
+        if !is_reply_to(&reply, query) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "mismatched reply",
+            ));
+        }
+        self.tap(SocketProtocol::Dot, bytes, sent_at, Some(&reply));
+
+        Ok(reply)
+    }
+
+    /// Like [Upstream::exchange], but over the upstream's HTTP/2 connection, which is
+    /// opened if there is none yet and shared with any other outstanding queries.
+    async fn exchange_https(&self, query: &DnsMessage<'_>, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let Transport::Https {
+            config,
+            server_name,
+            uri,
+        } = &self.transport
+        else {
+            return Err(io::Error::other("upstream does not use HTTPS"));
+        };
+
+        let connection = {
+            let mut https_connection = self.https_connection.lock().await;
+            match &*https_connection {
+                Some(connection) if !connection.is_closed() => connection.clone(),
+                _ => {
+                    let connection =
+                        HttpsConnection::connect(self.address, config, server_name.clone()).await?;
+                    https_connection.insert(Arc::new(connection)).clone()
+                }
+            }
+        };
+
+        let sent_at = SystemTime::now();
+        self.tap(SocketProtocol::Doh, bytes, sent_at, None);
+        let reply = connection.exchange(uri, bytes).await?;
+        if !is_reply_to(&reply, query) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "mismatched reply",
+            ));
+        }
+        self.tap(SocketProtocol::Doh, bytes, sent_at, Some(&reply));
+
+        Ok(reply)
+    }
+
+    /// Takes the most recently used idle connection, closing any that sat idle too long.
+    fn idle_connection(&self) -> Option<TcpStream> {
+        let mut idle_connections = self.idle_connections.lock().unwrap();
+        idle_connections.retain(|(_, idle_since)| idle_since.elapsed() < MAX_IDLE_TIME);
+        idle_connections.pop().map(|(stream, _)| stream)
+    }
+
+    fn release_connection(&self, stream: TcpStream) {
+        let mut idle_connections = self.idle_connections.lock().unwrap();
+        if idle_connections.len() < MAX_IDLE_CONNECTIONS {
+            idle_connections.push((stream, Instant::now()));
+        }
+    }
+}
+
+/// A group of interchangeable upstream resolvers, along with their health.
+#[derive(Debug)]
+pub struct UpstreamSet {
+    upstreams: Vec<Upstream>,
+    strategy: Strategy,
+    next: AtomicUsize,
+}
+
+impl UpstreamSet {
+    /// A set of plain DNS upstreams.
+    pub fn new(addresses: Vec<SocketAddr>, strategy: Strategy, log: &Arc<Logger>) -> Self {
+        let upstreams = addresses
+            .into_iter()
+            .map(|address| Upstream::new(address, Transport::Udp, log.clone()))
+            .collect();
+        Self::with_upstreams(upstreams, strategy)
+    }
+
+    pub fn with_upstreams(upstreams: Vec<Upstream>, strategy: Strategy) -> Self {
+        Self {
+            upstreams,
+            strategy,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Emits the queries and replies exchanged with the upstreams to `dnstap`.
+    pub fn with_dnstap(mut self, dnstap: Arc<Dnstap>) -> Self {
+        for upstream in &mut self.upstreams {
+            upstream.dnstap = Some(dnstap.clone());
+        }
+        self
+    }
+
+    pub fn upstreams(&self) -> &[Upstream] {
+        &self.upstreams
+    }
+
+    /// The upstreams to try for a query, in order of preference. Upstreams that are down
+    /// are left out, unless all of them are.
+    fn candidates(&self) -> Vec<&Upstream> {
+        let mut candidates: Vec<_> = self.upstreams.iter().filter(|u| u.is_up()).collect();
+        if candidates.is_empty() {
+            return self.upstreams.iter().collect();
+        }
+
+        match self.strategy {
+            Strategy::Failover => {}
+            Strategy::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed) % candidates.len();
+                candidates.rotate_left(start);
+            }
+            Strategy::LowestRtt => candidates.sort_by_key(|u| u.srtt()),
+        }
+
+        candidates
+    }
+
+    /// Sends `query` upstream and returns the reply, along with the upstream it came from.
+    pub async fn query(
+        &self,
+        query: &DnsMessage<'_>,
+    ) -> Result<(DnsMessage<'static>, SocketAddr), BoxError> {
+        let mut bytes = Vec::with_capacity(128);
+        query.serialize(&mut bytes)?;
+
+        let (reply, upstream) = self.query_bytes(query, &bytes).await?;
+        let reply = DnsMessage::try_parse(&mut Cursor::new(&reply[..]))?;
+
+        Ok((reply.into_owned(), upstream))
+    }
+
+    /// Sends `bytes`, the wire form of `query`, upstream and returns the wire form of the
+    /// reply, as sent by the upstream, along with the upstream it came from.
+    ///
+    /// Each query goes out with a fresh random ID from a random source port. Unanswered
+    /// queries are retransmitted to the next candidate upstream with a doubling timeout,
+    /// until the attempts or the overall deadline run out. Earlier attempts are still
+    /// listened to meanwhile, so a late reply to one is as good as any. Truncated replies
+    /// are retried over TCP with the same upstream.
+    ///
+    /// The case of the query name is randomized (DNS 0x20) for upstreams that preserve it,
+    /// and restored in the reply.
+    pub async fn query_bytes(
+        &self,
+        query: &DnsMessage<'_>,
+        bytes: &[u8],
+    ) -> Result<(Vec<u8>, SocketAddr), BoxError> {
+        let deadline = Instant::now() + QUERY_DEADLINE;
+
+        let id: u16 = rand::random();
+        let mut query = query.clone();
+        query.header.id = id;
+        let mut bytes = bytes.to_vec();
+        bytes[..2].copy_from_slice(&id.to_be_bytes());
+
+        let candidates = self.candidates();
+        if candidates.is_empty() {
+            return Err("no upstreams configured".into());
+        }
+
+        let attempts = ATTEMPTS.max(candidates.len());
+        let mut attempt_timeout = ATTEMPT_TIMEOUT;
+        let mut pending = Vec::new();
+        let mut failed_reply = None;
+        for attempt in 0..=attempts {
+            // Past the last attempt, only the earlier ones are left to wait for
+            let attempt_deadline = if attempt < attempts {
+                let upstream = candidates[attempt % candidates.len()];
+                pending.push(Attempt {
+                    number: attempt,
+                    upstream,
+                    started: Instant::now(),
+                    exchange: Box::pin(upstream.attempt(&query, &bytes)),
+                });
+                deadline.min(Instant::now() + attempt_timeout)
+            } else {
+                deadline
+            };
+
+            while !pending.is_empty() {
+                let finished = time::timeout_at(attempt_deadline, first_finished(&mut pending));
+                let Ok((finished, outcome)) = finished.await else {
+                    if attempt < attempts {
+                        let upstream = candidates[attempt % candidates.len()];
+                        upstream.record_failure();
+                        upstream.log.warn(format_args!(
+                            "Attempt {} to {} timed out",
+                            attempt + 1,
+                            upstream.address
+                        ));
+                    }
+                    break;
+                };
+
+                let upstream = finished.upstream;
+                match outcome {
+                    Ok(mut reply) => {
+                        upstream.record_success(finished.started.elapsed());
+                        restore_case(&mut reply, &bytes);
+                        match reply[3] & 0b0000_1111 {
+                            // Another upstream may do better, if there are any left to try
+                            RCODE_SERVER_FAILURE | RCODE_REFUSED
+                                if finished.number + 1 < candidates.len() =>
+                            {
+                                failed_reply = Some((reply, upstream.address))
+                            }
+                            _ => return Ok((reply, upstream.address)),
+                        }
+                    }
+                    Err(e) => {
+                        upstream.record_failure();
+                        upstream.log.warn(format_args!(
+                            "Attempt {} to {} failed: {e}",
+                            finished.number + 1,
+                            upstream.address
+                        ));
+                    }
+                }
+                // Otherwise this attempt is still waited for
+                if finished.number == attempt {
+                    break;
+                }
+            }
+
+            if Instant::now() >= deadline {
+                break;
+            }
+            attempt_timeout *= 2;
+        }
+
+        failed_reply.ok_or_else(|| "no reply from any upstream".into())
+    }
+
+    /// Sends a probe to every upstream that is down, so that recovered upstreams are put
+    /// back into rotation.
+    async fn probe(&self) {
+        let probe = DnsMessage {
+            header: DnsHeader {
+                id: 0,
+                qr_indicator: false,
+                opcode: Opcode::StandardQuery,
+                authoritative_answer: false,
+                truncation: false,
+                recursion_desired: true,
+                recursion_available: false,
+                reserved: 0,
+                response_code: 0,
+                question_count: 1,
+                answer_record_count: 0,
+                authority_record_count: 0,
+                additional_record_count: 0,
+            },
+            questions: vec![DnsQuestion {
+                name: Name { labels: vec![] },
+                qtype: Type::NS,
+                class: Class::IN,
+            }],
+            records: vec![],
+            authority_records: vec![],
+            additional_records: vec![],
+            edns: None,
+        };
+
+        for upstream in self.upstreams.iter().filter(|u| !u.is_up()) {
+            let mut probe = probe.clone();
+            probe.header.id = rand::random();
+            let mut bytes = Vec::with_capacity(32);
+            if probe.serialize(&mut bytes).is_err() {
+                continue;
+            }
+
+            // Any reply at all shows the upstream is reachable again
+            let started = Instant::now();
+            let exchange = async {
+                match upstream.transport {
+                    Transport::Udp => upstream.exchange(&probe, &bytes, false).await,
+                    Transport::Tls { .. } => upstream.exchange_tls(&probe, &bytes).await,
+                    Transport::Https { .. } => upstream.exchange_https(&probe, &bytes).await,
+                }
+            };
+            match time::timeout(ATTEMPT_TIMEOUT, exchange).await {
+                Ok(Ok(_)) => upstream.record_success(started.elapsed()),
+                _ => upstream.record_failure(),
+            }
+        }
+    }
+}
+
+/// Chooses the upstream set for a name: the set of the longest zone containing the name,
+/// or the default set when no zone does.
+#[derive(Debug, Default)]
+pub struct Routes {
+    zones: Vec<(Name<'static>, Arc<UpstreamSet>)>,
+    default: Option<Arc<UpstreamSet>>,
+}
+
+impl Routes {
+    pub fn new(default: Option<UpstreamSet>) -> Self {
+        Self {
+            zones: Vec::new(),
+            default: default.map(Arc::new),
+        }
+    }
+
+    pub fn add_zone(&mut self, zone: Name<'static>, upstreams: UpstreamSet) {
+        self.zones.push((zone, Arc::new(upstreams)));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.zones.is_empty() && self.default.is_none()
+    }
+
+    pub fn lookup(&self, name: &Name<'_>) -> Option<&Arc<UpstreamSet>> {
+        self.zones
+            .iter()
+            .filter(|(zone, _)| name.is_subdomain_of(zone))
+            .max_by_key(|(zone, _)| zone.labels.len())
+            .map(|(_, upstreams)| upstreams)
+            .or(self.default.as_ref())
+    }
+
+    /// Every upstream set, for health checking.
+    pub fn upstream_sets(&self) -> impl Iterator<Item = &Arc<UpstreamSet>> {
+        self.zones
+            .iter()
+            .map(|(_, upstreams)| upstreams)
+            .chain(&self.default)
+    }
+}
+
+/// A query sent to an upstream, waiting for its reply.
+struct Attempt<'a> {
+    /// Which attempt of the query this is, from zero.
+    number: usize,
+    upstream: &'a Upstream,
+    started: Instant,
+    exchange: Pin<Box<dyn Future<Output = io::Result<Vec<u8>>> + Send + 'a>>,
+}
+
+/// Waits for the first of `attempts` to finish and takes it out, leaving the rest to be
+/// waited for again.
+async fn first_finished<'a>(attempts: &mut Vec<Attempt<'a>>) -> (Attempt<'a>, io::Result<Vec<u8>>) {
+    future::poll_fn(|cx| {
+        let finished = attempts.iter_mut().enumerate().find_map(|(i, attempt)| {
+            match attempt.exchange.as_mut().poll(cx) {
+                Poll::Ready(outcome) => Some((i, outcome)),
+                Poll::Pending => None,
+            }
+        });
+        match finished {
+            Some((i, outcome)) => Poll::Ready((attempts.swap_remove(i), outcome)),
+            None => Poll::Pending,
+        }
+    })
+    .await
+}
+
+/// Periodically probes upstreams that are down, for as long as they are in use.
+pub async fn health_check(upstreams: Weak<UpstreamSet>) {
+    let mut interval = time::interval(PROBE_INTERVAL);
+    loop {
+        interval.tick().await;
+        // The upstreams were dropped on reload
+        let Some(upstreams) = upstreams.upgrade() else {
+            return;
+        };
+        upstreams.probe().await;
+    }
+}
+
+/// Binds a UDP socket for talking to `upstream` on a randomly chosen local port.
+async fn bind_random_port(upstream: SocketAddr) -> io::Result<UdpSocket> {
+    let ip: IpAddr = match upstream {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+
+    for _ in 0..RANDOM_PORT_ATTEMPTS {
+        let port = rand::random_range(1024..=u16::MAX);
+        match UdpSocket::bind((ip, port)).await {
+            Ok(sock) => return Ok(sock),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    // Leave it to the OS to pick a free ephemeral port
+    UdpSocket::bind((ip, 0)).await
+}
+
+/// Sends `bytes`, the wire form of `query`, over a TCP connection and reads the reply. Both
+/// are framed with a two byte length prefix.
+async fn exchange_framed(
+    stream: &mut TcpStream,
+    query: &DnsMessage<'_>,
+    bytes: &[u8],
+) -> io::Result<Vec<u8>> {
+    write_framed(stream, bytes).await?;
+    let reply = read_framed(stream).await?;
+    if !is_reply_to(&reply, query) {
+        // The caller drops the connection rather than reusing it
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "mismatched reply",
+        ));
+    }
+
+    Ok(reply)
+}
+
+/// The offset just past the Question section of `message`, in wire form.
+fn question_section_end(message: &[u8]) -> Option<usize> {
+    let mut buf = Cursor::new(message);
+    let header = DnsHeader::try_parse(&mut buf).ok()?;
+    for _ in 0..header.question_count {
+        DnsQuestion::try_parse(&mut buf).ok()?;
+    }
+
+    Some(buf.position() as usize)
+}
+
+/// Randomly flips the case of the letters in the names of the Question section of `query`,
+/// in wire form, so that spoofed replies also have to guess the case (DNS 0x20).
+fn randomize_case(query: &mut [u8]) {
+    let Some(end) = question_section_end(query) else {
+        return;
+    };
+
+    let mut pos = 12;
+    while pos < end {
+        let len = query[pos] as usize;
+        match len {
+            // End of a name, followed by the type and class
+            0 => pos += 5,
+            // A compression pointer ends the name too
+            _ if len & 0b1100_0000 != 0 => pos += 6,
+            _ => {
+                for byte in &mut query[pos + 1..pos + 1 + len] {
+                    if byte.is_ascii_alphabetic() && rand::random() {
+                        *byte ^= 0x20;
+                    }
+                }
+                pos += len + 1;
+            }
+        }
+    }
+}
+
+/// Whether the Question section of `reply` is byte for byte that of `query`.
+fn echoes_question(reply: &[u8], query: &[u8]) -> bool {
+    question_section_end(query).is_some_and(|end| reply.get(12..end) == query.get(12..end))
+}
+
+/// Puts back the case of the Question section of `original`, the query as given before its
+/// case was randomized, into `reply`.
+fn restore_case(reply: &mut [u8], original: &[u8]) {
+    let Some(end) = question_section_end(original) else {
+        return;
+    };
+    if let Some(section) = reply.get_mut(12..end) {
+        if section.eq_ignore_ascii_case(&original[12..end]) {
+            section.copy_from_slice(&original[12..end]);
+        }
+    }
+}
+
+/// Whether the TC bit is set in the header of `reply`.
+fn is_truncated(reply: &[u8]) -> bool {
+    reply.get(2).is_some_and(|flags| flags & 0b0000_0010 != 0)
+}
+
+/// Whether `reply` is an answer to `query`, rather than a stray or spoofed packet. Only the
+/// header and Question section of the reply are looked at.
+fn is_reply_to(reply: &[u8], query: &DnsMessage<'_>) -> bool {
+    let mut buf = Cursor::new(reply);
+    let Ok(header) = DnsHeader::try_parse(&mut buf) else {
+        return false;
+    };
+    if !header.qr_indicator || header.id != query.header.id {
+        return false;
+    }
+    // Servers may leave the Question section out of error replies
+    if header.question_count == 0 && header.response_code != 0 {
+        return true;
+    }
+    if header.question_count as usize != query.questions.len() {
+        return false;
+    }
+
+    query.questions.iter().all(|q| {
+        DnsQuestion::try_parse(&mut buf).is_ok_and(|r| {
+            r.qtype == q.qtype && r.class == q.class && r.name.eq_ignore_ascii_case(&q.name)
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::testing::{self, StubServer};
+
+    fn upstreams(upstream: &StubServer) -> UpstreamSet {
+        UpstreamSet::new(
+            vec![upstream.address],
+            Strategy::Failover,
+            &testing::logger(),
+        )
+    }
+
+    fn parse(bytes: &[u8]) -> DnsMessage<'static> {
+        DnsMessage::try_parse(&mut Cursor::new(bytes))
+            .unwrap()
+            .into_owned()
+    }
+
+    /// Sends `bytes` through `upstreams` and returns the reply.
+    async fn query(upstreams: &UpstreamSet, bytes: &[u8]) -> Result<Vec<u8>, BoxError> {
+        let (reply, _) = upstreams.query_bytes(&parse(bytes), bytes).await?;
+        Ok(reply)
+    }
+
+    #[test]
+    fn srtt_stays_bounded_through_long_outages() {
+        let upstream = Upstream::new(
+            "127.0.0.1:53".parse().unwrap(),
+            Transport::Udp,
+            testing::logger(),
+        );
+        upstream.record_success(Duration::from_millis(20));
+        // Far more failures than it takes to overflow a doubling Duration
+        for _ in 0..200 {
+            upstream.record_failure();
+        }
+        assert_eq!(upstream.srtt(), QUERY_DEADLINE);
+
+        upstream.record_success(Duration::from_millis(20));
+        assert!(upstream.is_up());
+        assert!(upstream.srtt() < QUERY_DEADLINE);
+    }
+
+    #[test]
+    fn routes_pick_the_longest_matching_zone() {
+        let set = |port| {
+            UpstreamSet::new(
+                vec![SocketAddr::from(([127, 0, 0, 1], port))],
+                Strategy::Failover,
+                &testing::logger(),
+            )
+        };
+        let mut routes = Routes::new(Some(set(1)));
+        routes.add_zone("corp.internal.".parse().unwrap(), set(2));
+        routes.add_zone("eu.corp.internal.".parse().unwrap(), set(3));
+        routes.add_zone("10.in-addr.arpa.".parse().unwrap(), set(4));
+
+        let routed = |name: &str| {
+            let name: Name<'static> = name.parse().unwrap();
+            routes.lookup(&name).unwrap().upstreams()[0].address.port()
+        };
+        assert_eq!(routed("www.example.com."), 1);
+        assert_eq!(routed("corp.internal."), 2);
+        assert_eq!(routed("wiki.CORP.Internal."), 2);
+        assert_eq!(routed("mail.eu.corp.internal."), 3);
+        assert_eq!(routed("4.3.2.10.in-addr.arpa."), 4);
+        // Sharing a suffix is not enough, the zone must match whole labels
+        assert_eq!(routed("notcorp.internal."), 1);
+        assert_eq!(routed("4.3.2.110.in-addr.arpa."), 1);
+    }
+
+    #[test]
+    fn names_outside_every_zone_have_no_route_without_a_default() {
+        let mut routes = Routes::new(None);
+        routes.add_zone(
+            "corp.internal.".parse().unwrap(),
+            UpstreamSet::new(
+                vec!["127.0.0.1:53".parse().unwrap()],
+                Strategy::Failover,
+                &testing::logger(),
+            ),
+        );
+
+        let name: Name<'static> = "www.example.com.".parse().unwrap();
+        assert!(routes.lookup(&name).is_none());
+        assert!(!routes.is_empty());
+    }
+
+    #[test]
+    fn case_randomization_survives_occasional_mismatches() {
+        let upstream = Upstream::new(
+            "127.0.0.1:53".parse().unwrap(),
+            Transport::Udp,
+            testing::logger(),
+        );
+        // Spoofed replies, each followed by the real one
+        for _ in 0..MAX_CASE_MISMATCHES * 2 {
+            upstream.record_case_mismatch();
+            upstream.record_case_preserved();
+        }
+        assert!(upstream.randomizes_case());
+
+        for _ in 0..MAX_CASE_MISMATCHES {
+            assert!(upstream.randomizes_case());
+            upstream.record_case_mismatch();
+        }
+        assert!(!upstream.randomizes_case());
+    }
+
+    #[test]
+    fn randomization_only_changes_the_case_of_the_query_name() {
+        let query = testing::query(1, "abcdefghijklmnopqrstuvwxyz.example.com.");
+        let mut randomized = query.clone();
+        randomize_case(&mut randomized);
+
+        assert_ne!(randomized, query);
+        assert!(randomized.eq_ignore_ascii_case(&query));
+        let end = question_section_end(&query).unwrap();
+        assert_eq!(randomized[..12], query[..12]);
+        assert_eq!(randomized[end - 4..], query[end - 4..]);
+    }
+
+    #[test]
+    fn replies_must_echo_the_question_byte_for_byte() {
+        let query = testing::query(1, "www.example.com.");
+        let reply = testing::reply(&query, [192, 0, 2, 1]);
+        assert!(echoes_question(&reply, &query));
+
+        let mut changed_case = reply.clone();
+        changed_case[13] = b'W';
+        assert!(!echoes_question(&changed_case, &query));
+        let other_name = testing::reply(&testing::query(1, "www.example.net."), [192, 0, 2, 1]);
+        assert!(!echoes_question(&other_name, &query));
+
+        restore_case(&mut changed_case, &query);
+        assert_eq!(changed_case, reply);
+    }
+
+    #[tokio::test]
+    async fn upstreams_that_change_the_case_are_queried_again_at_once() {
+        let upstream = StubServer::start(|query: Vec<u8>| async move {
+            let mut lowercased = query.clone();
+            lowercased[12..].make_ascii_lowercase();
+            Some(testing::reply(&lowercased, [192, 0, 2, 1]))
+        })
+        .await;
+        let upstreams = upstreams(&upstream);
+
+        let query = testing::query(1, "abcdefghijklmnopqrstuvwxyz.example.com.");
+        let started = Instant::now();
+        let reply = self::query(&upstreams, &query).await.unwrap();
+        assert!(started.elapsed() < ATTEMPT_TIMEOUT);
+        assert_eq!(testing::answer(&reply), [192, 0, 2, 1]);
+
+        // Once randomized, then again as given
+        let queries = upstream.queries();
+        assert_eq!(queries.len(), 2);
+        assert_ne!(queries[0][12..], query[12..]);
+        assert_eq!(queries[1][12..], query[12..]);
+        assert!(upstreams.upstreams()[0].is_up());
+        assert_eq!(
+            upstreams.upstreams()[0]
+                .health
+                .lock()
+                .unwrap()
+                .consecutive_failures,
+            0
+        );
+
+        // Until the upstream is taken not to preserve case at all
+        for _ in 1..MAX_CASE_MISMATCHES {
+            self::query(&upstreams, &query).await.unwrap();
+        }
+        assert!(!upstreams.upstreams()[0].randomizes_case());
+        let queried = upstream.queries().len();
+        self::query(&upstreams, &query).await.unwrap();
+        assert_eq!(upstream.queries().len(), queried + 1);
+    }
+
+    #[test]
+    fn replies_must_match_the_id_and_question() {
+        let query = testing::query(1, "www.example.com.");
+        let message = parse(&query);
+
+        assert!(is_reply_to(
+            &testing::reply(&query, [192, 0, 2, 1]),
+            &message
+        ));
+        let mut uppercased = query.clone();
+        uppercased[12..].make_ascii_uppercase();
+        assert!(is_reply_to(
+            &testing::reply(&uppercased, [192, 0, 2, 1]),
+            &message
+        ));
+        // Error replies may leave out the question
+        let mut failure = testing::failure(&query, RCODE_SERVER_FAILURE);
+        failure[4..6].copy_from_slice(&[0, 0]);
+        failure.truncate(12);
+        assert!(is_reply_to(&failure, &message));
+
+        let other_id = testing::reply(&testing::query(2, "www.example.com."), [192, 0, 2, 1]);
+        assert!(!is_reply_to(&other_id, &message));
+        let other_name = testing::reply(&testing::query(1, "www.example.net."), [192, 0, 2, 1]);
+        assert!(!is_reply_to(&other_name, &message));
+        assert!(!is_reply_to(&query, &message));
+        assert!(!is_reply_to(&query[..5], &message));
+    }
+
+    #[tokio::test]
+    async fn mismatched_replies_are_ignored_until_the_real_one_arrives() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstreams = UpstreamSet::new(
+            vec![server.local_addr().unwrap()],
+            Strategy::Failover,
+            &testing::logger(),
+        );
+        let serve = async {
+            let mut buf = vec![0; MAX_MESSAGE_SIZE];
+            let (len, client) = server.recv_from(&mut buf).await.unwrap();
+            let query = &buf[..len];
+            let id = u16::from_be_bytes([query[0], query[1]]);
+
+            let other_id = testing::reply(&testing::query(id ^ 1, "www.example.com."), [0; 4]);
+            let other_name = testing::reply(&testing::query(id, "www.example.net."), [0; 4]);
+            for reply in [other_id, other_name, testing::reply(query, [192, 0, 2, 1])] {
+                server.send_to(&reply, client).await.unwrap();
+            }
+        };
+
+        let query = testing::query(1, "www.example.com.");
+        let (reply, ()) = tokio::join!(self::query(&upstreams, &query), serve);
+        let reply = reply.unwrap();
+        assert_eq!(testing::answer(&reply), [192, 0, 2, 1]);
+    }
+
+    /// An upstream that answers queries after `delay`, except those numbered in `dropped`,
+    /// counting from zero.
+    async fn delaying_upstream(delay: Duration, dropped: &'static [usize]) -> StubServer {
+        let count = Arc::new(AtomicUsize::new(0));
+        StubServer::start(move |query: Vec<u8>| {
+            let number = count.fetch_add(1, Ordering::Relaxed);
+            async move {
+                time::sleep(delay).await;
+                (!dropped.contains(&number)).then(|| testing::reply(&query, [192, 0, 2, 1]))
+            }
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn dropped_replies_are_retried() {
+        let upstream = delaying_upstream(Duration::ZERO, &[0]).await;
+        let upstreams = upstreams(&upstream);
+
+        let started = Instant::now();
+        let reply = query(&upstreams, &testing::query(1, "www.example.com.")).await;
+        assert_eq!(testing::answer(&reply.unwrap()), [192, 0, 2, 1]);
+        assert!(started.elapsed() >= ATTEMPT_TIMEOUT);
+        assert_eq!(upstream.queries().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn late_replies_to_earlier_attempts_are_taken() {
+        // The retry goes unanswered, but the first reply comes in while it is waited for
+        let upstream = delaying_upstream(ATTEMPT_TIMEOUT + Duration::from_millis(200), &[1]).await;
+        let upstreams = upstreams(&upstream);
+
+        let started = Instant::now();
+        let reply = query(&upstreams, &testing::query(1, "www.example.com.")).await;
+        assert_eq!(testing::answer(&reply.unwrap()), [192, 0, 2, 1]);
+        assert!(started.elapsed() < ATTEMPT_TIMEOUT * 2);
+        assert_eq!(upstream.queries().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn queries_give_up_at_the_deadline() {
+        let upstream = StubServer::start(|_| async { None }).await;
+        let upstreams = upstreams(&upstream);
+
+        let started = Instant::now();
+        let reply = query(&upstreams, &testing::query(1, "www.example.com.")).await;
+        assert!(reply.is_err());
+        assert!(started.elapsed() >= QUERY_DEADLINE);
+        assert!(started.elapsed() < QUERY_DEADLINE + Duration::from_millis(500));
+        assert_eq!(upstream.queries().len(), ATTEMPTS);
+    }
+
+    #[tokio::test]
+    async fn server_failures_are_retried_with_the_next_upstream() {
+        let failing = StubServer::start(|query: Vec<u8>| async move {
+            Some(testing::failure(&query, RCODE_SERVER_FAILURE))
+        })
+        .await;
+        let answering = delaying_upstream(Duration::ZERO, &[]).await;
+        let upstreams = UpstreamSet::new(
+            vec![failing.address, answering.address],
+            Strategy::Failover,
+            &testing::logger(),
+        );
+
+        let started = Instant::now();
+        let query = testing::query(1, "www.example.com.");
+        let (reply, address) = upstreams.query_bytes(&parse(&query), &query).await.unwrap();
+        assert_eq!(address, answering.address);
+        assert_eq!(testing::answer(&reply), [192, 0, 2, 1]);
+        assert!(started.elapsed() < ATTEMPT_TIMEOUT);
+        // A server failure is a reply all the same, so the upstream is not held against it
+        assert!(upstreams.upstreams()[0].is_up());
+
+        // With no other upstream left to try, the failure is the answer
+        let upstreams = self::upstreams(&failing);
+        let reply = self::query(&upstreams, &query).await.unwrap();
+        assert_eq!(reply[3] & 0b0000_1111, RCODE_SERVER_FAILURE);
+        assert_eq!(failing.queries().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn truncated_replies_are_retried_over_reused_tcp_connections() {
+        let upstream = StubServer::start(|query: Vec<u8>| async move {
+            let mut truncated = testing::failure(&query, 0);
+            truncated[2] |= 0b0000_0010;
+            Some(truncated)
+        })
+        .await;
+        let listener = TcpListener::bind(upstream.address).await.unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let serve = tokio::spawn({
+            let connections = connections.clone();
+            async move {
+                loop {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    connections.fetch_add(1, Ordering::Relaxed);
+                    tokio::spawn(async move {
+                        while let Ok(query) = read_framed(&mut stream).await {
+                            let reply = testing::reply(&query, [192, 0, 2, 1]);
+                            write_framed(&mut stream, &reply).await.unwrap();
+                        }
+                    });
+                }
+            }
+        });
+        let upstreams = upstreams(&upstream);
+
+        for name in ["www.example.com.", "mail.example.com."] {
+            let reply = query(&upstreams, &testing::query(1, name)).await.unwrap();
+            assert_eq!(testing::answer(&reply), [192, 0, 2, 1]);
+            assert!(!is_truncated(&reply));
+        }
+        assert_eq!(upstream.queries().len(), 2);
+        assert_eq!(connections.load(Ordering::Relaxed), 1);
+        serve.abort();
+    }
+}